@@ -0,0 +1 @@
+cat node.example.com-4ac35ef0-582d-468d-8c95-cd3f2ee333f9.ocs.gz.sign <(echo) node.example.com-4ac35ef0-582d-468d-8c95-cd3f2ee333f9.ocs.gz | curl --user rudder:rudder --upload-file - https://rudder.example.com/rudder/relay-api/inventories/node.example.com-4ac35ef0-582d-468d-8c95-cd3f2ee333f9.ocs.gz
\ No newline at end of file
@@ -0,0 +1 @@
+curl -X DELETE 'http://localhost:3030/rudder/relay-api/1/remote-run/jobs/5c1b3e8d0f2a4b6c9d7e1f3a5b7c9d0e'
@@ -0,0 +1 @@
+curl --user rudder:rudder --upload-file 2020-04-07T11:20:58+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log.gz https://rudder.example.com/rudder/relay-api/reports/2020-04-07T11:20:58+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log.gz
\ No newline at end of file
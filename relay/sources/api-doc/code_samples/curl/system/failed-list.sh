@@ -0,0 +1 @@
+curl http://localhost:3030/rudder/relay-api/1/system/failed/reporting
@@ -0,0 +1 @@
+curl -X POST 'http://localhost:3030/rudder/relay-api/1/system/failed/reporting/2020-01-24T12:17:59+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log'
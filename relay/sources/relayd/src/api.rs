@@ -1,23 +1,36 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+mod failed;
+mod metrics;
 mod remote_run;
 mod shared_files;
 mod shared_folder;
 mod system;
 
+pub use self::remote_run::RemoteRunJobs;
 use crate::{
     api::{
+        metrics::Metrics,
         remote_run::{RemoteRun, RemoteRunTarget},
-        shared_files::{SharedFilesHeadParams, SharedFilesPutParams},
+        shared_files::{
+            SharedFilesHeadParams, SharedFilesListParams, SharedFilesPutParams,
+            CLIENT_CERTIFICATE_HEADER,
+        },
         shared_folder::SharedFolderParams,
         system::{Info, Status},
     },
     error::Error,
-    stats::Stats,
+    output::batch::RunlogBatch,
+    processing::{
+        inventory::{self, InventoryType},
+        reporting, OutputError,
+    },
+    stats::{Event, Stats},
     JobConfig,
 };
-use futures::Future;
+use bytes::IntoBuf;
+use futures::{future::poll_fn, sync::mpsc, Future};
 use serde::Serialize;
 use std::{
     collections::HashMap,
@@ -26,15 +39,16 @@ use std::{
     path::PathBuf,
     sync::{Arc, RwLock},
 };
+use tokio_threadpool::blocking;
 use tracing::{error, info, span, Level};
 use warp::{
     body::{self, FullBody},
     filters::{method::v2::*, path::Peek},
-    fs,
+    fs, header,
     http::StatusCode,
     path, query,
     reject::custom,
-    reply, Filter, Rejection, Reply,
+    reply, Buf, Filter, Rejection, Reply,
 };
 
 #[derive(Serialize, Debug, PartialEq, Eq)]
@@ -96,16 +110,40 @@ impl<T: Serialize> ApiResponse<T> {
 pub fn run(
     listen: SocketAddr,
     job_config: Arc<JobConfig>,
+    batch: Option<Arc<RunlogBatch>>,
     stats: Arc<RwLock<Stats>>,
+    tx_stats: mpsc::Sender<Event>,
 ) -> impl Future<Item = (), Error = ()> {
     let span = span!(Level::TRACE, "api");
     let _enter = span.enter();
 
-    // WARNING: Not stable, will be replaced soon
+    // WARNING: Deprecated, use metrics instead
     // Kept for testing mainly
+    let stats0 = stats.clone();
     let stats = get()
         .and(path("stats"))
-        .map(move || reply::json(&(*stats.clone().read().expect("open stats database"))));
+        .map(move || reply::json(&(*stats0.clone().read().expect("open stats database"))));
+
+    // Prometheus text exposition format
+    //
+    // Reading the failed directories is blocking
+    let job_config_metrics = job_config.clone();
+    let metrics = get()
+        .and(path("metrics"))
+        .and(path::end())
+        .and_then(move || {
+            let job_config = job_config_metrics.clone();
+            let stats = stats.clone();
+            poll_fn(move || blocking(|| Metrics::poll(job_config.clone(), stats.clone()))).then(
+                |res| {
+                    Ok::<_, Rejection>(reply::with_header(
+                        res.expect("the thread pool shut down").to_string(),
+                        "content-type",
+                        metrics::CONTENT_TYPE,
+                    ))
+                },
+            )
+        });
 
     // New endpoints, following Rudder's API format
     let info = get().and(path("info")).map(move || {
@@ -132,9 +170,55 @@ pub fn run(
         .reply()
     });
 
+    // Failed files are listed and moved on disk, which is blocking
+    let job_config11 = job_config.clone();
+    let failed_list = get()
+        .and(path::param::<String>())
+        .and(path::end())
+        .and_then(move |kind: String| {
+            let job_config = job_config11.clone();
+            poll_fn(move || blocking(|| failed::list(&job_config, &kind))).then(|res| {
+                Ok::<_, Rejection>(failed_reply(
+                    "listFailedFiles",
+                    res.expect("the thread pool shut down").map(Some),
+                ))
+            })
+        });
+
+    let job_config12 = job_config.clone();
+    let failed_retry = post()
+        .and(path::param::<String>())
+        .and(path::param::<String>())
+        .and(path::end())
+        .and_then(move |kind: String, name: String| {
+            let job_config = job_config12.clone();
+            poll_fn(move || blocking(|| failed::retry(&job_config, &kind, &name))).then(|res| {
+                Ok::<_, Rejection>(failed_reply::<()>(
+                    "retryFailedFile",
+                    res.expect("the thread pool shut down").map(|_| None),
+                ))
+            })
+        });
+
+    let job_config13 = job_config.clone();
+    let failed_delete = delete()
+        .and(path::param::<String>())
+        .and(path::param::<String>())
+        .and(path::end())
+        .and_then(move |kind: String, name: String| {
+            let job_config = job_config13.clone();
+            poll_fn(move || blocking(|| failed::delete(&job_config, &kind, &name))).then(|res| {
+                Ok::<_, Rejection>(failed_reply::<()>(
+                    "deleteFailedFile",
+                    res.expect("the thread pool shut down").map(|_| None),
+                ))
+            })
+        });
+
     // Old compatible endpoints
 
     let job_config2 = job_config.clone();
+    let tx_stats2 = tx_stats.clone();
     let node_id =
         post()
             .and(path("nodes"))
@@ -143,12 +227,13 @@ pub fn run(
                     RemoteRunTarget::Nodes(vec![node_id]),
                     &simple_map,
                 ) {
-                    Ok(handle) => handle.run(job_config2.clone()),
+                    Ok(handle) => handle.run(job_config2.clone(), tx_stats2.clone()),
                     Err(e) => Err(custom(e.to_string())),
                 },
             ));
 
     let job_config3 = job_config.clone();
+    let tx_stats3 = tx_stats.clone();
     let nodes =
         post()
             .and(path("nodes"))
@@ -164,7 +249,7 @@ pub fn run(
                             ),
                             &simple_map,
                         ) {
-                            Ok(handle) => handle.run(job_config3.clone()),
+                            Ok(handle) => handle.run(job_config3.clone(), tx_stats3.clone()),
                             Err(e) => Err(custom(e.to_string())),
                         },
                         None => Err(custom(Error::MissingTargetNodes)),
@@ -173,42 +258,74 @@ pub fn run(
             ));
 
     let job_config4 = job_config.clone();
+    let tx_stats4 = tx_stats.clone();
     let all = post().and(path("all")).and(body::form()).and_then(
         move |simple_map: HashMap<String, String>| match RemoteRun::new(
             RemoteRunTarget::All,
             &simple_map,
         ) {
-            Ok(handle) => handle.run(job_config4.clone()),
+            Ok(handle) => handle.run(job_config4.clone(), tx_stats4.clone()),
             Err(e) => Err(custom(e.to_string())),
         },
     );
 
+    let job_config16 = job_config.clone();
+    let job_get = get()
+        .and(path("jobs"))
+        .and(path::param::<String>())
+        .and(path::end())
+        .map(move |id: String| {
+            job_reply(
+                "getRemoteRunJob",
+                job_config16.remote_run_jobs.get(&id).map(Some),
+            )
+        });
+
+    let job_config17 = job_config.clone();
+    let job_delete = delete()
+        .and(path("jobs"))
+        .and(path::param::<String>())
+        .and(path::end())
+        .and_then(move |id: String| {
+            job_config17
+                .remote_run_jobs
+                .clone()
+                .cancel(&id)
+                .then(|res| Ok::<_, Rejection>(job_reply("cancelRemoteRunJob", res.map(Some))))
+        });
+
     let job_config5 = job_config.clone();
     let shared_files_put = put()
         .and(path::param::<String>())
         .and(path::param::<String>())
         .and(path::param::<String>())
         .and(query::<SharedFilesPutParams>())
-        .and(body::concat())
-        .map(
-            move |target_id, source_id, file_id, params: SharedFilesPutParams, buf: FullBody| {
-                reply::with_status(
-                    "".to_string(),
-                    match shared_files::put(
-                        target_id,
-                        source_id,
-                        file_id,
-                        params,
-                        job_config5.clone(),
-                        buf,
-                    ) {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("error while processing request: {}", e);
-                            StatusCode::INTERNAL_SERVER_ERROR
-                        }
-                    },
+        .and(body::stream())
+        .and_then(
+            move |target_id, source_id, file_id, params: SharedFilesPutParams, body| {
+                shared_files::put(
+                    target_id,
+                    source_id,
+                    file_id,
+                    params,
+                    job_config5.clone(),
+                    body,
                 )
+                .then(|res| {
+                    Ok::<_, Rejection>(reply::with_status(
+                        "".to_string(),
+                        match res {
+                            Ok(x) => x,
+                            Err(e) => {
+                                error!("error while processing request: {}", e);
+                                match e {
+                                    Error::LimitExceeded(_, _) => StatusCode::PAYLOAD_TOO_LARGE,
+                                    _ => StatusCode::INTERNAL_SERVER_ERROR,
+                                }
+                            }
+                        },
+                    ))
+                })
             },
         );
 
@@ -218,20 +335,69 @@ pub fn run(
         .and(path::param::<String>())
         .and(path::param::<String>())
         .and(query::<SharedFilesHeadParams>())
-        .map(move |target_id, source_id, file_id, params| {
-            reply::with_status(
-                "".to_string(),
-                match shared_files::head(target_id, source_id, file_id, params, job_config6.clone())
-                {
-                    Ok(x) => x,
-                    Err(e) => {
-                        error!("error while processing request: {}", e);
-                        StatusCode::INTERNAL_SERVER_ERROR
-                    }
+        .and_then(move |target_id, source_id, file_id, params| {
+            shared_files::head(target_id, source_id, file_id, params, job_config6.clone()).then(
+                |res| {
+                    Ok::<_, Rejection>(reply::with_status(
+                        "".to_string(),
+                        match res {
+                            Ok(x) => x,
+                            Err(e) => {
+                                error!("error while processing request: {}", e);
+                                StatusCode::INTERNAL_SERVER_ERROR
+                            }
+                        },
+                    ))
                 },
             )
         });
 
+    let job_config14 = job_config.clone();
+    let shared_files_get = get()
+        .and(path::param::<String>())
+        .and(path::param::<String>())
+        .and(path::param::<String>())
+        .and(path::end())
+        .and(header::optional::<String>(CLIENT_CERTIFICATE_HEADER))
+        .map(move |target_id, source_id, file_id, certificate| {
+            shared_files::get(
+                target_id,
+                source_id,
+                file_id,
+                certificate,
+                job_config14.clone(),
+            )
+            .unwrap_or_else(|e| {
+                error!("error while processing request: {}", e);
+                shared_files::empty_response(shared_files::status_code(&e))
+            })
+        });
+
+    let job_config15 = job_config.clone();
+    let shared_files_list = get()
+        .and(path::param::<String>())
+        .and(path::end())
+        .and(
+            query::<SharedFilesListParams>()
+                .map(Some)
+                .or_else(|_| Ok::<_, Rejection>((None,))),
+        )
+        .and(query::raw().or_else(|_| Ok::<_, Rejection>((String::new(),))))
+        .and(header::optional::<String>(CLIENT_CERTIFICATE_HEADER))
+        .map(move |target_id, params, raw_query: String, certificate| {
+            // No query string is a valid query, a malformed one is not
+            let res = match params {
+                Some(params) => Ok(params),
+                None if raw_query.is_empty() => Ok(SharedFilesListParams::default()),
+                None => Err(Error::InvalidQuery(raw_query)),
+            }
+            .and_then(|params| {
+                shared_files::list(target_id, params, certificate, job_config15.clone())
+            });
+            let status = res.as_ref().err().map(shared_files::status_code);
+            ApiResponse::new("listSharedFiles", res.map(Some), status).reply()
+        });
+
     let job_config7 = job_config.clone();
     let shared_folder_head = head()
         .and(path::peek())
@@ -246,24 +412,146 @@ pub fn run(
         });
     let shared_folder_get = fs::dir(job_config.cfg.shared_folder.path.clone());
 
+    // Refuse large uploads before reading them
+    let max_report_size = job_config
+        .cfg
+        .processing
+        .reporting
+        .limits
+        .max_compressed_size as u64;
+    let job_config8 = job_config.clone();
+    let tx_stats0 = tx_stats.clone();
+    let reports_put = put()
+        .and(path::param::<String>())
+        .and(path::end())
+        .and(body::content_length_limit(max_report_size))
+        .and(body::concat())
+        .and_then(move |runinfo: String, buf: FullBody| {
+            reporting::receive(
+                job_config8.clone(),
+                batch.clone(),
+                runinfo,
+                buf.into_buf().collect::<Vec<u8>>(),
+                tx_stats0.clone(),
+            )
+            .then(|res| Ok::<_, Rejection>(received_reply("putReport", res)))
+        });
+
+    let max_inventory_size = job_config
+        .cfg
+        .processing
+        .inventory
+        .limits
+        .max_compressed_size as u64;
+    let job_config9 = job_config.clone();
+    let tx_stats1 = tx_stats.clone();
+    let inventories_put = put()
+        .and(path::param::<String>())
+        .and(path::end())
+        .and(body::content_length_limit(max_inventory_size))
+        .and(body::concat())
+        .and_then(move |file: String, buf: FullBody| {
+            inventory::receive(
+                job_config9.clone(),
+                file,
+                InventoryType::New,
+                buf.into_buf().collect::<Vec<u8>>(),
+                tx_stats1.clone(),
+            )
+            .then(|res| Ok::<_, Rejection>(received_reply("putInventory", res)))
+        });
+
+    let job_config10 = job_config.clone();
+    let inventory_updates_put = put()
+        .and(path::param::<String>())
+        .and(path::end())
+        .and(body::content_length_limit(max_inventory_size))
+        .and(body::concat())
+        .and_then(move |file: String, buf: FullBody| {
+            inventory::receive(
+                job_config10.clone(),
+                file,
+                InventoryType::Update,
+                buf.into_buf().collect::<Vec<u8>>(),
+                tx_stats.clone(),
+            )
+            .then(|res| Ok::<_, Rejection>(received_reply("putInventoryUpdate", res)))
+        });
+
     // Routing
     // // /api/ for public API, /relay-api/ for internal relay API
     let base = path("rudder").and(path("relay-api"));
-    let system = path("system").and(stats.or(status).or(reload).or(info));
-    let remote_run = path("remote-run").and(nodes.or(all).or(node_id));
-    let shared_files = path("shared-files").and((shared_files_put).or(shared_files_head));
+    let failed_files = path("failed").and(failed_list.or(failed_retry).or(failed_delete));
+    let system = path("system").and(
+        stats
+            .or(metrics.clone())
+            .or(status)
+            .or(reload)
+            .or(info)
+            .or(failed_files),
+    );
+    let remote_run = path("remote-run").and(nodes.or(all).or(node_id).or(job_get).or(job_delete));
+    let shared_files = path("shared-files").and(
+        shared_files_put
+            .or(shared_files_head)
+            .or(shared_files_get)
+            .or(shared_files_list),
+    );
     let shared_folder = path("shared-folder").and(shared_folder_head.or(shared_folder_get));
+    let reports = path("reports").and(reports_put);
+    let inventories = path("inventories").and(inventories_put);
+    let inventory_updates = path("inventory-updates").and(inventory_updates_put);
 
     // Global route for /1/
-    let routes_1 = base
-        .and(path("1"))
-        .and(system.or(remote_run).or(shared_files).or(shared_folder))
+    let routes_1 = base.and(path("1")).and(
+        system
+            .or(remote_run)
+            .or(shared_files)
+            .or(shared_folder)
+            .or(reports)
+            .or(inventories)
+            .or(inventory_updates),
+    );
+    // Also on the default path used by Prometheus
+    let routes = metrics
+        .clone()
+        .or(routes_1)
         .recover(customize_error)
         .with(warp::log("relayd::relay-api"));
 
     info!("Starting API on {}", listen);
-    // TODO graceful shutdown
-    warp::serve(routes_1).bind(listen)
+    let (_addr, server) =
+        warp::serve(routes).bind_with_graceful_shutdown(listen, job_config.shutdown_requested());
+    server
+}
+
+/// Reply to a file sent for processing, transient errors meaning it should
+/// be sent again later
+fn received_reply(action: &'static str, res: Result<(), Error>) -> impl Reply {
+    let status = match &res {
+        Err(e) if OutputError::from(e) == OutputError::Transient => {
+            Some(StatusCode::SERVICE_UNAVAILABLE)
+        }
+        Err(Error::LimitExceeded(_, _)) => Some(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(_) => Some(StatusCode::BAD_REQUEST),
+        Ok(_) => None,
+    };
+    ApiResponse::<()>::new(action, res.map(|_| None), status).reply()
+}
+
+/// Reply to an action on failed files
+fn failed_reply<T: Serialize>(action: &'static str, res: Result<Option<T>, Error>) -> impl Reply {
+    let status = res.as_ref().err().map(failed::status_code);
+    ApiResponse::new(action, res, status).reply()
+}
+
+/// Reply to a request on a remote run job
+fn job_reply<T: Serialize>(action: &'static str, res: Result<Option<T>, Error>) -> impl Reply {
+    let status = match &res {
+        Err(Error::UnknownRemoteRunJob(_)) => Some(StatusCode::NOT_FOUND),
+        _ => None,
+    };
+    ApiResponse::new(action, res, status).reply()
 }
 
 fn customize_error(reject: Rejection) -> Result<impl Reply, Rejection> {
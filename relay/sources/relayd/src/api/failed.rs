@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    error::Error,
+    processing::{failed, RootDirectory},
+    JobConfig,
+};
+use tracing::info;
+use warp::http::StatusCode;
+
+/// Root directory of the given processing type
+fn directory(job_config: &JobConfig, kind: &str) -> Result<RootDirectory, Error> {
+    match kind {
+        "reporting" => Ok(job_config.cfg.processing.reporting.directory.clone()),
+        "inventory" => Ok(job_config.cfg.processing.inventory.directory.clone()),
+        _ => Err(Error::UnknownProcessingType(kind.to_string())),
+    }
+}
+
+pub fn list(job_config: &JobConfig, kind: &str) -> Result<Vec<failed::FailedFile>, Error> {
+    failed::list(&directory(job_config, kind)?)
+}
+
+pub fn retry(job_config: &JobConfig, kind: &str, name: &str) -> Result<(), Error> {
+    failed::retry(&directory(job_config, kind)?, name)?;
+    info!("{} file {} will be processed again", kind, name);
+    Ok(())
+}
+
+pub fn delete(job_config: &JobConfig, kind: &str, name: &str) -> Result<(), Error> {
+    failed::remove(&directory(job_config, kind)?, name)?;
+    info!("{} file {} was deleted", kind, name);
+    Ok(())
+}
+
+pub fn status_code(error: &Error) -> StatusCode {
+    match error {
+        Error::UnknownFailedFile(_) | Error::UnknownProcessingType(_) => StatusCode::NOT_FOUND,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,373 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    configuration::main::OutputSelect,
+    stats::{Queue, Stats},
+    JobConfig,
+};
+use std::{
+    fmt,
+    fs::read_dir,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+use tracing::warn;
+
+/// Content type of the text exposition format
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+#[derive(Debug, PartialEq, Eq, Default, Clone, Copy)]
+struct DirectorySize {
+    files: u64,
+    bytes: u64,
+}
+
+impl DirectorySize {
+    fn new(path: &Path) -> Option<Self> {
+        let entries = read_dir(path)
+            .map_err(|e| warn!("could not read {:?}: {}", path, e))
+            .ok()?;
+        let mut size = Self::default();
+        // Failure reasons are not counted
+        for metadata in entries
+            .filter_map(|e| e.ok())
+            .filter(|e| {
+                e.path()
+                    .extension()
+                    .map(|ext| ext != "reason")
+                    .unwrap_or(true)
+            })
+            .filter_map(|e| e.metadata().ok())
+        {
+            if metadata.is_file() {
+                size.files += 1;
+                size.bytes += metadata.len();
+            }
+        }
+        Some(size)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct PoolUsage {
+    connections: u32,
+    idle_connections: u32,
+    max_size: u32,
+}
+
+/// Snapshot of all metrics, rendered in Prometheus text format
+#[derive(Debug, PartialEq, Eq)]
+pub struct Metrics {
+    stats: Stats,
+    queues: Vec<(Queue, usize)>,
+    failed: Vec<(&'static str, DirectorySize)>,
+    pool: Option<PoolUsage>,
+}
+
+impl Metrics {
+    pub fn poll(job_config: Arc<JobConfig>, stats: Arc<RwLock<Stats>>) -> Self {
+        let processing = &job_config.cfg.processing;
+
+        let mut queues = vec![];
+        let mut failed = vec![];
+        if processing.reporting.is_enabled() {
+            queues.push(Queue::Reporting);
+            if let Some(size) = DirectorySize::new(&processing.reporting.directory.join("failed")) {
+                failed.push(("reporting", size));
+            }
+        }
+        if processing.inventory.output.is_enabled() {
+            queues.push(Queue::InventoryNew);
+            queues.push(Queue::InventoryUpdate);
+            if let Some(size) = DirectorySize::new(&processing.inventory.directory.join("failed")) {
+                failed.push(("inventory", size));
+            }
+        }
+
+        Self {
+            stats: stats.read().expect("open stats database").clone(),
+            queues: queues
+                .into_iter()
+                .map(|q| (q, job_config.queues.get(q)))
+                .collect(),
+            failed,
+            pool: job_config.pool.as_ref().map(|p| {
+                let state = p.state();
+                PoolUsage {
+                    connections: state.connections,
+                    idle_connections: state.idle_connections,
+                    max_size: p.max_size(),
+                }
+            }),
+        }
+    }
+}
+
+fn header(f: &mut fmt::Formatter, name: &str, kind: &str, help: &str) -> fmt::Result {
+    writeln!(f, "# HELP {} {}", name, help)?;
+    writeln!(f, "# TYPE {} {}", name, kind)
+}
+
+fn counter(f: &mut fmt::Formatter, name: &str, help: &str, value: u64) -> fmt::Result {
+    header(f, name, "counter", help)?;
+    writeln!(f, "{} {}", name, value)
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stats = &self.stats;
+
+        // Counters
+        counter(
+            f,
+            "relayd_reports_received_total",
+            "Reports received",
+            stats.report_received,
+        )?;
+        counter(
+            f,
+            "relayd_reports_refused_total",
+            "Reports refused",
+            stats.report_refused,
+        )?;
+        counter(
+            f,
+            "relayd_reports_sent_total",
+            "Reports forwarded upstream",
+            stats.report_sent,
+        )?;
+        counter(
+            f,
+            "relayd_reports_inserted_total",
+            "Reports inserted into the database",
+            stats.report_inserted,
+        )?;
+        counter(
+            f,
+            "relayd_reports_archived_total",
+            "Reports written to the local archive",
+            stats.report_archived,
+        )?;
+        counter(
+            f,
+            "relayd_reports_truncated_total",
+            "Reports with truncated messages",
+            stats.report_truncated,
+        )?;
+        counter(
+            f,
+            "relayd_reports_too_large_total",
+            "Reports refused for exceeding size limits",
+            stats.report_too_large,
+        )?;
+        counter(
+            f,
+            "relayd_inventories_received_total",
+            "Inventories received",
+            stats.inventory_received,
+        )?;
+        counter(
+            f,
+            "relayd_inventories_refused_total",
+            "Inventories refused",
+            stats.inventory_refused,
+        )?;
+        counter(
+            f,
+            "relayd_inventories_sent_total",
+            "Inventories forwarded upstream",
+            stats.inventory_sent,
+        )?;
+        counter(
+            f,
+            "relayd_shared_files_expired_total",
+            "Shared files removed once expired",
+            stats.shared_file_expired,
+        )?;
+        counter(
+            f,
+            "relayd_remote_run_not_triggered_total",
+            "Nodes not triggered as their sub-relay was unreachable",
+            stats.remote_run_not_triggered,
+        )?;
+
+        header(
+            f,
+            "relayd_remote_run_forward_failures_total",
+            "counter",
+            "Remote runs which could not be forwarded to a sub-relay by reason",
+        )?;
+        for (reason, count) in &stats.remote_run_forward_failed {
+            writeln!(
+                f,
+                "relayd_remote_run_forward_failures_total{{reason=\"{}\"}} {}",
+                reason, count
+            )?;
+        }
+
+        header(
+            f,
+            "relayd_upstream_responses_total",
+            "counter",
+            "Responses from upstream server by status code",
+        )?;
+        for (code, count) in &stats.upstream_responses {
+            writeln!(
+                f,
+                "relayd_upstream_responses_total{{code=\"{}\"}} {}",
+                code, count
+            )?;
+        }
+
+        // Latency
+        let name = "relayd_output_duration_seconds";
+        header(
+            f,
+            name,
+            "histogram",
+            "Time spent processing a file by output",
+        )?;
+        for (output, histogram) in &stats.output_duration {
+            for (bound, count) in histogram.buckets() {
+                let le = bound
+                    .map(|b| b.to_string())
+                    .unwrap_or_else(|| "+Inf".to_string());
+                writeln!(
+                    f,
+                    "{}_bucket{{output=\"{}\",le=\"{}\"}} {}",
+                    name, output, le, count
+                )?;
+            }
+            writeln!(
+                f,
+                "{}_sum{{output=\"{}\"}} {}",
+                name,
+                output,
+                histogram.sum().as_secs_f64()
+            )?;
+            writeln!(
+                f,
+                "{}_count{{output=\"{}\"}} {}",
+                name,
+                output,
+                histogram.count()
+            )?;
+        }
+
+        // Current state
+        header(
+            f,
+            "relayd_queue_depth",
+            "gauge",
+            "Files waiting in processing queues",
+        )?;
+        for (queue, depth) in &self.queues {
+            writeln!(f, "relayd_queue_depth{{queue=\"{}\"}} {}", queue, depth)?;
+        }
+
+        header(
+            f,
+            "relayd_failed_files",
+            "gauge",
+            "Files in failed directories",
+        )?;
+        for (kind, size) in &self.failed {
+            writeln!(f, "relayd_failed_files{{type=\"{}\"}} {}", kind, size.files)?;
+        }
+        header(
+            f,
+            "relayd_failed_bytes",
+            "gauge",
+            "Size of files in failed directories",
+        )?;
+        for (kind, size) in &self.failed {
+            writeln!(f, "relayd_failed_bytes{{type=\"{}\"}} {}", kind, size.bytes)?;
+        }
+
+        if let Some(pool) = self.pool {
+            header(
+                f,
+                "relayd_database_connections",
+                "gauge",
+                "Database connections in the pool",
+            )?;
+            writeln!(f, "relayd_database_connections {}", pool.connections)?;
+            header(
+                f,
+                "relayd_database_idle_connections",
+                "gauge",
+                "Idle database connections in the pool",
+            )?;
+            writeln!(
+                f,
+                "relayd_database_idle_connections {}",
+                pool.idle_connections
+            )?;
+            header(
+                f,
+                "relayd_database_max_connections",
+                "gauge",
+                "Maximum size of the database connection pool",
+            )?;
+            writeln!(f, "relayd_database_max_connections {}", pool.max_size)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::{Event, ForwardFailure, Output};
+    use std::time::Duration;
+
+    #[test]
+    fn it_renders_metrics() {
+        let mut stats = Stats::default();
+        stats.event(Event::ReportReceived);
+        stats.event(Event::UpstreamResponse(200));
+        stats.event(Event::OutputDuration(
+            Output::ReportUpstream,
+            Duration::from_millis(20),
+        ));
+        stats.event(Event::RemoteRunForwardFailed(ForwardFailure::Tls));
+        stats.event(Event::RemoteRunNotTriggered(3));
+
+        let metrics = Metrics {
+            stats,
+            queues: vec![(Queue::Reporting, 3)],
+            failed: vec![(
+                "reporting",
+                DirectorySize {
+                    files: 2,
+                    bytes: 1024,
+                },
+            )],
+            pool: None,
+        }
+        .to_string();
+
+        assert!(metrics.contains("# TYPE relayd_reports_received_total counter\n"));
+        assert!(metrics.contains("\nrelayd_reports_received_total 1\n"));
+        assert!(metrics.contains("\nrelayd_upstream_responses_total{code=\"200\"} 1\n"));
+        assert!(metrics.contains("\nrelayd_remote_run_forward_failures_total{reason=\"tls\"} 1\n"));
+        assert!(metrics.contains("\nrelayd_remote_run_not_triggered_total 3\n"));
+        assert!(metrics.contains(
+            "\nrelayd_output_duration_seconds_bucket{output=\"report_upstream\",le=\"0.01\"} 0\n"
+        ));
+        assert!(metrics.contains(
+            "\nrelayd_output_duration_seconds_bucket{output=\"report_upstream\",le=\"0.025\"} 1\n"
+        ));
+        assert!(metrics.contains(
+            "\nrelayd_output_duration_seconds_bucket{output=\"report_upstream\",le=\"+Inf\"} 1\n"
+        ));
+        assert!(metrics
+            .contains("\nrelayd_output_duration_seconds_sum{output=\"report_upstream\"} 0.02\n"));
+        assert!(metrics.contains("\nrelayd_queue_depth{queue=\"reporting\"} 3\n"));
+        assert!(metrics.contains("\nrelayd_failed_files{type=\"reporting\"} 2\n"));
+        assert!(metrics.contains("\nrelayd_failed_bytes{type=\"reporting\"} 1024\n"));
+        assert!(!metrics.contains("relayd_database_connections"));
+    }
+}
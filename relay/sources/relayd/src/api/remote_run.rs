@@ -1,21 +1,57 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+mod deadline;
+mod event;
+mod job;
+mod process;
+
+pub use self::job::RemoteRunJobs;
+use self::{
+    deadline::Deadline,
+    event::{lines, OutputFormat, RunEvent, RunEventKind},
+    job::JobId,
+    process::Terminate,
+};
 use crate::{
-    configuration::main::RemoteRun as RemoteRunCfg, data::node::Host, error::Error, JobConfig,
+    api::ApiResponse,
+    configuration::main::RemoteRun as RemoteRunCfg,
+    data::node::{Host, NodeId, NodeIdRef},
+    error::Error,
+    processing::send_events,
+    stats::{Event, ForwardFailure},
+    JobConfig,
+};
+use futures::{
+    future::{self, Either},
+    stream,
+    sync::mpsc,
+    Future, Stream,
 };
-use futures::{Future, Stream};
 use hyper::{Body, Chunk};
+use rand::Rng;
 use regex::Regex;
+use serde::Serialize;
 use std::{
+    cmp::max,
     collections::HashMap,
-    io::BufReader,
+    error::Error as StdError,
+    io::{self, BufReader},
     process::{Command, Stdio},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+use tokio_process::Child;
+use tracing::{debug, error, info, span, trace, warn, Level};
+use warp::http::{
+    header::{HeaderValue, CONTENT_TYPE},
+    Response,
 };
-use tokio_process::{Child, CommandExt};
-use tracing::{debug, error, span, trace, Level};
 
 // From futures_stream_select_all crate (https://github.com/swizard0/futures-stream-select-all)
 // Will be in future versions of futures
@@ -62,10 +98,124 @@ where
     }
 }
 
-#[derive(Debug)]
+/// Runs items by batches of `batch_size`, with at most `max_parallel_batches`
+/// batches running at the same time
+///
+/// Batches are distributed among `max_parallel_batches` lanes running their
+/// batches one after the other, each batch starting after a random delay
+/// within the spread window.
+fn batches<T, I, S, F>(
+    items: Vec<T>,
+    cfg: &RemoteRunCfg,
+    run: F,
+) -> Box<dyn Stream<Item = I, Error = Error> + Send>
+where
+    T: Send + 'static,
+    I: Send + 'static,
+    S: Stream<Item = I, Error = Error> + Send + 'static,
+    F: Fn(Vec<T>) -> S + Send + Sync + 'static,
+{
+    let batch_size = if cfg.batch_size == 0 {
+        items.len()
+    } else {
+        cfg.batch_size
+    };
+    let mut lanes: Vec<Vec<Vec<T>>> = (0..max(cfg.max_parallel_batches, 1))
+        .map(|_| vec![])
+        .collect();
+    let lanes_number = lanes.len();
+    let mut items = items.into_iter().peekable();
+    let mut batch_number = 0;
+    while items.peek().is_some() {
+        lanes[batch_number % lanes_number].push(items.by_ref().take(batch_size).collect());
+        batch_number += 1;
+    }
+    trace!(
+        "Running {} batches of {} items at most",
+        batch_number,
+        batch_size
+    );
+
+    let run = Arc::new(run);
+    let spread = cfg.spread;
+    let lanes: Vec<_> = lanes
+        .into_iter()
+        .filter(|lane| !lane.is_empty())
+        .map(|lane| {
+            let run = run.clone();
+            stream::iter_ok::<_, Error>(lane)
+                .map(move |batch| {
+                    let run = run.clone();
+                    let wait = if spread.as_millis() == 0 {
+                        Either::A(future::ok(()))
+                    } else {
+                        Either::B(Delay::new(Instant::now() + jitter(spread)).map_err(Error::from))
+                    };
+                    wait.map(move |_| run(batch)).flatten_stream()
+                })
+                .flatten()
+        })
+        .collect();
+    select_all(lanes)
+}
+
+/// Runs items with at most `max_parallel` of them running at the same time,
+/// 0 meaning no limit
+///
+/// Items are distributed among `max_parallel` lanes running their items one
+/// after the other.
+fn parallel<T, I, S, F>(
+    items: Vec<T>,
+    max_parallel: usize,
+    run: F,
+) -> Box<dyn Stream<Item = I, Error = Error> + Send>
+where
+    T: Send + 'static,
+    I: Send + 'static,
+    S: Stream<Item = I, Error = Error> + Send + 'static,
+    F: Fn(T) -> S + Send + Sync + 'static,
+{
+    let max_parallel = if max_parallel == 0 {
+        items.len()
+    } else {
+        max_parallel
+    };
+    let mut lanes: Vec<Vec<T>> = (0..max(max_parallel, 1)).map(|_| vec![]).collect();
+    let lanes_number = lanes.len();
+    for (item_number, item) in items.into_iter().enumerate() {
+        lanes[item_number % lanes_number].push(item);
+    }
+
+    let run = Arc::new(run);
+    select_all(
+        lanes
+            .into_iter()
+            .filter(|lane| !lane.is_empty())
+            .map(|lane| {
+                let run = run.clone();
+                stream::iter_ok::<_, Error>(lane)
+                    .map(move |item| run(item))
+                    .flatten()
+            })
+            .collect::<Vec<_>>(),
+    )
+}
+
+/// Picks a delay within the spread window
+fn jitter(spread: Duration) -> Duration {
+    Duration::from_millis(rand::thread_rng().gen_range(0, spread.as_millis() as u64))
+}
+
+#[derive(Serialize, Debug)]
+struct StartedJob {
+    id: JobId,
+}
+
+#[derive(Debug, Clone)]
 pub struct RemoteRun {
     target: RemoteRunTarget,
     run_parameters: RunParameters,
+    output_format: OutputFormat,
 }
 
 impl RemoteRun {
@@ -81,111 +231,169 @@ impl RemoteRun {
                     options.get("classes")
                 },
             )?,
+            output_format: match options.get("format") {
+                Some(format) => format.parse()?,
+                None => OutputFormat::default(),
+            },
         })
     }
 
-    fn consume(
-        stream: impl Stream<Item = Chunk, Error = Error> + Send + 'static,
-    ) -> impl Future<Item = (), Error = ()> {
-        stream
-            .for_each(|l| {
-                trace!("Read {:#?}", l);
-                Ok(())
-            })
-            .map_err(|e| error!("Stream error: {}", e))
-    }
-
     pub fn run(
         &self,
         job_config: Arc<JobConfig>,
+        stats: mpsc::Sender<Event>,
     ) -> Result<impl warp::reply::Reply, warp::reject::Rejection> {
         debug!(
-            "Starting remote run (asynchronous: {}, keep_output: {})",
-            self.run_parameters.asynchronous, self.run_parameters.keep_output
+            "Starting remote run (asynchronous: {}, keep_output: {}, format: {})",
+            self.run_parameters.asynchronous, self.run_parameters.keep_output, self.output_format
         );
-        match (
-            self.run_parameters.asynchronous,
-            self.run_parameters.keep_output,
-        ) {
-            // Async and output -> spawn in background and stream output
-            (true, true) => Ok(warp::reply::html(Body::wrap_stream(
-                self.run_parameters
-                    .remote_run(
-                        &job_config.cfg.remote_run,
-                        self.target.neighbors(job_config.clone()),
-                        self.run_parameters.asynchronous,
-                    )
-                    .select(select_all(
-                        self.target
-                            .next_hops(job_config.clone())
-                            .iter()
-                            .map(|(relay, target)| {
-                                self.forward_call(job_config.clone(), relay.clone(), target.clone())
-                            }),
-                    )),
-            ))),
-            // Async and no output -> spawn in background and return early
-            (true, false) => {
-                for (relay, target) in self.target.next_hops(job_config.clone()) {
-                    tokio::spawn(RemoteRun::consume(self.forward_call(
-                        job_config.clone(),
-                        relay,
-                        target,
-                    )));
-                }
-                tokio::spawn(RemoteRun::consume(self.run_parameters.remote_run(
+
+        // Async and no output -> run in background and return the job id
+        if self.run_parameters.asynchronous && !self.run_parameters.keep_output {
+            return Ok(self.start_job(job_config, stats));
+        }
+
+        let output: Box<dyn Stream<Item = Chunk, Error = Error> + Send> = match self.output_format {
+            OutputFormat::Text => self.text_output(job_config, stats),
+            OutputFormat::Json => Box::new(self.events(job_config, stats).map(|e| e.to_chunk())),
+        };
+        // Output -> stream output (or the end of the runs for sync runs)
+        // No output -> wait until the end and only return timeouts and forwarding errors
+        // Events -> stream events, without output lines when not kept
+        Ok(RemoteRun::reply(
+            Body::wrap_stream(output),
+            match self.output_format {
+                OutputFormat::Text => "text/html; charset=utf-8",
+                OutputFormat::Json => "application/x-ndjson",
+            },
+        ))
+    }
+
+    fn reply(body: Body, content_type: &'static str) -> Response<Body> {
+        let mut response = Response::new(body);
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        response
+    }
+
+    /// Runs in the background, tracked as a job with the output and exit
+    /// status of each node
+    fn start_job(&self, job_config: Arc<JobConfig>, stats: mpsc::Sender<Event>) -> Response<Body> {
+        let tracked = RemoteRun {
+            target: self.target.clone(),
+            run_parameters: RunParameters {
+                asynchronous: false,
+                keep_output: true,
+                conditions: self.run_parameters.conditions.clone(),
+            },
+            output_format: OutputFormat::Json,
+        };
+        let targets = match &self.target {
+            RemoteRunTarget::Nodes(nodes) => nodes.clone(),
+            RemoteRunTarget::All => vec![],
+        };
+
+        let (id, task) = job_config
+            .remote_run_jobs
+            .clone()
+            .start(&targets, tracked.events(job_config.clone(), stats));
+        info!("Started remote run job {}", id);
+        tokio::spawn(job_config.until_shutdown(task));
+
+        RemoteRun::reply(
+            Body::from(
+                serde_json::to_string(&ApiResponse::new::<Error>(
+                    "remoteRun",
+                    Ok(Some(StartedJob { id })),
+                    None,
+                ))
+                .expect("could not serialize job"),
+            ),
+            "application/json",
+        )
+    }
+
+    /// Raw output of local and forwarded runs
+    fn text_output(
+        &self,
+        job_config: Arc<JobConfig>,
+        stats: mpsc::Sender<Event>,
+    ) -> Box<dyn Stream<Item = Chunk, Error = Error> + Send> {
+        let remote_run = self.clone();
+        let forward_config = job_config.clone();
+        let forwarded = batches(
+            self.target.next_hops(job_config.clone()),
+            &job_config.cfg.remote_run,
+            move |relays| {
+                select_all(
+                    relays
+                        .into_iter()
+                        .map(|hop| {
+                            remote_run.forward_call(forward_config.clone(), hop, stats.clone())
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            },
+        );
+
+        Box::new(
+            self.run_parameters
+                .remote_run(
                     &job_config.cfg.remote_run,
-                    self.target.neighbors(job_config.clone()),
+                    self.target
+                        .neighbors(job_config.clone())
+                        .into_iter()
+                        .map(|(_, hostname)| hostname)
+                        .collect(),
                     self.run_parameters.asynchronous,
-                )));
+                )
+                .select(forwarded),
+        )
+    }
 
-                Ok(warp::reply::html(Body::empty()))
-            }
-            // Sync and no output -> wait until the send and return empty output
-            (false, false) => Ok(warp::reply::html(Body::wrap_stream(
-                self.run_parameters
-                    .remote_run(
-                        &job_config.cfg.remote_run,
-                        self.target.neighbors(job_config.clone()),
-                        self.run_parameters.asynchronous,
-                    )
-                    .map(|_| Chunk::from(""))
-                    .select(select_all(
-                        self.target
-                            .next_hops(job_config.clone())
-                            .iter()
-                            .map(|(relay, target)| {
-                                self.forward_call(job_config.clone(), relay.clone(), target.clone())
-                            }),
-                    )),
-            ))),
-            // Sync and output -> wait until the end and return output
-            (false, true) => Ok(warp::reply::html(Body::wrap_stream(
-                self.run_parameters
-                    .remote_run(
-                        &job_config.cfg.remote_run,
-                        self.target.neighbors(job_config.clone()),
-                        self.run_parameters.asynchronous,
-                    )
-                    .select(select_all(
-                        self.target
-                            .next_hops(job_config.clone())
-                            .iter()
-                            .map(|(relay, target)| {
-                                self.forward_call(job_config.clone(), relay.clone(), target.clone())
-                            }),
-                    )),
-            ))),
-        }
+    /// Events of local and forwarded runs
+    fn events(
+        &self,
+        job_config: Arc<JobConfig>,
+        stats: mpsc::Sender<Event>,
+    ) -> Box<dyn Stream<Item = RunEvent, Error = Error> + Send> {
+        let remote_run = self.clone();
+        let forward_config = job_config.clone();
+        let forwarded = batches(
+            self.target.next_hops(job_config.clone()),
+            &job_config.cfg.remote_run,
+            move |relays| {
+                select_all(
+                    relays
+                        .into_iter()
+                        .map(|hop| {
+                            remote_run.forward_events(forward_config.clone(), hop, stats.clone())
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            },
+        );
+
+        Box::new(
+            self.run_parameters
+                .remote_run_events(
+                    &job_config.cfg.remote_run,
+                    &job_config.cfg.general.node_id,
+                    self.target.neighbors(job_config.clone()),
+                )
+                .select(forwarded),
+        )
     }
 
-    fn forward_call(
+    fn forward_request(
         &self,
         job_config: Arc<JobConfig>,
         node: Host,
         // Target for the sub relay
         target: RemoteRunTarget,
-    ) -> impl Stream<Item = Chunk, Error = Error> + Send + 'static {
+    ) -> impl Future<Item = reqwest::r#async::Response, Error = reqwest::Error> + Send + 'static
+    {
         let report_span = span!(Level::TRACE, "upstream");
         let _report_enter = report_span.enter();
 
@@ -205,6 +413,7 @@ impl RemoteRun {
                 .collect::<Vec<&str>>()
                 .join(","),
         );
+        params.insert("format", self.output_format.to_string());
         if let RemoteRunTarget::Nodes(nodes) = &target {
             params.insert("nodes", nodes.join(","));
         }
@@ -222,19 +431,236 @@ impl RemoteRun {
             ))
             .form(&params)
             .send()
-            .map(|response| response.into_body())
-            .flatten_stream()
-            .map_err(|e| {
-                error!("{}", e);
-                e.into()
+    }
+
+    fn forward_call(
+        &self,
+        job_config: Arc<JobConfig>,
+        hop: NextHop,
+        stats: mpsc::Sender<Event>,
+    ) -> impl Stream<Item = Chunk, Error = Error> + Send + 'static {
+        let timeout = job_config.cfg.remote_run.forward_timeout;
+        let timed_out = {
+            let hop = hop.clone();
+            let stats = stats.clone();
+            move || {
+                let message = format!(
+                    "Remote run forwarded to {} timed out after {}\n",
+                    hop.hostname,
+                    humantime::format_duration(timeout)
+                );
+                warn!("{}", message.trim_end());
+                tokio::spawn(send_events(
+                    vec![Event::RemoteRunForwardFailed(ForwardFailure::Timeout)],
+                    stats,
+                ));
+                Chunk::from(message)
+            }
+        };
+
+        Deadline::new(
+            self.forward_request(job_config, hop.hostname.clone(), hop.target.clone())
+                .and_then(|response| response.error_for_status())
+                .map(|response| response.into_body())
+                .flatten_stream()
+                .map(|c| c.into())
+                // Don't fail if a relay is not available,
+                // report it along with the nodes it should have triggered
+                .or_else(move |e: reqwest::Error| {
+                    let failure = ForwardError::from(e);
+                    failure.report(&hop, stats.clone());
+                    Ok::<_, Error>(Chunk::from(format!(
+                        "Remote run could not be forwarded to {} ({}): {}, nodes not triggered: {}\n",
+                        hop.hostname,
+                        failure.reason,
+                        failure.error,
+                        hop.nodes.join(",")
+                    )))
+                }),
+            timeout,
+            timed_out,
+        )
+    }
+
+    /// Events of a sub-relay, with this relay added to their path
+    ///
+    /// Failures, including responses without any valid event, are reported as a
+    /// `relay_unreachable` event.
+    fn forward_events(
+        &self,
+        job_config: Arc<JobConfig>,
+        hop: NextHop,
+        stats: mpsc::Sender<Event>,
+    ) -> impl Stream<Item = RunEvent, Error = Error> + Send + 'static {
+        let my_id = job_config.cfg.general.node_id.clone();
+        let timeout = job_config.cfg.remote_run.forward_timeout;
+        let timed_out = {
+            let relay_id = hop.id.clone();
+            let my_id = my_id.clone();
+            let stats = stats.clone();
+            move || {
+                warn!("Remote run forwarded to {} timed out", relay_id);
+                tokio::spawn(send_events(
+                    vec![Event::RemoteRunForwardFailed(ForwardFailure::Timeout)],
+                    stats,
+                ));
+                RunEvent::new(
+                    &relay_id,
+                    &my_id,
+                    RunEventKind::TimedOut {
+                        timeout: timeout.as_secs(),
+                    },
+                )
+            }
+        };
+        // Whether the sub-relay answered with events, or failed in a way already reported
+        let answered = Arc::new(AtomicBool::new(false));
+        let unreachable = {
+            let hop = hop.clone();
+            let my_id = my_id.clone();
+            let stats = stats.clone();
+            let answered = answered.clone();
+            move |e: reqwest::Error| {
+                answered.store(true, Ordering::Relaxed);
+                let failure = ForwardError::from(e);
+                failure.report(&hop, stats.clone());
+                Ok::<_, Error>(RunEvent::new(
+                    &hop.id,
+                    &my_id,
+                    RunEventKind::RelayUnreachable {
+                        reason: failure.reason,
+                        status: failure.status,
+                        error: failure.error,
+                        not_triggered: hop.nodes.clone(),
+                    },
+                ))
+            }
+        };
+
+        // An empty or unparsable response would otherwise leave the nodes
+        // behind the sub-relay without any event
+        let invalid_response = {
+            let hop = hop.clone();
+            let my_id = my_id.clone();
+            let answered = answered.clone();
+            future::lazy(move || {
+                if answered.load(Ordering::Relaxed) {
+                    return Ok(None);
+                }
+                let failure = ForwardError {
+                    reason: ForwardFailure::InvalidResponse,
+                    status: None,
+                    error: "no valid remote run event in response".to_string(),
+                };
+                failure.report(&hop, stats);
+                Ok::<_, Error>(Some(RunEvent::new(
+                    &hop.id,
+                    &my_id,
+                    RunEventKind::RelayUnreachable {
+                        reason: failure.reason,
+                        status: failure.status,
+                        error: failure.error,
+                        not_triggered: hop.nodes,
+                    },
+                )))
             })
-            // Don't fail if a relay is not available,
-            // just log it
-            .or_else(|_: Error| futures::future::empty())
-            .map(|c| c.into())
+        };
+
+        let relay_id = hop.id.clone();
+        let events = lines(
+            self.forward_request(job_config, hop.hostname, hop.target)
+                .and_then(|response| response.error_for_status())
+                .map(|response| response.into_body())
+                .flatten_stream()
+                .map(|c| c.into()),
+        )
+        .filter_map(move |line| match serde_json::from_str::<RunEvent>(&line) {
+            Ok(event) => {
+                answered.store(true, Ordering::Relaxed);
+                Some(event.forwarded(&my_id))
+            }
+            Err(e) => {
+                warn!("invalid remote run event from {}: {}", relay_id, e);
+                None
+            }
+        })
+        .or_else(unreachable)
+        .chain(invalid_response.into_stream().filter_map(|event| event));
+        Deadline::new(events, timeout, timed_out)
     }
 }
 
+/// Why a call forwarded to a sub-relay failed
+#[derive(Debug)]
+struct ForwardError {
+    reason: ForwardFailure,
+    status: Option<u16>,
+    error: String,
+}
+
+impl ForwardError {
+    fn report(&self, hop: &NextHop, stats: mpsc::Sender<Event>) {
+        error!(
+            "Remote run could not be forwarded to {} ({}): {}, nodes not triggered: {:?}",
+            hop.hostname, hop.id, self.error, hop.nodes
+        );
+        tokio::spawn(send_events(
+            vec![
+                Event::RemoteRunForwardFailed(self.reason),
+                Event::RemoteRunNotTriggered(hop.nodes.len()),
+            ],
+            stats,
+        ));
+    }
+}
+
+impl From<reqwest::Error> for ForwardError {
+    fn from(error: reqwest::Error) -> Self {
+        let reason = if error.status().is_some() {
+            ForwardFailure::Status
+        } else if error.is_timeout() {
+            ForwardFailure::Timeout
+        } else if is_tls_error(&error) {
+            ForwardFailure::Tls
+        } else {
+            ForwardFailure::Connection
+        };
+        Self {
+            reason,
+            status: error.status().map(|s| s.as_u16()),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Looks for an openssl error in the causes, as TLS errors are only exposed
+/// as connection errors
+fn is_tls_error(error: &reqwest::Error) -> bool {
+    let mut current = error.get_ref().map(|e| e as &(dyn StdError + 'static));
+    while let Some(e) = current {
+        if e.is::<openssl::ssl::Error>() || e.is::<openssl::error::ErrorStack>() {
+            return true;
+        }
+        // io errors don't give the wrapped error as source
+        current = match e.downcast_ref::<io::Error>() {
+            Some(e) => e.get_ref().map(|e| e as &(dyn StdError + 'static)),
+            None => e.source(),
+        };
+    }
+    false
+}
+
+/// Sub-relay to forward a run to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NextHop {
+    pub id: NodeId,
+    pub hostname: Host,
+    /// Target for the sub-relay
+    pub target: RemoteRunTarget,
+    /// Target nodes reached through the sub-relay
+    pub nodes: Vec<NodeId>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RemoteRunTarget {
     All,
@@ -242,28 +668,40 @@ pub enum RemoteRunTarget {
 }
 
 impl RemoteRunTarget {
-    pub fn neighbors(&self, job_config: Arc<JobConfig>) -> Vec<Host> {
+    /// Nodes to trigger directly, with their hostnames
+    pub fn neighbors(&self, job_config: Arc<JobConfig>) -> Vec<(NodeId, Host)> {
         let nodes = job_config.nodes.read().expect("Cannot read nodes list");
         let neighbors = match self {
-            RemoteRunTarget::All => nodes.my_neighbors(),
-            RemoteRunTarget::Nodes(nodeslist) => nodes.my_neighbors_from(nodeslist),
+            RemoteRunTarget::All => nodes.my_neighbor_nodes(),
+            RemoteRunTarget::Nodes(nodeslist) => nodes.my_neighbor_nodes_from(nodeslist),
         };
         debug!("Neighbors: {:#?}", neighbors);
         neighbors
     }
 
-    pub fn next_hops(&self, job_config: Arc<JobConfig>) -> Vec<(Host, RemoteRunTarget)> {
+    /// Sub-relays to forward the run to
+    pub fn next_hops(&self, job_config: Arc<JobConfig>) -> Vec<NextHop> {
         let nodes = job_config.nodes.read().expect("Cannot read nodes list");
         let next_hops = match self {
             RemoteRunTarget::All => nodes
-                .my_sub_relays()
+                .my_sub_relay_nodes()
                 .into_iter()
-                .map(|r| (r, RemoteRunTarget::All))
+                .map(|(id, hostname)| NextHop {
+                    nodes: nodes.nodes_behind(&id),
+                    id,
+                    hostname,
+                    target: RemoteRunTarget::All,
+                })
                 .collect(),
             RemoteRunTarget::Nodes(nodeslist) => nodes
-                .my_sub_relays_from(nodeslist)
+                .my_sub_relay_nodes_from(nodeslist)
                 .into_iter()
-                .map(|(relay, nodes)| (relay, RemoteRunTarget::Nodes(nodes)))
+                .map(|(id, hostname, nodes)| NextHop {
+                    id,
+                    hostname,
+                    target: RemoteRunTarget::Nodes(nodes.clone()),
+                    nodes,
+                })
                 .collect(),
         };
         debug!("Next-hops: {:#?}", next_hops);
@@ -271,7 +709,7 @@ impl RemoteRunTarget {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct Condition {
     data: String,
 }
@@ -302,7 +740,7 @@ impl FromStr for Condition {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 struct RunParameters {
     asynchronous: bool,
     keep_output: bool,
@@ -381,34 +819,156 @@ impl RunParameters {
             return Box::new(futures::stream::empty());
         }
 
+        let parameters = self.clone();
+        let batch_cfg = cfg.clone();
+        batches(nodes, cfg, move |batch| {
+            parameters.run_command(&batch_cfg, batch, asynchronous)
+        })
+    }
+
+    /// Triggers the nodes with a single command
+    fn run_command(
+        &self,
+        cfg: &RemoteRunCfg,
+        nodes: Vec<String>,
+        asynchronous: bool,
+    ) -> Box<dyn Stream<Item = Chunk, Error = Error> + Send + 'static> {
+        let timed_out = format!(
+            "Remote run on {} timed out after {}\n",
+            nodes.join(","),
+            humantime::format_duration(cfg.run_timeout)
+        );
         let mut cmd = self.command(cfg, nodes);
         cmd.stdout(Stdio::piped());
 
-        match (asynchronous, cmd.spawn_async()) {
-            (false, Ok(c)) => Box::new(
-                // send output at once
-                c.wait_with_output()
-                    .map(|o| o.stdout)
-                    .map(Chunk::from)
-                    .map_err(|e| e.into())
-                    .into_stream(),
-            ),
-            (true, Ok(mut c)) => {
-                // stream lines
-                let lines = RunParameters::lines_stream(&mut c);
-                tokio::spawn(c.map(|_| ()).map_err(|_| ()));
-                Box::new(lines)
+        // The child is owned by the stream, so that it is stopped when the
+        // client disconnects or the timeout fires
+        let output: Box<dyn Stream<Item = Chunk, Error = Error> + Send> =
+            match (asynchronous, process::spawn(&mut cmd)) {
+                (false, Ok(c)) => {
+                    let pid = c.id();
+                    Box::new(Terminate::new(
+                        // send output at once
+                        c.wait_with_output()
+                            .map(|o| o.stdout)
+                            .map(Chunk::from)
+                            .map_err(|e| e.into())
+                            .into_stream(),
+                        pid,
+                    ))
+                }
+                (true, Ok(mut c)) => {
+                    // stream lines
+                    let lines = RunParameters::lines_stream(&mut c);
+                    let pid = c.id();
+                    Box::new(Terminate::new(
+                        lines
+                            .map(Some)
+                            .chain(c.map(|_| None).map_err(Error::from).into_stream())
+                            .filter_map(|line| line),
+                        pid,
+                    ))
+                }
+                (_, Err(e)) => {
+                    error!("Remote run error while running '{:#?}': {}", cmd, e);
+                    return Box::new(futures::stream::once(Err(e.into())));
+                }
+            };
+        let output: Box<dyn Stream<Item = Chunk, Error = Error> + Send> = if self.keep_output {
+            output
+        } else {
+            Box::new(output.filter(|_| false))
+        };
+        Box::new(Deadline::new(output, cfg.run_timeout, move || {
+            warn!("{}", timed_out.trim_end());
+            Chunk::from(timed_out)
+        }))
+    }
+
+    /// Triggers the nodes separately, to get their own output and exit status
+    fn remote_run_events(
+        &self,
+        cfg: &RemoteRunCfg,
+        relay_id: &NodeIdRef,
+        nodes: Vec<(NodeId, Host)>,
+    ) -> Box<dyn Stream<Item = RunEvent, Error = Error> + Send + 'static> {
+        trace!("Starting local remote run on {:#?} with {:#?}", nodes, cfg);
+
+        let parameters = self.clone();
+        let batch_cfg = cfg.clone();
+        let relay_id = relay_id.to_string();
+        batches(nodes, cfg, move |batch| {
+            let parameters = parameters.clone();
+            let node_cfg = batch_cfg.clone();
+            let relay_id = relay_id.clone();
+            parallel(
+                batch,
+                batch_cfg.max_parallel_runs,
+                move |(node_id, hostname)| {
+                    parameters.node_events(&node_cfg, &relay_id, node_id, hostname)
+                },
+            )
+        })
+    }
+
+    fn node_events(
+        &self,
+        cfg: &RemoteRunCfg,
+        relay_id: &NodeIdRef,
+        node_id: NodeId,
+        hostname: Host,
+    ) -> Box<dyn Stream<Item = RunEvent, Error = Error> + Send + 'static> {
+        let mut cmd = self.command(cfg, vec![hostname]);
+        cmd.stdout(Stdio::piped());
+
+        let relay_id = relay_id.to_string();
+        let event = move |kind| RunEvent::new(&node_id, &relay_id, kind);
+        let output_event = event.clone();
+        let finished_event = event.clone();
+        let timed_out_event = event.clone();
+        let timeout = cfg.run_timeout;
+
+        match process::spawn(&mut cmd) {
+            Ok(mut child) => {
+                let keep_output = self.keep_output;
+                // Output is always read, so that the agent does not block on it
+                let output = RunParameters::output_lines(&mut child)
+                    .filter(move |_| keep_output)
+                    .map(move |line| output_event(RunEventKind::Output { line }));
+                let pid = child.id();
+                let finished = child.map_err(Error::from).map(move |status| {
+                    finished_event(RunEventKind::Finished {
+                        exit_code: status.code(),
+                    })
+                });
+
+                Box::new(Deadline::new(
+                    Terminate::new(
+                        stream::once(Ok(event(RunEventKind::Started)))
+                            .chain(output)
+                            .chain(finished.into_stream()),
+                        pid,
+                    ),
+                    timeout,
+                    move || {
+                        let event = timed_out_event(RunEventKind::TimedOut {
+                            timeout: timeout.as_secs(),
+                        });
+                        warn!("Remote run on {} timed out", event.node_id);
+                        event
+                    },
+                ))
             }
-            (_, Err(e)) => {
+            Err(e) => {
                 error!("Remote run error while running '{:#?}': {}", cmd, e);
-                Box::new(futures::stream::once(Err(e.into())))
+                Box::new(stream::once(Err(e.into())))
             }
         }
     }
 
-    fn lines_stream(
+    fn output_lines(
         child: &mut Child,
-    ) -> impl Stream<Item = Chunk, Error = Error> + Send + 'static {
+    ) -> impl Stream<Item = String, Error = Error> + Send + 'static {
         let stdout = child
             .stdout()
             .take()
@@ -417,6 +977,12 @@ impl RunParameters {
         tokio_io::io::lines(BufReader::new(stdout))
             .map_err(Error::from)
             .inspect(|line| debug!("output: {}", line))
+    }
+
+    fn lines_stream(
+        child: &mut Child,
+    ) -> impl Stream<Item = Chunk, Error = Error> + Send + 'static {
+        RunParameters::output_lines(child)
             .map(|mut l| {
                 l.push('\n');
                 l
@@ -429,6 +995,61 @@ impl RunParameters {
 mod tests {
     use super::*;
 
+    #[test]
+    fn it_runs_by_batches() {
+        let cfg = RemoteRunCfg {
+            batch_size: 2,
+            max_parallel_batches: 2,
+            ..RemoteRunCfg::default()
+        };
+        let mut runs = batches((1..=5).collect(), &cfg, |batch: Vec<u32>| {
+            stream::once(Ok(batch))
+        })
+        .collect()
+        .wait()
+        .unwrap();
+        runs.sort();
+        assert_eq!(runs, vec![vec![1, 2], vec![3, 4], vec![5]]);
+
+        let cfg = RemoteRunCfg {
+            batch_size: 0,
+            ..RemoteRunCfg::default()
+        };
+        let runs = batches((1..=5).collect(), &cfg, |batch: Vec<u32>| {
+            stream::once(Ok(batch))
+        })
+        .collect()
+        .wait()
+        .unwrap();
+        assert_eq!(runs, vec![vec![1, 2, 3, 4, 5]]);
+    }
+
+    #[test]
+    fn it_limits_parallel_runs() {
+        let mut runs = parallel((1..=5).collect(), 2, |item: u32| {
+            stream::iter_ok(vec![item, item * 10])
+        })
+        .collect()
+        .wait()
+        .unwrap();
+        runs.sort();
+        assert_eq!(runs, vec![1, 2, 3, 4, 5, 10, 20, 30, 40, 50]);
+
+        // Each lane runs its items one after the other
+        let lanes = parallel((1..=5).collect(), 2, |item: u32| stream::once(Ok(item)))
+            .collect()
+            .wait()
+            .unwrap();
+        let first_lane: Vec<u32> = lanes.iter().filter(|i| *i % 2 == 1).cloned().collect();
+        assert_eq!(first_lane, vec![1, 3, 5]);
+
+        let runs = parallel((1..=5).collect(), 0, |item: u32| stream::once(Ok(item)))
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(runs.len(), 5);
+    }
+
     #[test]
     fn it_handles_command_injection() {
         assert!(Condition::from_str("cl$$y").is_err());
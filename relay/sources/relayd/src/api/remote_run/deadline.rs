@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::error::Error;
+use futures::{Async, Poll, Stream};
+use std::time::{Duration, Instant};
+use tokio::timer::Delay;
+
+/// Stream stopped after a timeout, ending with an item reporting it
+///
+/// The inner stream is dropped when the timeout fires, which stops the
+/// child processes it owns.
+pub struct Deadline<S, F> {
+    stream: Option<S>,
+    delay: Delay,
+    on_timeout: Option<F>,
+}
+
+impl<S, F> Deadline<S, F>
+where
+    S: Stream<Error = Error>,
+    F: FnOnce() -> S::Item,
+{
+    pub fn new(stream: S, timeout: Duration, on_timeout: F) -> Self {
+        Self {
+            stream: Some(stream),
+            delay: Delay::new(Instant::now() + timeout),
+            on_timeout: Some(on_timeout),
+        }
+    }
+}
+
+impl<S, F> Stream for Deadline<S, F>
+where
+    S: Stream<Error = Error>,
+    F: FnOnce() -> S::Item,
+{
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            // Over or timed out
+            None => return Ok(Async::Ready(None)),
+        };
+        match stream.poll()? {
+            Async::Ready(Some(item)) => return Ok(Async::Ready(Some(item))),
+            Async::Ready(None) => {
+                self.stream = None;
+                return Ok(Async::Ready(None));
+            }
+            Async::NotReady => (),
+        }
+
+        match self.delay.poll()? {
+            Async::Ready(()) => {
+                self.stream = None;
+                let on_timeout = self.on_timeout.take().expect("timeout already reported");
+                Ok(Async::Ready(Some(on_timeout())))
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, Future};
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn it_stops_streams_after_timeout() {
+        let mut runtime = Runtime::new().unwrap();
+
+        let finished = Deadline::new(
+            stream::iter_ok(vec!["line"]),
+            Duration::from_secs(10),
+            || "timeout",
+        );
+        assert_eq!(runtime.block_on(finished.collect()).unwrap(), vec!["line"]);
+
+        let never_ends = stream::iter_ok(vec!["line"]).chain(stream::poll_fn(
+            || -> Poll<Option<&'static str>, Error> { Ok(Async::NotReady) },
+        ));
+        let stopped = Deadline::new(never_ends, Duration::from_millis(10), || "timeout");
+        assert_eq!(
+            runtime.block_on(stopped.collect()).unwrap(),
+            vec!["line", "timeout"]
+        );
+    }
+}
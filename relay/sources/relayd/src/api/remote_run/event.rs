@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    data::node::{NodeId, NodeIdRef},
+    error::Error,
+    stats::ForwardFailure,
+};
+use futures::{stream, Stream};
+use hyper::Chunk;
+use serde::{Deserialize, Serialize};
+use std::{fmt, mem, str::FromStr};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Agent output, as is
+    Text,
+    /// Newline-delimited JSON events
+    Json,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Text
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(Error::InvalidOutputFormat(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                OutputFormat::Text => "text",
+                OutputFormat::Json => "json",
+            }
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RunEventKind {
+    Started,
+    /// A line of agent output
+    Output {
+        line: String,
+    },
+    /// No exit code when the agent was killed by a signal
+    Finished {
+        exit_code: Option<i32>,
+    },
+    /// The event's node is the relay which could not be contacted
+    RelayUnreachable {
+        reason: ForwardFailure,
+        /// Status code of a non-2xx response
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        status: Option<u16>,
+        error: String,
+        /// Target nodes behind the relay
+        #[serde(default)]
+        not_triggered: Vec<NodeId>,
+    },
+    /// The run was stopped, or the call forwarded to the relay when the
+    /// event's node is a relay
+    TimedOut {
+        /// In seconds
+        timeout: u64,
+    },
+}
+
+/// Event of a structured remote run, sent as a JSON line
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RunEvent {
+    pub node_id: NodeId,
+    /// Relays the event went through, from the one which received the
+    /// request to the one which triggered the node
+    pub relay_path: Vec<NodeId>,
+    #[serde(flatten)]
+    pub kind: RunEventKind,
+}
+
+impl RunEvent {
+    pub fn new(node_id: &NodeIdRef, relay_id: &NodeIdRef, kind: RunEventKind) -> Self {
+        Self {
+            node_id: node_id.to_string(),
+            relay_path: vec![relay_id.to_string()],
+            kind,
+        }
+    }
+
+    /// Event received from a sub-relay
+    pub fn forwarded(mut self, relay_id: &NodeIdRef) -> Self {
+        self.relay_path.insert(0, relay_id.to_string());
+        self
+    }
+
+    pub fn to_chunk(&self) -> Chunk {
+        let mut line = serde_json::to_string(self).expect("could not serialize event");
+        line.push('\n');
+        Chunk::from(line)
+    }
+}
+
+/// Splits a stream of chunks into lines, without line breaks
+pub fn lines<S>(chunks: S) -> impl Stream<Item = String, Error = S::Error>
+where
+    S: Stream<Item = Chunk>,
+{
+    let mut buffer = vec![];
+    chunks
+        .map(Some)
+        // Marks the end of the stream, to get the last unterminated line
+        .chain(stream::once(Ok(None)))
+        .map(move |chunk| {
+            let mut lines = vec![];
+            match chunk {
+                Some(chunk) => {
+                    buffer.extend_from_slice(&chunk);
+                    while let Some(end) = buffer.iter().position(|b| *b == b'\n') {
+                        let line: Vec<u8> = buffer.drain(..=end).collect();
+                        lines.push(String::from_utf8_lossy(&line[..end]).to_string());
+                    }
+                }
+                None if !buffer.is_empty() => lines
+                    .push(String::from_utf8_lossy(&mem::replace(&mut buffer, vec![])).to_string()),
+                None => (),
+            }
+            stream::iter_ok::<_, S::Error>(lines)
+        })
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn it_serializes_events() {
+        let event = RunEvent::new(
+            "node",
+            "relay",
+            RunEventKind::Finished { exit_code: Some(0) },
+        )
+        .forwarded("root");
+        let serialized =
+            r#"{"node_id":"node","relay_path":["root","relay"],"event":"finished","exit_code":0}"#;
+
+        assert_eq!(
+            &event.to_chunk()[..],
+            format!("{}\n", serialized).as_bytes()
+        );
+        assert_eq!(serde_json::from_str::<RunEvent>(serialized).unwrap(), event);
+        assert_eq!(
+            serde_json::from_str::<RunEvent>(
+                r#"{"node_id":"node","relay_path":["relay"],"event":"started"}"#
+            )
+            .unwrap(),
+            RunEvent::new("node", "relay", RunEventKind::Started)
+        );
+
+        let unreachable = RunEvent::new(
+            "relay",
+            "root",
+            RunEventKind::RelayUnreachable {
+                reason: ForwardFailure::Status,
+                status: Some(503),
+                error: "HTTP status server error (503 Service Unavailable)".to_string(),
+                not_triggered: vec!["node".to_string()],
+            },
+        );
+        let serialized = r#"{"node_id":"relay","relay_path":["root"],"event":"relay_unreachable","reason":"status","status":503,"error":"HTTP status server error (503 Service Unavailable)","not_triggered":["node"]}"#;
+        assert_eq!(
+            &unreachable.to_chunk()[..],
+            format!("{}\n", serialized).as_bytes()
+        );
+        assert_eq!(
+            serde_json::from_str::<RunEvent>(serialized).unwrap(),
+            unreachable
+        );
+    }
+
+    #[test]
+    fn it_splits_lines() {
+        let chunks = stream::iter_ok::<_, Error>(vec![
+            Chunk::from("first\nsec"),
+            Chunk::from("ond\n"),
+            Chunk::from("\nlast"),
+        ]);
+        assert_eq!(
+            lines(chunks).collect().wait().unwrap(),
+            vec![
+                "first".to_string(),
+                "second".to_string(),
+                "".to_string(),
+                "last".to_string()
+            ]
+        );
+    }
+}
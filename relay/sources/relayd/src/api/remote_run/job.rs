@@ -0,0 +1,671 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use super::event::{RunEvent, RunEventKind};
+use crate::{configuration::main::RemoteRun as RemoteRunCfg, data::node::NodeId, error::Error};
+use chrono::{DateTime, Duration, Utc};
+use futures::{
+    future::{err, ok, poll_fn, Either},
+    sync::oneshot,
+    Future, Stream,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use tokio::timer::Interval;
+use tokio_threadpool::blocking;
+use tracing::{debug, info, warn};
+
+const JOB_EXTENSION: &str = "json";
+/// Replaces the output lines exceeding the limit
+const TRUNCATED_OUTPUT: &str = "[output truncated]";
+/// Frequency of the removal of expired jobs
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+pub type JobId = String;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeState {
+    /// Not triggered yet
+    Pending,
+    Running,
+    /// The agent exited with code 0
+    Success,
+    Failure,
+    /// Only for relays, their nodes could not be triggered
+    Unreachable,
+    /// Stopped after the run timeout, or the forwarding timeout for relays
+    TimedOut,
+    Cancelled,
+    /// Never triggered, as unknown or behind an unreachable relay
+    Skipped,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeRun {
+    pub state: NodeState,
+    pub relay_path: Vec<NodeId>,
+    pub started: Option<DateTime<Utc>>,
+    pub finished: Option<DateTime<Utc>>,
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+    /// Agent output lines
+    pub output: Vec<String>,
+}
+
+impl NodeRun {
+    fn pending() -> Self {
+        Self {
+            state: NodeState::Pending,
+            relay_path: vec![],
+            started: None,
+            finished: None,
+            exit_code: None,
+            error: None,
+            output: vec![],
+        }
+    }
+
+    fn is_over(&self) -> bool {
+        self.state != NodeState::Pending && self.state != NodeState::Running
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Finished,
+    /// Could not be completed, see the job's error
+    Failed,
+    Cancelled,
+    /// The relay was stopped while it was running
+    Interrupted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub id: JobId,
+    pub state: JobState,
+    pub created: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    pub error: Option<String>,
+    pub nodes: BTreeMap<NodeId, NodeRun>,
+}
+
+impl Job {
+    /// Targets are pending until they are started, other nodes are added when
+    /// their first event is received
+    pub fn new(id: JobId, targets: &[NodeId], now: DateTime<Utc>) -> Self {
+        Self {
+            id,
+            state: JobState::Running,
+            created: now,
+            finished: None,
+            error: None,
+            nodes: targets
+                .iter()
+                .map(|n| (n.clone(), NodeRun::pending()))
+                .collect(),
+        }
+    }
+
+    pub fn is_over(&self) -> bool {
+        self.state != JobState::Running
+    }
+
+    /// Only the first `max_output_lines` lines of output of each node are kept,
+    /// 0 meaning no limit
+    pub fn apply(&mut self, event: RunEvent, max_output_lines: usize, now: DateTime<Utc>) {
+        if self.is_over() {
+            return;
+        }
+
+        let node = self
+            .nodes
+            .entry(event.node_id.clone())
+            .or_insert_with(NodeRun::pending);
+        node.relay_path = event.relay_path;
+        match event.kind {
+            RunEventKind::Started => {
+                node.state = NodeState::Running;
+                node.started = Some(now);
+            }
+            RunEventKind::Output { line } => {
+                if max_output_lines == 0 || node.output.len() < max_output_lines {
+                    node.output.push(line)
+                } else if node.output.len() == max_output_lines {
+                    node.output.push(TRUNCATED_OUTPUT.to_string())
+                }
+            }
+            RunEventKind::Finished { exit_code } => {
+                node.state = if exit_code == Some(0) {
+                    NodeState::Success
+                } else {
+                    NodeState::Failure
+                };
+                node.exit_code = exit_code;
+                node.finished = Some(now);
+            }
+            RunEventKind::RelayUnreachable {
+                reason,
+                error,
+                not_triggered,
+                ..
+            } => {
+                node.state = NodeState::Unreachable;
+                node.error = Some(format!("{}: {}", reason, error));
+                node.finished = Some(now);
+                for id in not_triggered {
+                    let node = self.nodes.entry(id).or_insert_with(NodeRun::pending);
+                    if !node.is_over() {
+                        node.state = NodeState::Skipped;
+                        node.error = Some(format!("relay {} unreachable", event.node_id));
+                        node.finished = Some(now);
+                    }
+                }
+            }
+            RunEventKind::TimedOut { timeout } => {
+                node.state = NodeState::TimedOut;
+                node.error = Some(format!("timed out after {}s", timeout));
+                node.finished = Some(now);
+            }
+        }
+    }
+
+    /// Ends the job, with the state of nodes which did not finish
+    fn end(&mut self, state: JobState, remaining: NodeState, now: DateTime<Utc>) {
+        if self.is_over() {
+            return;
+        }
+
+        self.state = state;
+        self.finished = Some(now);
+        for node in self.nodes.values_mut().filter(|n| !n.is_over()) {
+            node.state = remaining;
+            node.finished = Some(now);
+        }
+    }
+
+    pub fn finish(&mut self, result: Result<(), &Error>, now: DateTime<Utc>) {
+        match result {
+            Ok(()) => self.end(JobState::Finished, NodeState::Skipped, now),
+            Err(e) => {
+                self.end(JobState::Failed, NodeState::Skipped, now);
+                self.error = Some(e.to_string());
+            }
+        }
+    }
+
+    pub fn cancel(&mut self, now: DateTime<Utc>) {
+        self.end(JobState::Cancelled, NodeState::Cancelled, now)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    jobs: HashMap<JobId, Job>,
+    /// Stops running jobs
+    cancel: HashMap<JobId, oneshot::Sender<()>>,
+}
+
+/// Asynchronous remote runs, kept in memory and optionally on disk
+#[derive(Debug)]
+pub struct RemoteRunJobs {
+    registry: Mutex<Registry>,
+    /// Held while storing a job, so that the latest state is written last
+    storage: Mutex<()>,
+    directory: Option<PathBuf>,
+    retention: Duration,
+    max_output_lines: usize,
+}
+
+impl RemoteRunJobs {
+    /// Loads stored jobs, the running ones having been interrupted
+    pub fn new(cfg: &RemoteRunCfg) -> Result<Self, Error> {
+        let jobs = Self {
+            registry: Mutex::new(Registry::default()),
+            storage: Mutex::new(()),
+            directory: cfg.jobs_directory.clone(),
+            retention: Duration::from_std(cfg.job_retention)
+                .expect("job retention is out of range"),
+            max_output_lines: cfg.max_output_lines,
+        };
+
+        if let Some(directory) = &cfg.jobs_directory {
+            fs::create_dir_all(directory)?;
+            let now = Utc::now();
+            for mut job in RemoteRunJobs::load(directory)? {
+                let id = job.id.clone();
+                let interrupted = !job.is_over();
+                if interrupted {
+                    info!("remote run job {} was interrupted", id);
+                    job.end(JobState::Interrupted, NodeState::Skipped, now);
+                }
+                jobs.registry
+                    .lock()
+                    .expect("could not lock remote run jobs")
+                    .jobs
+                    .insert(id.clone(), job);
+                if interrupted {
+                    jobs.store(&id);
+                }
+            }
+        }
+        Ok(jobs)
+    }
+
+    fn load(directory: &Path) -> Result<Vec<Job>, Error> {
+        let mut jobs = vec![];
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.extension().map(|e| e != JOB_EXTENSION).unwrap_or(true) {
+                continue;
+            }
+            match fs::read_to_string(&path)
+                .map_err(Error::from)
+                .and_then(|c| serde_json::from_str(&c).map_err(Error::from))
+            {
+                Ok(job) => jobs.push(job),
+                Err(e) => warn!("could not read remote run job {:?}: {}", path, e),
+            }
+        }
+        Ok(jobs)
+    }
+
+    fn path(directory: &Path, id: &str) -> PathBuf {
+        directory.join(format!("{}.{}", id, JOB_EXTENSION))
+    }
+
+    /// Stores the current state of a job, in the thread pool
+    fn save(self: Arc<Self>, id: JobId) -> impl Future<Item = (), Error = ()> + Send {
+        if self.directory.is_none() {
+            return Either::A(ok(()));
+        }
+        Either::B(poll_fn(move || blocking(|| self.store(&id))).then(|res| {
+            res.expect("the thread pool shut down");
+            Ok(())
+        }))
+    }
+
+    /// Writes the current state of a job, blocking
+    fn store(&self, id: &str) {
+        let directory = match &self.directory {
+            Some(directory) => directory,
+            None => return,
+        };
+        let _storage = self
+            .storage
+            .lock()
+            .expect("could not lock remote run jobs storage");
+        let job = match self
+            .registry
+            .lock()
+            .expect("could not lock remote run jobs")
+            .jobs
+            .get(id)
+        {
+            Some(job) => job.clone(),
+            // Already forgotten
+            None => return,
+        };
+        if let Err(e) = serde_json::to_string(&job)
+            .map_err(Error::from)
+            .and_then(|c| fs::write(RemoteRunJobs::path(directory, id), c).map_err(Error::from))
+        {
+            warn!("could not store remote run job {}: {}", id, e);
+        }
+    }
+
+    /// Periodically forgets expired jobs
+    pub fn cleanup_expired(self: Arc<Self>) -> impl Future<Item = (), Error = ()> + Send {
+        Interval::new(Instant::now(), CLEANUP_INTERVAL)
+            .map_err(|e| warn!("interval error: {}", e))
+            .for_each(move |_instant| {
+                let jobs = self.clone();
+                poll_fn(move || {
+                    blocking(|| {
+                        let mut registry = jobs
+                            .registry
+                            .lock()
+                            .expect("could not lock remote run jobs");
+                        jobs.cleanup(&mut registry, Utc::now())
+                    })
+                })
+                .then(|res| {
+                    res.expect("the thread pool shut down");
+                    Ok(())
+                })
+            })
+    }
+
+    /// Forgets jobs finished for longer than the retention
+    fn cleanup(&self, registry: &mut Registry, now: DateTime<Utc>) {
+        let expired: Vec<JobId> = registry
+            .jobs
+            .values()
+            .filter(|j| {
+                j.finished
+                    .map(|f| f + self.retention < now)
+                    .unwrap_or(false)
+            })
+            .map(|j| j.id.clone())
+            .collect();
+        for id in expired {
+            debug!("forgetting remote run job {}", id);
+            registry.jobs.remove(&id);
+            if let Some(directory) = &self.directory {
+                match fs::remove_file(RemoteRunJobs::path(directory, &id)) {
+                    Ok(()) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => warn!("could not remove remote run job {}: {}", id, e),
+                }
+            }
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Result<Job, Error> {
+        self.registry
+            .lock()
+            .expect("could not lock remote run jobs")
+            .jobs
+            .get(id)
+            .cloned()
+            .ok_or_else(|| Error::UnknownRemoteRunJob(id.to_string()))
+    }
+
+    /// Stops a running job, does nothing if it is already over
+    pub fn cancel(self: Arc<Self>, id: &str) -> impl Future<Item = Job, Error = Error> + Send {
+        let job = {
+            let mut registry = self
+                .registry
+                .lock()
+                .expect("could not lock remote run jobs");
+            let job = match registry.jobs.get_mut(id) {
+                Some(job) => job,
+                None => return Either::A(err(Error::UnknownRemoteRunJob(id.to_string()))),
+            };
+            if !job.is_over() {
+                info!("cancelling remote run job {}", id);
+                job.cancel(Utc::now());
+            }
+            let job = job.clone();
+            if let Some(cancel) = registry.cancel.remove(id) {
+                // The job may have ended in the meantime
+                let _ = cancel.send(());
+            }
+            job
+        };
+        Either::B(self.save(id.to_string()).then(move |_| Ok(job)))
+    }
+
+    /// Registers a new job and returns a task running it
+    pub fn start<S>(
+        self: Arc<Self>,
+        targets: &[NodeId],
+        events: S,
+    ) -> (JobId, impl Future<Item = (), Error = ()> + Send)
+    where
+        S: Stream<Item = RunEvent, Error = Error> + Send,
+    {
+        let now = Utc::now();
+        let id = format!("{:032x}", rand::thread_rng().gen::<u128>());
+        let job = Job::new(id.clone(), targets, now);
+        let (cancel_tx, cancel_rx) = oneshot::channel();
+        {
+            let mut registry = self
+                .registry
+                .lock()
+                .expect("could not lock remote run jobs");
+            registry.jobs.insert(id.clone(), job);
+            registry.cancel.insert(id.clone(), cancel_tx);
+        }
+        debug!("started remote run job {}", id);
+
+        let jobs = self.clone();
+        let job_id = id.clone();
+        let max_output_lines = self.max_output_lines;
+        let jobs_end = self.clone();
+        let job_id_end = id.clone();
+        let task = self.save(id.clone()).and_then(move |_| {
+            events
+                .for_each(move |event| {
+                    jobs.update(&job_id, |job| {
+                        job.apply(event, max_output_lines, Utc::now())
+                    });
+                    Ok(())
+                })
+                // Dropping the events stream stops the runs
+                .select2(cancel_rx)
+                .then(move |res| match res {
+                    Ok(Either::A(_)) => Either::A(jobs_end.end(&job_id_end, Ok(()))),
+                    Err(Either::A((e, _))) => Either::A(jobs_end.end(&job_id_end, Err(&e))),
+                    // Already marked as cancelled
+                    Ok(Either::B(_)) | Err(Either::B(_)) => Either::B(ok(())),
+                })
+        });
+        (id, task)
+    }
+
+    fn update<F: FnOnce(&mut Job)>(&self, id: &str, f: F) {
+        if let Some(job) = self
+            .registry
+            .lock()
+            .expect("could not lock remote run jobs")
+            .jobs
+            .get_mut(id)
+        {
+            f(job)
+        }
+    }
+
+    fn end(
+        self: Arc<Self>,
+        id: &str,
+        result: Result<(), &Error>,
+    ) -> impl Future<Item = (), Error = ()> + Send {
+        {
+            let mut registry = self
+                .registry
+                .lock()
+                .expect("could not lock remote run jobs");
+            registry.cancel.remove(id);
+            match registry.jobs.get_mut(id) {
+                Some(job) => {
+                    job.finish(result, Utc::now());
+                    debug!("remote run job {} is over: {:?}", id, job.state);
+                }
+                None => return Either::A(ok(())),
+            }
+        }
+        Either::B(self.save(id.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::ForwardFailure;
+    use futures::{stream, Async, Poll};
+    use tempfile::tempdir;
+    use tokio::runtime::Runtime;
+
+    fn event(node_id: &str, kind: RunEventKind) -> RunEvent {
+        RunEvent::new(node_id, "root", kind)
+    }
+
+    #[test]
+    fn it_tracks_node_states() {
+        let now = Utc::now();
+        let mut job = Job::new(
+            "id".to_string(),
+            &[
+                "node1".to_string(),
+                "node2".to_string(),
+                "node3".to_string(),
+            ],
+            now,
+        );
+        job.apply(event("node1", RunEventKind::Started), 0, now);
+        job.apply(
+            event(
+                "node1",
+                RunEventKind::Output {
+                    line: "OK".to_string(),
+                },
+            ),
+            0,
+            now,
+        );
+        job.apply(
+            event("node1", RunEventKind::Finished { exit_code: Some(0) }),
+            0,
+            now,
+        );
+        job.apply(event("node2", RunEventKind::Started), 0, now);
+        job.apply(
+            event("node2", RunEventKind::Finished { exit_code: Some(1) }),
+            0,
+            now,
+        );
+        job.apply(
+            event(
+                "relay",
+                RunEventKind::RelayUnreachable {
+                    reason: ForwardFailure::Connection,
+                    status: None,
+                    error: "refused".to_string(),
+                    not_triggered: vec!["node5".to_string()],
+                },
+            ),
+            0,
+            now,
+        );
+        assert_eq!(job.nodes["node1"].state, NodeState::Success);
+        assert_eq!(job.nodes["node1"].output, vec!["OK".to_string()]);
+        for line in &["1", "2", "3"] {
+            job.apply(
+                event(
+                    "node2",
+                    RunEventKind::Output {
+                        line: line.to_string(),
+                    },
+                ),
+                2,
+                now,
+            );
+        }
+        assert_eq!(
+            job.nodes["node2"].output,
+            vec![
+                "1".to_string(),
+                "2".to_string(),
+                TRUNCATED_OUTPUT.to_string()
+            ]
+        );
+        assert_eq!(job.nodes["node2"].state, NodeState::Failure);
+        assert_eq!(job.nodes["node3"].state, NodeState::Pending);
+        job.apply(
+            event("node4", RunEventKind::TimedOut { timeout: 60 }),
+            0,
+            now,
+        );
+        assert_eq!(job.nodes["relay"].state, NodeState::Unreachable);
+        assert_eq!(
+            job.nodes["relay"].error,
+            Some("connection: refused".to_string())
+        );
+        assert_eq!(job.nodes["node5"].state, NodeState::Skipped);
+        assert_eq!(job.nodes["node4"].state, NodeState::TimedOut);
+
+        job.finish(Ok(()), now);
+        assert_eq!(job.state, JobState::Finished);
+        assert_eq!(job.nodes["node3"].state, NodeState::Skipped);
+
+        // Over
+        job.cancel(now);
+        assert_eq!(job.state, JobState::Finished);
+    }
+
+    #[test]
+    fn it_stores_jobs() {
+        let dir = tempdir().unwrap();
+        let cfg = RemoteRunCfg {
+            jobs_directory: Some(dir.path().to_path_buf()),
+            ..RemoteRunCfg::default()
+        };
+        let jobs = Arc::new(RemoteRunJobs::new(&cfg).unwrap());
+        let mut runtime = Runtime::new().unwrap();
+
+        let (finished, task) = jobs.clone().start(
+            &["root".to_string()],
+            stream::iter_ok(vec![
+                event("root", RunEventKind::Started),
+                event("root", RunEventKind::Finished { exit_code: Some(0) }),
+            ]),
+        );
+        runtime.block_on(task).unwrap();
+        let (running, _task) = jobs
+            .clone()
+            .start(&["root".to_string()], stream::empty::<_, Error>());
+        // Done first by the task
+        runtime
+            .block_on(jobs.clone().save(running.clone()))
+            .unwrap();
+
+        assert_eq!(jobs.get(&finished).unwrap().state, JobState::Finished);
+        assert_eq!(jobs.get(&running).unwrap().state, JobState::Running);
+        assert!(jobs.get("unknown").is_err());
+
+        // Restarted
+        let jobs = RemoteRunJobs::new(&cfg).unwrap();
+        assert_eq!(jobs.get(&finished).unwrap().state, JobState::Finished);
+        assert_eq!(
+            jobs.get(&finished).unwrap().nodes["root"].state,
+            NodeState::Success
+        );
+        assert_eq!(jobs.get(&running).unwrap().state, JobState::Interrupted);
+    }
+
+    #[test]
+    fn it_forgets_expired_jobs() {
+        let jobs = Arc::new(RemoteRunJobs::new(&RemoteRunCfg::default()).unwrap());
+        let (id, task) = jobs
+            .clone()
+            .start(&["root".to_string()], stream::empty::<_, Error>());
+        task.wait().unwrap();
+        let finished = jobs.get(&id).unwrap().finished.unwrap();
+
+        let mut registry = jobs.registry.lock().unwrap();
+        jobs.cleanup(&mut registry, finished + jobs.retention);
+        assert!(registry.jobs.contains_key(&id));
+        jobs.cleanup(
+            &mut registry,
+            finished + jobs.retention + Duration::seconds(1),
+        );
+        assert!(!registry.jobs.contains_key(&id));
+    }
+
+    #[test]
+    fn it_cancels_jobs() {
+        let jobs = Arc::new(RemoteRunJobs::new(&RemoteRunCfg::default()).unwrap());
+        // Never ends
+        let events = stream::poll_fn(|| -> Poll<Option<RunEvent>, Error> { Ok(Async::NotReady) });
+        let (id, task) = jobs.clone().start(&["root".to_string()], events);
+        assert_eq!(
+            jobs.clone().cancel(&id).wait().unwrap().state,
+            JobState::Cancelled
+        );
+        task.wait().unwrap();
+        let job = jobs.get(&id).unwrap();
+        assert_eq!(job.state, JobState::Cancelled);
+        assert_eq!(job.nodes["root"].state, NodeState::Cancelled);
+    }
+}
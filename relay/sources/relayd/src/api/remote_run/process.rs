@@ -0,0 +1,114 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::error::Error;
+use futures::{Async, Poll, Stream};
+use std::{io, os::unix::process::CommandExt as _, process::Command};
+use tokio_process::{Child, CommandExt};
+use tracing::debug;
+
+/// Starts the command in its own process group, to be able to stop
+/// all the processes it starts
+pub fn spawn(cmd: &mut Command) -> io::Result<Child> {
+    // setsid is async-signal-safe, so it can be called between fork and exec
+    unsafe {
+        cmd.pre_exec(|| {
+            if libc::setsid() == -1 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(())
+            }
+        });
+    }
+    let mut child = cmd.spawn_async()?;
+    // Killed by `Terminate` instead, the process is still reaped when dropped
+    child.forget();
+    Ok(child)
+}
+
+/// Stream reading a process, which stops it when dropped before the end
+///
+/// A `SIGKILL` on the child, as done by `tokio_process`, would only stop `sudo`
+/// and leave the agent running. The whole process group is sent a `SIGTERM`
+/// instead, which `sudo` relays to the agent.
+pub struct Terminate<S> {
+    stream: S,
+    pid: libc::pid_t,
+    exited: bool,
+}
+
+impl<S> Terminate<S> {
+    /// `stream` must end once the child process `pid` has exited
+    pub fn new(stream: S, pid: u32) -> Self {
+        Self {
+            stream,
+            pid: pid as libc::pid_t,
+            exited: false,
+        }
+    }
+}
+
+impl<S> Stream for Terminate<S>
+where
+    S: Stream<Error = Error>,
+{
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let res = self.stream.poll();
+        if let Ok(Async::Ready(None)) = res {
+            self.exited = true;
+        }
+        res
+    }
+}
+
+impl<S> Drop for Terminate<S> {
+    fn drop(&mut self) {
+        if !self.exited {
+            debug!("stopping process group {}", self.pid);
+            // The group may already be gone
+            unsafe {
+                libc::kill(-self.pid, libc::SIGTERM);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{process::Stdio, thread, time::Duration};
+    use tokio::runtime::Runtime;
+
+    fn is_running(pid: libc::pid_t) -> bool {
+        unsafe { libc::kill(pid, 0) == 0 }
+    }
+
+    #[test]
+    fn it_stops_the_process_group() {
+        let mut runtime = Runtime::new().unwrap();
+
+        // Relays SIGTERM to the agent like sudo does
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg("trap 'kill $agent; wait $agent; exit' TERM; sleep 30 & agent=$!; echo $agent; wait")
+            .stdout(Stdio::piped());
+        let mut child = runtime
+            .block_on(futures::lazy(move || spawn(&mut cmd)))
+            .unwrap();
+        let stdout = child.stdout().take().unwrap();
+        let lines = tokio_io::io::lines(io::BufReader::new(stdout)).map_err(Error::from);
+        let (agent, lines) = runtime
+            .block_on(Terminate::new(lines, child.id()).into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        let agent: libc::pid_t = agent.unwrap().parse().unwrap();
+        assert!(is_running(agent));
+
+        drop(lines);
+        thread::sleep(Duration::from_millis(500));
+        assert!(!is_running(agent));
+    }
+}
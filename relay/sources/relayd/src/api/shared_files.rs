@@ -2,26 +2,55 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use crate::{
-    data::shared_file::{Metadata, SharedFile},
+    data::{
+        node::{Host, NodeIdRef},
+        shared_file::{Metadata, SharedFile},
+    },
     error::Error,
+    hashing::{Hash, Hasher},
     JobConfig,
 };
-use bytes::IntoBuf;
+use bytes::Bytes;
 use chrono::Utc;
-use futures::future::Future;
+use futures::{
+    future::{err, ok, poll_fn, result, Either, Future},
+    stream, Stream,
+};
 use hex;
 use humantime::parse_duration;
+use hyper::Body;
+use openssl::x509::X509;
 use serde::{Deserialize, Serialize};
 use std::{
     fs,
-    io::{BufRead, BufReader, Read},
+    io::{self, Write},
+    path::{Path, PathBuf},
     str,
     str::FromStr,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
     time::Duration,
 };
-use tracing::{debug, span, warn, Level};
-use warp::{body::FullBody, http::StatusCode, Buf};
+use tokio::codec::{BytesCodec, FramedRead};
+use tokio_threadpool::blocking;
+use tracing::{debug, error, span, warn, Level};
+use warp::{
+    body::BodyStream,
+    http::{header::CONTENT_LENGTH, Response, StatusCode},
+    Buf,
+};
+
+/// Header containing the client certificate (PEM), set by the reverse proxy
+pub const CLIENT_CERTIFICATE_HEADER: &str = "X-Rudder-Client-Certificate";
+/// Prefix of the headers containing the metadata of a downloaded file
+const METADATA_HEADER_PREFIX: &str = "X-Rudder-Metadata-";
+const METADATA_EXTENSION: &str = ".metadata";
+/// Extension of the temporary files of uploads in progress
+const UPLOAD_EXTENSION: &str = ".upload";
+/// Maximum size of the metadata preceding an uploaded file
+const MAX_HEADER_SIZE: usize = 64 * 1024;
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct SharedFilesPutParams {
@@ -46,14 +75,64 @@ impl SharedFilesPutParams {
     }
 }
 
+/// Relay storing the files of a target node
+#[derive(Debug)]
+enum Destination {
+    /// Directly managed by this relay
+    Local,
+    /// Behind a sub-relay, with its hostname
+    SubRelay(Host),
+    /// Not under this relay
+    Upstream,
+}
+
+fn destination(target_id: &NodeIdRef, job_config: &JobConfig) -> Result<Destination, Error> {
+    let nodes = job_config.nodes.read().expect("Cannot read nodes list");
+    if nodes.is_subnode(target_id) {
+        match nodes.sub_relay_for(target_id) {
+            Ok(None) => Ok(Destination::Local),
+            Ok(Some(relay)) => Ok(Destination::SubRelay(relay)),
+            Err(()) => Err(Error::UnknownNode(target_id.to_string())),
+        }
+    } else if job_config.cfg.general.node_id == "root" {
+        Err(Error::UnknownNode(target_id.to_string()))
+    } else {
+        Ok(Destination::Upstream)
+    }
+}
+
+/// Base url of the API of a sub-relay
+fn sub_relay_url(hostname: &str) -> String {
+    format!("https://{}/rudder", hostname)
+}
+
+/// Status to reply with when forwarding a request to another relay
+fn forwarded_status(
+    server: &str,
+    response: Result<reqwest::r#async::Response, reqwest::Error>,
+) -> StatusCode {
+    match response {
+        Ok(response) => {
+            if !response.status().is_success() {
+                warn!("{} answered {}", server, response.status());
+            }
+            response.status()
+        }
+        Err(e) => {
+            error!("could not forward request to {}: {}", server, e);
+            StatusCode::BAD_GATEWAY
+        }
+    }
+}
+
 pub fn put(
     target_id: String,
     source_id: String,
     file_id: String,
     params: SharedFilesPutParams,
     job_config: Arc<JobConfig>,
-    body: FullBody,
-) -> Result<StatusCode, Error> {
+    body: BodyStream,
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send> {
     let span = span!(
         Level::INFO,
         "shared_files_put",
@@ -63,137 +142,447 @@ pub fn put(
     );
     let _enter = span.enter();
 
-    let file = SharedFile::new(source_id, target_id, file_id)?;
+    let file = match SharedFile::new(source_id, target_id, file_id) {
+        Ok(file) => file,
+        Err(e) => return Box::new(err(e)),
+    };
+    let destination = match destination(&file.target_id, &job_config) {
+        Ok(destination) => destination,
+        Err(e) => return Box::new(err(e)),
+    };
+    let known_key_hash = match source_key_hash(&file.source_id, &job_config) {
+        Ok(known_key_hash) => known_key_hash,
+        Err(e) => return Box::new(err(e)),
+    };
+    // The root server knows all nodes
+    if known_key_hash.is_none() && job_config.cfg.general.node_id == "root" {
+        warn!("unknown source {}", file.source_id);
+        return Box::new(ok(StatusCode::NOT_FOUND));
+    }
 
-    if job_config
-        .nodes
-        .read()
-        .expect("Cannot read nodes list")
-        .is_subnode(&file.target_id)
-    {
-        put_local(file, params, job_config, body)
-    } else if job_config.cfg.general.node_id == "root" {
-        Err(Error::UnknownNode(file.target_id))
-    } else {
-        put_forward(file, params, job_config, body)
+    match destination {
+        Destination::Local => put_local(file, params, known_key_hash, job_config, body),
+        Destination::SubRelay(relay) => put_forward(
+            file,
+            params,
+            sub_relay_url(&relay),
+            known_key_hash,
+            job_config,
+            body,
+        ),
+        Destination::Upstream => {
+            let upstream = job_config.cfg.output.upstream.url.clone();
+            put_forward(file, params, upstream, known_key_hash, job_config, body)
+        }
     }
 }
 
-fn put_forward(
-    file: SharedFile,
-    params: SharedFilesPutParams,
-    job_config: Arc<JobConfig>,
-    body: FullBody,
-) -> Result<StatusCode, Error> {
+/// Hash of the key of the source node, if it is known by this relay
+///
+/// Only the relays between the source node and the root server know it.
+/// Files from other nodes come through the upstream relay, which checks
+/// them before forwarding.
+fn source_key_hash(source_id: &NodeIdRef, job_config: &JobConfig) -> Result<Option<Hash>, Error> {
+    let nodes = job_config.nodes.read().expect("Cannot read nodes list");
+    if !nodes.is_subnode(source_id) {
+        return Ok(None);
+    }
+    match nodes.key_hash(source_id) {
+        Some(key_hash) => Ok(Some(key_hash)),
+        None => Err(Error::UnknownNode(source_id.to_string())),
+    }
+}
+
+/// Fails as soon as the received data exceeds the maximum size
+fn limit_size<S>(body: S, max_size: usize) -> impl Stream<Item = Vec<u8>, Error = Error>
+where
+    S: Stream<Error = Error>,
+    S::Item: Buf,
+{
+    let mut size = 0;
+    body.and_then(move |chunk| {
+        size += chunk.remaining();
+        if size > max_size {
+            Err(Error::LimitExceeded("shared file size", max_size))
+        } else {
+            Ok(chunk.collect::<Vec<u8>>())
+        }
+    })
+}
+
+/// Sends a shared file to another relay
+fn forward_request(
+    job_config: &JobConfig,
+    server: &str,
+    file: &SharedFile,
+    params: &SharedFilesPutParams,
+    body: reqwest::r#async::Body,
+) -> impl Future<Item = reqwest::r#async::Response, Error = reqwest::Error> {
     job_config
         .client
         .clone()
         .put(&format!(
             "{}/{}/{}",
-            job_config.cfg.output.upstream.url,
+            server,
             "relay-api/shared-files",
             file.url(),
         ))
-        .query(&params)
-        .body(body.into_buf().collect::<Vec<u8>>())
+        .query(params)
+        .body(body)
         .send()
-        .wait()
-        .map(|r| r.status())
-        .map_err(|e| e.into())
 }
 
-pub fn put_local(
+fn put_forward(
     file: SharedFile,
     params: SharedFilesPutParams,
+    server: String,
+    known_key_hash: Option<Hash>,
     job_config: Arc<JobConfig>,
-    body: FullBody,
-) -> Result<StatusCode, Error> {
-    if !job_config
-        .nodes
-        .read()
-        .expect("Cannot read nodes list")
-        .is_subnode(&file.source_id)
-    {
-        warn!("unknown source {}", file.source_id);
-        return Ok(StatusCode::NOT_FOUND);
-    }
+    body: BodyStream,
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send> {
+    debug!("forwarding shared file {} to {}", file.url(), server);
 
-    let mut stream = BufReader::new(body.into_buf().reader());
-    let mut raw_meta = String::new();
-    // Here we cannot iterate on lines as the file content may not be valid UTF-8.
-    let mut read = 2;
-    // Let's read while we find an empty line.
-    while read > 1 {
-        read = stream.read_line(&mut raw_meta)?;
+    // Relays not knowing the source can't check it, so do it before sending it further
+    if let Some(known_key_hash) = known_key_hash {
+        return Box::new(
+            receive(file.clone(), &job_config, body)
+                .and_then(move |upload| {
+                    in_thread_pool(upload, move |mut upload| {
+                        upload
+                            .check(Some(&known_key_hash))
+                            .map(|status| (upload, status))
+                    })
+                })
+                .and_then(move |(upload, status)| {
+                    if status != StatusCode::OK {
+                        return Either::A(ok(status));
+                    }
+                    let body = match upload.body() {
+                        Ok(body) => body,
+                        Err(e) => return Either::A(err(e)),
+                    };
+                    Either::B(
+                        forward_request(
+                            &job_config,
+                            &server,
+                            &file,
+                            &params,
+                            reqwest::r#async::Body::wrap_stream(body),
+                        )
+                        .then(move |response| {
+                            // Keep the temporary file until it is sent
+                            drop(upload);
+                            Ok(forwarded_status(&server, response))
+                        }),
+                    )
+                }),
+        );
     }
-    let meta = Metadata::from_str(&raw_meta)?;
 
-    let base_path = job_config
+    let max_size = job_config.cfg.shared_files.max_size;
+    // The client only keeps the description of body errors
+    let too_large = Arc::new(AtomicBool::new(false));
+    let body_too_large = too_large.clone();
+    let body = limit_size(body.map_err(Error::from), max_size).map_err(move |e| {
+        if let Error::LimitExceeded(_, _) = e {
+            body_too_large.store(true, Ordering::SeqCst);
+        }
+        e.to_string()
+    });
+
+    Box::new(
+        forward_request(
+            &job_config,
+            &server,
+            &file,
+            &params,
+            reqwest::r#async::Body::wrap_stream(body),
+        )
+        .then(move |response| {
+            if too_large.load(Ordering::SeqCst) {
+                Err(Error::LimitExceeded("shared file size", max_size))
+            } else {
+                Ok(forwarded_status(&server, response))
+            }
+        }),
+    )
+}
+
+/// Receives an uploaded file into a temporary file
+fn receive(
+    file: SharedFile,
+    job_config: &JobConfig,
+    body: BodyStream,
+) -> impl Future<Item = Upload, Error = Error> {
+    let directory = job_config
         .cfg
         .shared_files
         .path
         .join(&file.target_id)
         .join("files")
         .join(&file.source_id);
-    let pubkey = meta.pubkey()?;
 
-    let known_key_hash = job_config
-        .nodes
-        .read()
-        .expect("Cannot read nodes list")
-        .key_hash(&file.source_id)
-        .ok_or_else(|| Error::UnknownNode(file.source_id.to_string()))?;
-    let key_hash = known_key_hash.hash_type.hash(&pubkey.public_key_to_der()?);
-    if key_hash != known_key_hash {
-        warn!(
-            "hash of public key ({}) does not match known hash ({})",
-            key_hash, known_key_hash
-        );
-        return Ok(StatusCode::NOT_FOUND);
+    limit_size(
+        body.map_err(Error::from),
+        job_config.cfg.shared_files.max_size,
+    )
+    .fold(
+        Upload::new(directory, file, job_config.shared_files_lock.clone()),
+        |upload, chunk| {
+            in_thread_pool(upload, move |mut upload| {
+                upload.write(&chunk).map(|()| upload)
+            })
+        },
+    )
+}
+
+/// Runs an operation doing file system accesses or signature checks
+/// on the upload outside of the reactor
+fn in_thread_pool<T, F>(upload: Upload, operation: F) -> impl Future<Item = T, Error = Error>
+where
+    F: FnOnce(Upload) -> Result<T, Error>,
+{
+    // Only taken once the operation actually runs
+    let mut pending = Some((upload, operation));
+    poll_fn(move || {
+        blocking(|| {
+            let (upload, operation) = pending.take().expect("operation already ran");
+            operation(upload)
+        })
+    })
+    .then(|res| res.expect("the thread pool shut down"))
+}
+
+pub fn put_local(
+    file: SharedFile,
+    params: SharedFilesPutParams,
+    known_key_hash: Option<Hash>,
+    job_config: Arc<JobConfig>,
+    body: BodyStream,
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send> {
+    let ttl = match params.ttl() {
+        Ok(ttl) => ttl,
+        Err(e) => {
+            warn!("invalid ttl: {}", e);
+            return Box::new(ok(StatusCode::INTERNAL_SERVER_ERROR));
+        }
+    };
+
+    Box::new(receive(file, &job_config, body).and_then(move |upload| {
+        in_thread_pool(upload, move |upload| {
+            upload.finish(known_key_hash.as_ref(), ttl)
+        })
+    }))
+}
+
+/// Shared file being received
+///
+/// The content is written to a temporary file while its hash is computed,
+/// to avoid keeping large files in memory.
+struct Upload {
+    directory: PathBuf,
+    file: SharedFile,
+    /// Shared with the expired files cleanup
+    lock: Arc<Mutex<()>>,
+    /// Beginning of the body, until the empty line separating the metadata
+    /// from the file content
+    header: Vec<u8>,
+    content: Option<UploadContent>,
+}
+
+struct UploadContent {
+    metadata: Metadata,
+    hasher: Hasher,
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl UploadContent {
+    fn new(directory: &Path, file_id: &str, metadata: Metadata) -> Result<Self, Error> {
+        fs::create_dir_all(directory)?;
+        // Hidden and unique, as the same file can be uploaded concurrently
+        let path = directory.join(format!(
+            ".{}.{}{}",
+            file_id,
+            rand::random::<u32>(),
+            UPLOAD_EXTENSION
+        ));
+        Ok(Self {
+            hasher: metadata.hash.hash_type.hasher(),
+            metadata,
+            file: fs::File::create(&path)?,
+            path,
+        })
     }
 
-    // Read file content
-    let mut file_content = vec![];
-    stream.read_to_end(&mut file_content)?;
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.hasher.input(data);
+        self.file.write_all(data)?;
+        Ok(())
+    }
 
-    match meta.validate_signature(
-        &file_content,
-        meta.hash.hash_type,
-        &hex::decode(&meta.digest)?,
-    ) {
-        Ok(is_valid) => {
-            if !is_valid {
-                warn!("invalid signature");
+    /// Checks the received file against its metadata
+    ///
+    /// The key is only compared to the known one when the source node is known.
+    fn check(&self, file: &SharedFile, known_key_hash: Option<&Hash>) -> Result<StatusCode, Error> {
+        // Signed for another node, rudder-signature-v2 only
+        if let Some(target_id) = self.metadata.target_id() {
+            if target_id != file.target_id {
+                warn!("file was signed for another node ({})", target_id);
                 return Ok(StatusCode::INTERNAL_SERVER_ERROR);
             }
         }
-        Err(e) => {
-            warn!("error checking file signature: {}", e);
+
+        if let Some(known_key_hash) = known_key_hash {
+            let pubkey = self.metadata.pubkey()?;
+            let key_hash = known_key_hash.hash_type.hash(&pubkey.public_key_to_der()?);
+            if key_hash != *known_key_hash {
+                warn!(
+                    "hash of public key ({}) does not match known hash ({})",
+                    key_hash, known_key_hash
+                );
+                return Ok(StatusCode::NOT_FOUND);
+            }
+        }
+
+        let digest = self.hasher.clone().result();
+        if !hex::encode(&digest).eq_ignore_ascii_case(&self.metadata.hash.value) {
+            warn!("hash of the file does not match its metadata");
             return Ok(StatusCode::INTERNAL_SERVER_ERROR);
         }
-    }
 
-    // Everything is correct, let's store the file
-    fs::create_dir_all(&base_path)?;
-    fs::write(
-        &base_path.join(format!("{}.metadata", file.file_id)),
-        format!(
-            "{}expires={}\n",
-            meta,
-            match params.ttl() {
-                // Removal timestamp = now + ttl
-                Ok(ttl) => (Utc::now()
-                    + chrono::Duration::from_std(ttl).expect("Unexpectedly large duration"))
-                .timestamp(),
-                Err(e) => {
-                    warn!("invalid ttl: {}", e);
+        match self.metadata.validate_signature_digest(
+            &digest,
+            self.metadata.hash.hash_type,
+            &hex::decode(&self.metadata.digest)?,
+        ) {
+            Ok(is_valid) => {
+                if !is_valid {
+                    warn!("invalid signature");
                     return Ok(StatusCode::INTERNAL_SERVER_ERROR);
                 }
             }
-        ),
-    )?;
-    fs::write(&base_path.join(file.file_id), file_content)?;
-    Ok(StatusCode::OK)
+            Err(e) => {
+                warn!("error checking file signature: {}", e);
+                return Ok(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+        Ok(StatusCode::OK)
+    }
+
+    /// Moves the checked file into place
+    fn store(
+        self,
+        directory: &Path,
+        file: &SharedFile,
+        ttl: Duration,
+        lock: &Mutex<()>,
+    ) -> Result<(), Error> {
+        let mut metadata = self.metadata;
+        // Removal timestamp = now + ttl
+        metadata.expires = Some(
+            (Utc::now() + chrono::Duration::from_std(ttl).expect("Unexpectedly large duration"))
+                .timestamp(),
+        );
+        // Prevents the cleanup from removing the new file along with the expired metadata
+        let _cleanup = lock.lock().expect("shared files lock poisoned");
+        fs::write(
+            directory.join(format!("{}{}", file.file_id, METADATA_EXTENSION)),
+            metadata.to_string(),
+        )?;
+        fs::rename(&self.path, directory.join(&file.file_id))?;
+        Ok(())
+    }
+}
+
+impl Upload {
+    fn new(directory: PathBuf, file: SharedFile, lock: Arc<Mutex<()>>) -> Self {
+        Self {
+            directory,
+            file,
+            lock,
+            header: vec![],
+            content: None,
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        if let Some(ref mut content) = self.content {
+            return content.write(data);
+        }
+
+        // Only look for the separator in new data, the previous byte included
+        let start = self.header.len().saturating_sub(1);
+        self.header.extend_from_slice(data);
+        let end = self.header[start..]
+            .windows(2)
+            .position(|w| w == b"\n\n")
+            .map(|position| start + position);
+        if end.unwrap_or_else(|| self.header.len()) > MAX_HEADER_SIZE {
+            return Err(Error::LimitExceeded(
+                "shared file metadata size",
+                MAX_HEADER_SIZE,
+            ));
+        }
+        if let Some(end) = end {
+            let data = self.header.split_off(end + 2);
+            let metadata = Metadata::from_str(str::from_utf8(&self.header)?)?;
+            let mut content = UploadContent::new(&self.directory, &self.file.file_id, metadata)?;
+            content.write(&data)?;
+            self.content = Some(content);
+        }
+        Ok(())
+    }
+
+    /// Checks the whole received file
+    fn check(&mut self, known_key_hash: Option<&Hash>) -> Result<StatusCode, Error> {
+        if self.content.is_none() {
+            // Empty file
+            self.content = Some(UploadContent::new(
+                &self.directory,
+                &self.file.file_id,
+                Metadata::from_str(str::from_utf8(&self.header)?)?,
+            )?);
+        }
+        self.content
+            .as_ref()
+            .expect("missing upload content")
+            .check(&self.file, known_key_hash)
+    }
+
+    /// Received body, to forward it once checked
+    fn body(&self) -> Result<impl Stream<Item = Bytes, Error = String>, Error> {
+        let content = fs::File::open(&self.content.as_ref().expect("missing upload content").path)?;
+        Ok(stream::once(Ok(Bytes::from(self.header.clone()))).chain(
+            FramedRead::new(tokio::fs::File::from_std(content), BytesCodec::new())
+                .map(|chunk| chunk.freeze())
+                .map_err(|e| e.to_string()),
+        ))
+    }
+
+    /// Checks the received file and moves it into place
+    fn finish(mut self, known_key_hash: Option<&Hash>, ttl: Duration) -> Result<StatusCode, Error> {
+        let status = self.check(known_key_hash)?;
+        if status != StatusCode::OK {
+            return Ok(status);
+        }
+        let content = self.content.take().expect("missing upload content");
+        let path = content.path.clone();
+        let res = content.store(&self.directory, &self.file, ttl, &self.lock);
+        if res.is_err() {
+            let _ = fs::remove_file(path);
+        }
+        res.map(|()| StatusCode::OK)
+    }
+}
+
+impl Drop for Upload {
+    /// Removes the temporary file of an interrupted upload
+    fn drop(&mut self) {
+        if let Some(ref content) = self.content {
+            let _ = fs::remove_file(&content.path);
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -207,7 +596,7 @@ pub fn head(
     file_id: String,
     params: SharedFilesHeadParams,
     job_config: Arc<JobConfig>,
-) -> Result<StatusCode, Error> {
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send> {
     let span = span!(
         Level::INFO,
         "shared_files_head",
@@ -217,41 +606,45 @@ pub fn head(
     );
     let _enter = span.enter();
 
-    let file = SharedFile::new(source_id, target_id, file_id)?;
+    let file = match SharedFile::new(source_id, target_id, file_id) {
+        Ok(file) => file,
+        Err(e) => return Box::new(err(e)),
+    };
 
-    if job_config
-        .nodes
-        .read()
-        .expect("Cannot read nodes list")
-        .is_subnode(&file.target_id)
-    {
-        head_local(file, params, job_config)
-    } else if job_config.cfg.general.node_id == "root" {
-        Err(Error::UnknownNode(file.target_id))
-    } else {
-        head_forward(file, params, job_config)
+    match destination(&file.target_id, &job_config) {
+        Ok(Destination::Local) => Box::new(result(head_local(file, params, job_config))),
+        Ok(Destination::SubRelay(relay)) => Box::new(head_forward(
+            file,
+            params,
+            sub_relay_url(&relay),
+            job_config,
+        )),
+        Ok(Destination::Upstream) => {
+            let upstream = job_config.cfg.output.upstream.url.clone();
+            Box::new(head_forward(file, params, upstream, job_config))
+        }
+        Err(e) => Box::new(err(e)),
     }
 }
 
 fn head_forward(
     file: SharedFile,
     params: SharedFilesHeadParams,
+    server: String,
     job_config: Arc<JobConfig>,
-) -> Result<StatusCode, Error> {
+) -> impl Future<Item = StatusCode, Error = Error> {
     job_config
         .client
         .clone()
         .head(&format!(
             "{}/{}/{}",
-            job_config.cfg.output.upstream.url,
+            server,
             "relay-api/shared-files",
             file.url(),
         ))
         .query(&params)
         .send()
-        .wait()
-        .map(|r| r.status())
-        .map_err(|e| e.into())
+        .then(move |response| Ok(forwarded_status(&server, response)))
 }
 
 pub fn head_local(
@@ -293,9 +686,409 @@ pub fn head_local(
     })
 }
 
+/// Parses a PEM certificate passed in a header
+///
+/// Line breaks may have been replaced by spaces by the reverse proxy.
+fn parse_certificate(header: &str) -> Result<X509, Error> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+    let content = header
+        .trim()
+        .trim_start_matches(BEGIN)
+        .trim_end_matches(END)
+        .split_whitespace()
+        .collect::<Vec<&str>>()
+        .join("\n");
+    Ok(X509::from_pem(
+        format!("{}\n{}\n{}\n", BEGIN, content, END).as_bytes(),
+    )?)
+}
+
+/// Checks the client certificate belongs to the given node
+fn authenticate(
+    node_id: &str,
+    client_certificate: Option<String>,
+    job_config: &JobConfig,
+) -> Result<(), Error> {
+    let certificate = match client_certificate {
+        Some(ref c) if !c.trim().is_empty() => parse_certificate(c)?,
+        _ => return Err(Error::MissingClientCertificate),
+    };
+    if job_config
+        .nodes
+        .read()
+        .expect("Cannot read nodes list")
+        .is_certificate_of(node_id, &certificate)?
+    {
+        Ok(())
+    } else {
+        Err(Error::InvalidClientCertificate(node_id.to_string()))
+    }
+}
+
+pub fn status_code(error: &Error) -> StatusCode {
+    match error {
+        Error::MissingClientCertificate | Error::InvalidClientCertificate(_) => {
+            StatusCode::FORBIDDEN
+        }
+        Error::InvalidSharedFile(_) | Error::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub fn empty_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Downloads a shared file, only allowed for its target node
+///
+/// The file metadata is sent in headers.
+pub fn get(
+    target_id: String,
+    source_id: String,
+    file_id: String,
+    client_certificate: Option<String>,
+    job_config: Arc<JobConfig>,
+) -> Result<Response<Body>, Error> {
+    let span = span!(
+        Level::INFO,
+        "shared_files_get",
+        target_id = %target_id,
+        source_id = %source_id,
+        file_id = %file_id,
+    );
+    let _enter = span.enter();
+
+    let file = SharedFile::new(source_id, target_id, file_id)?;
+
+    authenticate(&file.target_id, client_certificate, &job_config)?;
+
+    let base_path = job_config
+        .cfg
+        .shared_files
+        .path
+        .join(&file.target_id)
+        .join("files")
+        .join(&file.source_id);
+    let metadata_path = base_path.join(format!("{}.metadata", file.file_id));
+
+    if !metadata_path.exists() {
+        debug!("file {} does not exist", metadata_path.display());
+        return Ok(empty_response(StatusCode::NOT_FOUND));
+    }
+    let metadata = Metadata::from_str(&fs::read_to_string(&metadata_path)?)?;
+    // Not removed yet
+    if metadata.has_expired(Utc::now().timestamp()) {
+        debug!("file {} has expired", metadata_path.display());
+        return Ok(empty_response(StatusCode::NOT_FOUND));
+    }
+
+    let content = match fs::File::open(base_path.join(&file.file_id)) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            debug!("file {} has metadata but no content", file.path().display());
+            return Ok(empty_response(StatusCode::NOT_FOUND));
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut response = Response::builder();
+    response.header(CONTENT_LENGTH, content.metadata()?.len().to_string());
+    for (key, value) in metadata.fields() {
+        response.header(
+            format!("{}{}", METADATA_HEADER_PREFIX, key.replace('_', "-")).as_str(),
+            value,
+        );
+    }
+    response
+        .body(Body::wrap_stream(
+            FramedRead::new(tokio::fs::File::from_std(content), BytesCodec::new())
+                .map(|chunk| chunk.freeze()),
+        ))
+        .map_err(|e| Error::InvalidHeader(e.to_string()))
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct SharedFilesListParams {
+    source: Option<String>,
+}
+
+/// A file waiting for its target node
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct SharedFileEntry {
+    pub source_id: String,
+    pub file_id: String,
+    pub hash: String,
+    /// Timestamp
+    pub expires: Option<i64>,
+    pub size: u64,
+}
+
+/// Lists the files shared with a node, only allowed for this node
+pub fn list(
+    target_id: String,
+    params: SharedFilesListParams,
+    client_certificate: Option<String>,
+    job_config: Arc<JobConfig>,
+) -> Result<Vec<SharedFileEntry>, Error> {
+    let span = span!(
+        Level::INFO,
+        "shared_files_list",
+        target_id = %target_id,
+    );
+    let _enter = span.enter();
+
+    SharedFile::check_id("target_id", &target_id)?;
+    authenticate(&target_id, client_certificate, &job_config)?;
+
+    list_local(
+        &job_config
+            .cfg
+            .shared_files
+            .path
+            .join(&target_id)
+            .join("files"),
+        params.source.as_ref().map(|s| s.as_str()),
+        Utc::now().timestamp(),
+    )
+}
+
+/// Non-expired files in a target node directory, sorted by source and file id
+fn list_local(
+    directory: &Path,
+    source: Option<&str>,
+    now: i64,
+) -> Result<Vec<SharedFileEntry>, Error> {
+    let mut files = vec![];
+    // Nothing was shared with this node yet
+    if !directory.exists() {
+        return Ok(files);
+    }
+
+    for source_dir in fs::read_dir(directory)? {
+        let source_dir = source_dir?;
+        let source_id = source_dir.file_name().to_string_lossy().to_string();
+        if !source_dir.file_type()?.is_dir() || source.map(|s| s != source_id).unwrap_or(false) {
+            continue;
+        }
+
+        for entry in fs::read_dir(source_dir.path())? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            if !name.ends_with(METADATA_EXTENSION) || name == METADATA_EXTENSION {
+                continue;
+            }
+            let file_id = name[..name.len() - METADATA_EXTENSION.len()].to_string();
+
+            let metadata = match fs::read_to_string(source_dir.path().join(&name))
+                .map_err(Error::from)
+                .and_then(|m| Metadata::from_str(&m))
+            {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    warn!(
+                        "could not read metadata of {}/{}: {}",
+                        source_id, file_id, e
+                    );
+                    continue;
+                }
+            };
+            if metadata.has_expired(now) {
+                continue;
+            }
+            let size = match fs::metadata(source_dir.path().join(&file_id)) {
+                Ok(m) => m.len(),
+                // Metadata without content
+                Err(_) => continue,
+            };
+
+            files.push(SharedFileEntry {
+                source_id: source_id.clone(),
+                file_id,
+                hash: metadata.hash.to_string(),
+                expires: metadata.expires,
+                size,
+            });
+        }
+    }
+    files.sort_by(|a, b| (&a.source_id, &a.file_id).cmp(&(&b.source_id, &b.file_id)));
+    Ok(files)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hashing::HashType;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_limits_upload_size() {
+        let chunks = || {
+            stream::iter_ok::<_, Error>(vec![
+                io::Cursor::new(b"abc".to_vec()),
+                io::Cursor::new(b"def".to_vec()),
+            ])
+        };
+
+        assert_eq!(
+            limit_size(chunks(), 6).collect().wait().unwrap(),
+            vec![b"abc".to_vec(), b"def".to_vec()]
+        );
+        match limit_size(chunks(), 5).collect().wait() {
+            Err(Error::LimitExceeded(_, 5)) => (),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    fn shared_file(file_id: &str) -> SharedFile {
+        SharedFile::new(
+            "e745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+            "37817c4d-fbf7-4850-a985-50021f4e8f41".to_string(),
+            file_id.to_string(),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_writes_uploaded_files() {
+        let file = "tests/api_shared_files/37817c4d-fbf7-4850-a985-50021f4e8f41/files/e745a140-40bc-4b86-b6dc-084488fc906b/file2";
+        let signature = fs::read_to_string(format!("{}.sign", file)).unwrap();
+        let content = fs::read_to_string(format!("{}.source", file)).unwrap();
+        let metadata = Metadata::from_str(&signature).unwrap();
+        let key_hash =
+            HashType::Sha256.hash(&metadata.pubkey().unwrap().public_key_to_der().unwrap());
+        let ttl = Duration::from_secs(60);
+        let dir = tempdir().unwrap();
+
+        // Header and content split across chunks
+        let mut upload = Upload::new(
+            dir.path().to_path_buf(),
+            shared_file("file2"),
+            Arc::new(Mutex::new(())),
+        );
+        for chunk in format!("{}\n{}", signature, content).as_bytes().chunks(7) {
+            upload.write(chunk).unwrap();
+        }
+        assert_eq!(upload.finish(Some(&key_hash), ttl).unwrap(), StatusCode::OK);
+        assert_eq!(
+            fs::read_to_string(dir.path().join("file2")).unwrap(),
+            content
+        );
+        assert!(dir.path().join("file2.metadata").exists());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        // Content not matching the metadata
+        let mut upload = Upload::new(
+            dir.path().to_path_buf(),
+            shared_file("other"),
+            Arc::new(Mutex::new(())),
+        );
+        upload
+            .write(format!("{}\n{}", signature, "test").as_bytes())
+            .unwrap();
+        assert_eq!(
+            upload.finish(Some(&key_hash), ttl).unwrap(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        // Key not matching the known one
+        let mut upload = Upload::new(
+            dir.path().to_path_buf(),
+            shared_file("other"),
+            Arc::new(Mutex::new(())),
+        );
+        upload
+            .write(format!("{}\n{}", signature, content).as_bytes())
+            .unwrap();
+        assert_eq!(
+            upload
+                .finish(Some(&HashType::Sha256.hash(b"other key")), ttl)
+                .unwrap(),
+            StatusCode::NOT_FOUND
+        );
+
+        // Source unknown to this relay
+        let mut upload = Upload::new(
+            dir.path().to_path_buf(),
+            shared_file("file3"),
+            Arc::new(Mutex::new(())),
+        );
+        upload
+            .write(format!("{}\n{}", signature, content).as_bytes())
+            .unwrap();
+        assert_eq!(upload.finish(None, ttl).unwrap(), StatusCode::OK);
+        assert!(dir.path().join("file3").exists());
+
+        // Interrupted upload
+        let mut upload = Upload::new(
+            dir.path().to_path_buf(),
+            shared_file("other"),
+            Arc::new(Mutex::new(())),
+        );
+        upload
+            .write(format!("{}\n{}", signature, content).as_bytes())
+            .unwrap();
+        drop(upload);
+
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn it_limits_uploaded_metadata_size() {
+        let dir = tempdir().unwrap();
+        let mut upload = Upload::new(
+            dir.path().to_path_buf(),
+            shared_file("file2"),
+            Arc::new(Mutex::new(())),
+        );
+        let line = format!("{}\n", "a".repeat(1023));
+        for _ in 0..64 {
+            upload.write(line.as_bytes()).unwrap();
+        }
+        assert!(upload.write(b"a").is_err());
+        assert!(upload.content.is_none());
+    }
+
+    #[test]
+    fn it_parses_certificates_from_headers() {
+        let pem = fs::read_to_string("tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert")
+            .unwrap();
+        let cert = X509::from_pem(pem.as_bytes()).unwrap().to_der().unwrap();
+
+        assert_eq!(parse_certificate(&pem).unwrap().to_der().unwrap(), cert);
+        // As sent by Apache httpd
+        assert_eq!(
+            parse_certificate(&pem.replace('\n', " "))
+                .unwrap()
+                .to_der()
+                .unwrap(),
+            cert
+        );
+        assert!(parse_certificate("").is_err());
+    }
+
+    #[test]
+    fn it_lists_shared_files() {
+        let directory =
+            Path::new("tests/api_shared_files/37817c4d-fbf7-4850-a985-50021f4e8f41/files");
+        let file = SharedFileEntry {
+            source_id: "e745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+            file_id: "file".to_string(),
+            hash: "sha512:dda78e9b97a69aca3cff21de266246bde0d91bc4b61df72bfb0387564ac0c7bd64dd4caca39ce1ef400f32aa711ec4909789705beec93314eb65fabd5183bbfe".to_string(),
+            expires: Some(1_580_941_341),
+            size: fs::metadata(directory.join("e745a140-40bc-4b86-b6dc-084488fc906b").join("file")).unwrap().len(),
+        };
+
+        assert_eq!(list_local(directory, None, 0).unwrap(), vec![file]);
+        assert_eq!(list_local(directory, Some("unknown"), 0).unwrap(), vec![]);
+        // Expired
+        assert_eq!(list_local(directory, None, 1_580_941_342).unwrap(), vec![]);
+        assert_eq!(
+            list_local(Path::new("tests/api_shared_files/unknown"), None, 0).unwrap(),
+            vec![]
+        );
+    }
 
     #[test]
     pub fn it_parses_ttl() {
@@ -1,9 +1,15 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use crate::{api::ApiResult, check_configuration, output::database::ping, Error, JobConfig};
+use crate::{
+    api::ApiResult,
+    check_configuration,
+    configuration::main::{InventoryOutputSelect, ReportingOutputSelect},
+    output::{circuit_breaker::BreakerStatus, database::ping},
+    Error, JobConfig,
+};
 use serde::Serialize;
-use std::sync::Arc;
+use std::{collections::BTreeMap, sync::Arc};
 use structopt::clap::crate_version;
 
 #[derive(Serialize, Debug, PartialEq, Eq)]
@@ -52,10 +58,29 @@ impl From<Result<(), Error>> for State {
 pub struct Status {
     database: Option<State>,
     configuration: State,
+    /// Circuit breakers of the outputs in use
+    outputs: BTreeMap<&'static str, BreakerStatus>,
 }
 
 impl Status {
     pub fn poll(job_config: Arc<JobConfig>) -> Self {
+        let processing = &job_config.cfg.processing;
+        let mut outputs = BTreeMap::new();
+        if job_config.pool.is_some() {
+            let breaker = &job_config.breakers.database;
+            outputs.insert(breaker.name(), breaker.status());
+        }
+        if processing.reporting.uses(ReportingOutputSelect::Upstream)
+            || processing.inventory.output == InventoryOutputSelect::Upstream
+        {
+            let breaker = &job_config.breakers.upstream;
+            outputs.insert(breaker.name(), breaker.status());
+        }
+        if processing.reporting.uses(ReportingOutputSelect::Archive) {
+            let breaker = &job_config.breakers.archive;
+            outputs.insert(breaker.name(), breaker.status());
+        }
+
         Self {
             database: job_config
                 .pool
@@ -64,6 +89,7 @@ impl Status {
             configuration: check_configuration(&job_config.cli_cfg.configuration_dir)
                 .map_err(|e| e)
                 .into(),
+            outputs,
         }
     }
 }
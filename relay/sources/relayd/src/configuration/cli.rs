@@ -1,6 +1,8 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+use crate::data::{node::NodeId, RunInfo};
+use chrono::{DateTime, FixedOffset};
 use std::path::{Path, PathBuf};
 
 #[derive(StructOpt, Debug)]
@@ -21,6 +23,10 @@ pub struct CliConfiguration {
     /// Checks the syntax of the configuration file and exit
     #[structopt(short = "t", long = "test")]
     pub check_configuration: bool,
+
+    /// Runs a command instead of starting the server
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
 }
 
 impl CliConfiguration {
@@ -29,6 +35,69 @@ impl CliConfiguration {
         Self {
             configuration_dir: path.as_ref().to_path_buf(),
             check_configuration,
+            command: None,
         }
     }
 }
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Prints runlogs stored in the local archive
+    Archive(ArchiveQuery),
+    /// Inserts runlog files or archived runlogs into the database
+    Replay(ReplayOptions),
+}
+
+#[derive(StructOpt, Debug, Default)]
+pub struct RunlogFilter {
+    /// Only uses runlogs of this node
+    #[structopt(short = "n", long = "node")]
+    pub node_id: Option<NodeId>,
+
+    /// Only uses runlogs executed after this date (RFC 3339)
+    #[structopt(long = "from")]
+    pub from: Option<DateTime<FixedOffset>>,
+
+    /// Only uses runlogs executed before this date (RFC 3339)
+    #[structopt(long = "to")]
+    pub to: Option<DateTime<FixedOffset>>,
+}
+
+impl RunlogFilter {
+    pub fn matches(&self, info: &RunInfo) -> bool {
+        self.node_id
+            .as_ref()
+            .map(|id| *id == info.node_id)
+            .unwrap_or(true)
+            && self.from.map(|from| info.timestamp >= from).unwrap_or(true)
+            && self.to.map(|to| info.timestamp <= to).unwrap_or(true)
+    }
+}
+
+#[derive(StructOpt, Debug, Default)]
+pub struct ArchiveQuery {
+    #[structopt(flatten)]
+    pub filter: RunlogFilter,
+
+    /// Prints reports in agent output format instead of JSON lines
+    #[structopt(long = "text")]
+    pub text: bool,
+
+    /// Archive files or directories, defaults to the configured archive directory
+    #[structopt(parse(from_os_str))]
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(StructOpt, Debug, Default)]
+pub struct ReplayOptions {
+    #[structopt(flatten)]
+    pub filter: RunlogFilter,
+
+    /// Inserts runlogs even if they were already inserted
+    #[structopt(short = "f", long = "force")]
+    pub force: bool,
+
+    /// Runlog files, archive files, or directories containing them
+    #[structopt(parse(from_os_str), required = true)]
+    pub paths: Vec<PathBuf>,
+}
@@ -58,6 +58,31 @@ where
     d.deserialize_str(V)
 }
 
+// For compatibility with single output fields, "disabled" meaning no output
+fn compat_outputs<'de, D>(d: D) -> Result<Vec<ReportingOutputSelect>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(ReportingOutputSelect),
+        Many(Vec<ReportingOutputSelect>),
+    }
+
+    let outputs = match OneOrMany::deserialize(d)? {
+        OneOrMany::One(output) => vec![output],
+        OneOrMany::Many(outputs) => outputs,
+    };
+    let mut res = vec![];
+    for output in outputs {
+        if output != ReportingOutputSelect::Disabled && !res.contains(&output) {
+            res.push(output);
+        }
+    }
+    Ok(res)
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 // Default can be implemented in serde using the Default trait
 pub struct Configuration {
@@ -112,6 +137,11 @@ pub struct GeneralConfig {
     pub core_threads: Option<usize>,
     #[serde(default = "GeneralConfig::default_blocking_threads")]
     pub blocking_threads: usize,
+    /// Maximum time given to in-flight processing to complete when
+    /// stopping the service
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "GeneralConfig::default_shutdown_timeout")]
+    pub shutdown_timeout: Duration,
 }
 
 impl GeneralConfig {
@@ -130,6 +160,10 @@ impl GeneralConfig {
     fn default_blocking_threads() -> usize {
         100
     }
+
+    fn default_shutdown_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
@@ -191,6 +225,145 @@ impl Default for CleanupConfig {
     }
 }
 
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct RetryConfig {
+    /// Delay before the first retry, doubled at each attempt
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "RetryConfig::default_retry_initial_delay")]
+    pub initial_delay: Duration,
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "RetryConfig::default_retry_max_delay")]
+    pub max_delay: Duration,
+    /// Give up after this number of failed attempts
+    #[serde(default = "RetryConfig::default_retry_max_attempts")]
+    pub max_attempts: u32,
+    /// Give up when first failure is older than this
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "RetryConfig::default_retry_max_age")]
+    pub max_age: Duration,
+}
+
+impl RetryConfig {
+    /// 1 minute
+    fn default_retry_initial_delay() -> Duration {
+        Duration::from_secs(60)
+    }
+
+    /// 1 hour
+    fn default_retry_max_delay() -> Duration {
+        Duration::from_secs(3600)
+    }
+
+    fn default_retry_max_attempts() -> u32 {
+        10
+    }
+
+    /// 1 day
+    fn default_retry_max_age() -> Duration {
+        Duration::from_secs(3600 * 24)
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay: Self::default_retry_initial_delay(),
+            max_delay: Self::default_retry_max_delay(),
+            max_attempts: Self::default_retry_max_attempts(),
+            max_age: Self::default_retry_max_age(),
+        }
+    }
+}
+
+/// Agent log messages preceding a report, inserted as `log_*` reports
+/// with the metadata of the report
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct ReportLogsConfig {
+    #[serde(default = "ReportLogsConfig::default_enabled")]
+    pub enabled: bool,
+    /// Maximum size of log messages attached to a report, in bytes
+    #[serde(default = "ReportLogsConfig::default_max_size_per_report")]
+    pub max_size_per_report: usize,
+    /// Maximum size of log messages in a runlog, in bytes
+    #[serde(default = "ReportLogsConfig::default_max_size_per_runlog")]
+    pub max_size_per_runlog: usize,
+}
+
+impl ReportLogsConfig {
+    fn default_enabled() -> bool {
+        true
+    }
+
+    /// 64 kiB
+    fn default_max_size_per_report() -> usize {
+        64 * 1024
+    }
+
+    /// 1 MiB
+    fn default_max_size_per_runlog() -> usize {
+        1024 * 1024
+    }
+}
+
+impl Default for ReportLogsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: Self::default_enabled(),
+            max_size_per_report: Self::default_max_size_per_report(),
+            max_size_per_runlog: Self::default_max_size_per_runlog(),
+        }
+    }
+}
+
+/// Limits protecting against misbehaving agents, sizes are in bytes
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct ReportLimitsConfig {
+    /// Larger report files are refused
+    #[serde(default = "ReportLimitsConfig::default_max_compressed_size")]
+    pub max_compressed_size: usize,
+    /// Larger runlogs are refused, checked while extracting
+    #[serde(default = "ReportLimitsConfig::default_max_uncompressed_size")]
+    pub max_uncompressed_size: usize,
+    /// Runlogs with more reports are refused
+    #[serde(default = "ReportLimitsConfig::default_max_reports")]
+    pub max_reports: usize,
+    /// Longer messages are truncated
+    #[serde(default = "ReportLimitsConfig::default_max_message_size")]
+    pub max_message_size: usize,
+}
+
+impl ReportLimitsConfig {
+    /// 10 MiB
+    fn default_max_compressed_size() -> usize {
+        10 * 1024 * 1024
+    }
+
+    /// 50 MiB
+    fn default_max_uncompressed_size() -> usize {
+        50 * 1024 * 1024
+    }
+
+    fn default_max_reports() -> usize {
+        50_000
+    }
+
+    /// 64 kiB
+    fn default_max_message_size() -> usize {
+        64 * 1024
+    }
+}
+
+impl Default for ReportLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_compressed_size: Self::default_max_compressed_size(),
+            max_uncompressed_size: Self::default_max_uncompressed_size(),
+            max_reports: Self::default_max_reports(),
+            max_message_size: Self::default_max_message_size(),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone, Default)]
 pub struct ProcessingConfig {
     #[serde(default)]
@@ -209,6 +382,10 @@ pub struct InventoryConfig {
     pub catchup: CatchupConfig,
     #[serde(default)]
     pub cleanup: CleanupConfig,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub limits: InventoryLimitsConfig,
 }
 
 impl InventoryConfig {
@@ -224,6 +401,40 @@ impl Default for InventoryConfig {
             output: InventoryOutputSelect::default(),
             catchup: Default::default(),
             cleanup: Default::default(),
+            retry: Default::default(),
+            limits: Default::default(),
+        }
+    }
+}
+
+/// Limits on inventories received through the API, sizes are in bytes
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct InventoryLimitsConfig {
+    /// Larger requests are refused
+    #[serde(default = "InventoryLimitsConfig::default_max_compressed_size")]
+    pub max_compressed_size: usize,
+    /// Larger inventories are refused, checked while extracting
+    #[serde(default = "InventoryLimitsConfig::default_max_uncompressed_size")]
+    pub max_uncompressed_size: usize,
+}
+
+impl InventoryLimitsConfig {
+    /// 20 MiB
+    fn default_max_compressed_size() -> usize {
+        20 * 1024 * 1024
+    }
+
+    /// 200 MiB
+    fn default_max_uncompressed_size() -> usize {
+        200 * 1024 * 1024
+    }
+}
+
+impl Default for InventoryLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_compressed_size: Self::default_max_compressed_size(),
+            max_uncompressed_size: Self::default_max_uncompressed_size(),
         }
     }
 }
@@ -245,39 +456,78 @@ impl Default for InventoryOutputSelect {
 pub struct ReportingConfig {
     #[serde(default = "ReportingConfig::default_directory")]
     pub directory: BaseDirectory,
+    /// Outputs which need to process a report before removing it
+    #[serde(default)]
+    #[serde(deserialize_with = "compat_outputs")]
+    pub output: Vec<ReportingOutputSelect>,
+    /// Outputs allowed to fail without blocking the report
     #[serde(default)]
-    pub output: ReportingOutputSelect,
+    #[serde(deserialize_with = "compat_outputs")]
+    pub optional_output: Vec<ReportingOutputSelect>,
     #[serde(default)]
     pub catchup: CatchupConfig,
     #[serde(default)]
     pub cleanup: CleanupConfig,
     #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
     pub skip_event_types: HashSet<String>,
+    #[serde(default)]
+    pub logs: ReportLogsConfig,
+    #[serde(default)]
+    pub limits: ReportLimitsConfig,
 }
 
 impl ReportingConfig {
     fn default_directory() -> PathBuf {
         PathBuf::from("/var/rudder/reports/")
     }
+
+    /// All outputs in use, with whether they are required
+    pub fn outputs(&self) -> Vec<(ReportingOutputSelect, bool)> {
+        self.output
+            .iter()
+            .map(|o| (*o, true))
+            .chain(
+                self.optional_output
+                    .iter()
+                    .filter(|o| !self.output.contains(o))
+                    .map(|o| (*o, false)),
+            )
+            .collect()
+    }
+
+    pub fn uses(&self, output: ReportingOutputSelect) -> bool {
+        self.output.contains(&output) || self.optional_output.contains(&output)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.output.is_empty() || !self.optional_output.is_empty()
+    }
 }
 
 impl Default for ReportingConfig {
     fn default() -> Self {
         Self {
             directory: Self::default_directory(),
-            output: ReportingOutputSelect::default(),
+            output: vec![],
+            optional_output: vec![],
             catchup: Default::default(),
             cleanup: Default::default(),
+            retry: Default::default(),
             skip_event_types: Default::default(),
+            logs: Default::default(),
+            limits: Default::default(),
         }
     }
 }
 
-#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
 #[serde(rename_all = "lowercase")]
 pub enum ReportingOutputSelect {
     Database,
     Upstream,
+    Archive,
     Disabled,
 }
 
@@ -309,6 +559,44 @@ pub struct RemoteRun {
     pub command: PathBuf,
     #[serde(default = "RemoteRun::default_use_sudo")]
     pub use_sudo: bool,
+    /// Keeps asynchronous jobs across restarts, in memory only when not set
+    #[serde(default)]
+    pub jobs_directory: Option<PathBuf>,
+    /// Finished jobs are forgotten after this duration
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "RemoteRun::default_job_retention")]
+    pub job_retention: Duration,
+    /// Output lines kept for each node in asynchronous jobs, 0 meaning no limit
+    #[serde(default = "RemoteRun::default_max_output_lines")]
+    pub max_output_lines: usize,
+    /// Nodes (and sub-relays) are handled by batches of this size, 0 meaning a
+    /// single batch
+    ///
+    /// With the text output, all the nodes of a batch are triggered by a single
+    /// agent command. In asynchronous jobs, each node gets its own agent command,
+    /// with at most `max_parallel_runs` of them running at once in a batch.
+    #[serde(default = "RemoteRun::default_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "RemoteRun::default_max_parallel_batches")]
+    pub max_parallel_batches: usize,
+    /// Maximum number of agent commands running at once in a batch of an
+    /// asynchronous job, 0 meaning no limit
+    #[serde(default = "RemoteRun::default_max_parallel_runs")]
+    pub max_parallel_runs: usize,
+    /// Each batch is started after a random delay within this window
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "RemoteRun::default_spread")]
+    pub spread: Duration,
+    /// Agent runs are stopped after this duration, for each node (or each
+    /// batch when not tracking nodes separately)
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "RemoteRun::default_run_timeout")]
+    pub run_timeout: Duration,
+    /// Calls forwarded to a sub-relay are stopped after this duration,
+    /// it should allow all the batches of the sub-relay to run
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "RemoteRun::default_forward_timeout")]
+    pub forward_timeout: Duration,
 }
 
 impl RemoteRun {
@@ -319,6 +607,38 @@ impl RemoteRun {
     fn default_use_sudo() -> bool {
         true
     }
+
+    fn default_job_retention() -> Duration {
+        Duration::from_secs(60 * 60 * 24)
+    }
+
+    fn default_max_output_lines() -> usize {
+        1000
+    }
+
+    fn default_batch_size() -> usize {
+        100
+    }
+
+    fn default_max_parallel_batches() -> usize {
+        10
+    }
+
+    fn default_max_parallel_runs() -> usize {
+        10
+    }
+
+    fn default_spread() -> Duration {
+        Duration::from_secs(0)
+    }
+
+    fn default_run_timeout() -> Duration {
+        Duration::from_secs(30 * 60)
+    }
+
+    fn default_forward_timeout() -> Duration {
+        Duration::from_secs(60 * 60)
+    }
 }
 
 impl Default for RemoteRun {
@@ -326,6 +646,15 @@ impl Default for RemoteRun {
         Self {
             command: Self::default_command(),
             use_sudo: Self::default_use_sudo(),
+            jobs_directory: None,
+            job_retention: Self::default_job_retention(),
+            max_output_lines: Self::default_max_output_lines(),
+            batch_size: Self::default_batch_size(),
+            max_parallel_batches: Self::default_max_parallel_batches(),
+            max_parallel_runs: Self::default_max_parallel_runs(),
+            spread: Self::default_spread(),
+            run_timeout: Self::default_run_timeout(),
+            forward_timeout: Self::default_forward_timeout(),
         }
     }
 }
@@ -334,18 +663,37 @@ impl Default for RemoteRun {
 pub struct SharedFiles {
     #[serde(default = "SharedFiles::default_path")]
     pub path: PathBuf,
+    /// Check for expired files with this frequency
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "SharedFiles::default_cleanup_frequency")]
+    pub cleanup_frequency: Duration,
+    /// Larger uploaded files are refused, in bytes
+    #[serde(default = "SharedFiles::default_max_size")]
+    pub max_size: usize,
 }
 
 impl SharedFiles {
     fn default_path() -> PathBuf {
         PathBuf::from("/var/rudder/shared-files/")
     }
+
+    /// 10 minutes
+    fn default_cleanup_frequency() -> Duration {
+        Duration::from_secs(600)
+    }
+
+    /// 100 MiB
+    fn default_max_size() -> usize {
+        100 * 1024 * 1024
+    }
 }
 
 impl Default for SharedFiles {
     fn default() -> Self {
         Self {
             path: Self::default_path(),
+            cleanup_frequency: Self::default_cleanup_frequency(),
+            max_size: Self::default_max_size(),
         }
     }
 }
@@ -376,6 +724,72 @@ pub struct OutputConfig {
     pub database: DatabaseConfig,
     #[serde(default)]
     pub upstream: UpstreamConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    /// Applies to each output separately
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreakerConfig,
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ArchiveConfig {
+    #[serde(default = "ArchiveConfig::default_directory")]
+    pub directory: PathBuf,
+    /// Removes archives older than the retention
+    #[serde(default)]
+    pub cleanup: CleanupConfig,
+    /// Maximum total size of the archives in bytes, oldest ones
+    /// being removed first
+    #[serde(default)]
+    pub max_size: Option<u64>,
+}
+
+impl ArchiveConfig {
+    fn default_directory() -> PathBuf {
+        PathBuf::from("/var/rudder/reports/archive/")
+    }
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            directory: Self::default_directory(),
+            cleanup: Default::default(),
+            max_size: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
+pub struct CircuitBreakerConfig {
+    /// Stop sending files to an output after this number of consecutive
+    /// transient errors
+    #[serde(default = "CircuitBreakerConfig::default_failure_threshold")]
+    pub failure_threshold: u32,
+    /// Check if the output is available again with this frequency
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "CircuitBreakerConfig::default_probe_interval")]
+    pub probe_interval: Duration,
+}
+
+impl CircuitBreakerConfig {
+    fn default_failure_threshold() -> u32 {
+        5
+    }
+
+    /// 30 seconds
+    fn default_probe_interval() -> Duration {
+        Duration::from_secs(30)
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: Self::default_failure_threshold(),
+            probe_interval: Self::default_probe_interval(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
@@ -387,6 +801,14 @@ pub struct DatabaseConfig {
     pub password: Secret,
     #[serde(default = "DatabaseConfig::default_max_pool_size")]
     pub max_pool_size: u32,
+    /// Maximum number of runlogs inserted in a single transaction,
+    /// 1 disables batching
+    #[serde(default = "DatabaseConfig::default_batch_size")]
+    pub batch_size: usize,
+    /// Maximum time a runlog waits for other ones before insertion
+    #[serde(deserialize_with = "compat_humantime")]
+    #[serde(default = "DatabaseConfig::default_batch_window")]
+    pub batch_window: Duration,
 }
 
 impl DatabaseConfig {
@@ -397,6 +819,15 @@ impl DatabaseConfig {
     fn default_max_pool_size() -> u32 {
         10
     }
+
+    fn default_batch_size() -> usize {
+        50
+    }
+
+    /// 1 second
+    fn default_batch_window() -> Duration {
+        Duration::from_secs(1)
+    }
 }
 
 impl Default for DatabaseConfig {
@@ -405,6 +836,8 @@ impl Default for DatabaseConfig {
             url: Self::default_url(),
             password: Default::default(),
             max_pool_size: Self::default_max_pool_size(),
+            batch_size: Self::default_batch_size(),
+            batch_window: Self::default_batch_window(),
         }
     }
 }
@@ -478,6 +911,7 @@ mod tests {
                 listen: "127.0.0.1:3030".parse().unwrap(),
                 core_threads: None,
                 blocking_threads: 100,
+                shutdown_timeout: Duration::from_secs(10),
             },
             processing: ProcessingConfig {
                 inventory: InventoryConfig {
@@ -491,10 +925,21 @@ mod tests {
                         frequency: Duration::from_secs(3600),
                         retention: Duration::from_secs(3600 * 24 * 7),
                     },
+                    retry: RetryConfig {
+                        initial_delay: Duration::from_secs(60),
+                        max_delay: Duration::from_secs(3600),
+                        max_attempts: 10,
+                        max_age: Duration::from_secs(3600 * 24),
+                    },
+                    limits: InventoryLimitsConfig {
+                        max_compressed_size: 20 * 1024 * 1024,
+                        max_uncompressed_size: 200 * 1024 * 1024,
+                    },
                 },
                 reporting: ReportingConfig {
                     directory: PathBuf::from("/var/rudder/reports/"),
-                    output: ReportingOutputSelect::Disabled,
+                    output: vec![],
+                    optional_output: vec![],
                     catchup: CatchupConfig {
                         frequency: Duration::from_secs(10),
                         limit: 50,
@@ -503,7 +948,24 @@ mod tests {
                         frequency: Duration::from_secs(3600),
                         retention: Duration::from_secs(3600 * 24 * 7),
                     },
+                    retry: RetryConfig {
+                        initial_delay: Duration::from_secs(60),
+                        max_delay: Duration::from_secs(3600),
+                        max_attempts: 10,
+                        max_age: Duration::from_secs(3600 * 24),
+                    },
                     skip_event_types: HashSet::new(),
+                    logs: ReportLogsConfig {
+                        enabled: true,
+                        max_size_per_report: 64 * 1024,
+                        max_size_per_runlog: 1024 * 1024,
+                    },
+                    limits: ReportLimitsConfig {
+                        max_compressed_size: 10 * 1024 * 1024,
+                        max_uncompressed_size: 50 * 1024 * 1024,
+                        max_reports: 50_000,
+                        max_message_size: 64 * 1024,
+                    },
                 },
             },
             output: OutputConfig {
@@ -518,14 +980,39 @@ mod tests {
                     url: "postgres://rudder@127.0.0.1/rudder".to_string(),
                     password: Secret::new("".to_string()),
                     max_pool_size: 10,
+                    batch_size: 50,
+                    batch_window: Duration::from_secs(1),
+                },
+                archive: ArchiveConfig {
+                    directory: PathBuf::from("/var/rudder/reports/archive/"),
+                    cleanup: CleanupConfig {
+                        frequency: Duration::from_secs(3600),
+                        retention: Duration::from_secs(3600 * 24 * 7),
+                    },
+                    max_size: None,
+                },
+                circuit_breaker: CircuitBreakerConfig {
+                    failure_threshold: 5,
+                    probe_interval: Duration::from_secs(30),
                 },
             },
             remote_run: RemoteRun {
                 command: PathBuf::from("/opt/rudder/bin/rudder"),
                 use_sudo: true,
+                jobs_directory: None,
+                job_retention: Duration::from_secs(60 * 60 * 24),
+                max_output_lines: 1000,
+                batch_size: 100,
+                max_parallel_batches: 10,
+                max_parallel_runs: 10,
+                spread: Duration::from_secs(0),
+                run_timeout: Duration::from_secs(30 * 60),
+                forward_timeout: Duration::from_secs(60 * 60),
             },
             shared_files: SharedFiles {
                 path: PathBuf::from("/var/rudder/shared-files/"),
+                cleanup_frequency: Duration::from_secs(600),
+                max_size: 100 * 1024 * 1024,
             },
             shared_folder: SharedFolder {
                 path: PathBuf::from("/var/rudder/configuration-repository/shared-files/"),
@@ -553,6 +1040,51 @@ mod tests {
         assert!(default.parse::<Configuration>().is_ok());
     }
 
+    #[test]
+    fn it_parses_reporting_outputs() {
+        let parse = |outputs: &str| -> ReportingConfig {
+            toml::from_str(&format!("directory = \"/tmp\"\n{}", outputs)).unwrap()
+        };
+
+        assert_eq!(parse("").output, vec![]);
+        assert_eq!(parse("output = \"disabled\"").output, vec![]);
+        assert_eq!(
+            parse("output = \"database\"").output,
+            vec![ReportingOutputSelect::Database]
+        );
+        let cfg = parse(
+            "output = [\"database\", \"upstream\", \"database\"]\noptional_output = \"upstream\"",
+        );
+        assert_eq!(
+            cfg.output,
+            vec![
+                ReportingOutputSelect::Database,
+                ReportingOutputSelect::Upstream
+            ]
+        );
+        assert_eq!(
+            cfg.outputs(),
+            vec![
+                (ReportingOutputSelect::Database, true),
+                (ReportingOutputSelect::Upstream, true)
+            ]
+        );
+        let cfg = parse("output = [\"database\"]\noptional_output = [\"upstream\"]");
+        assert_eq!(
+            cfg.outputs(),
+            vec![
+                (ReportingOutputSelect::Database, true),
+                (ReportingOutputSelect::Upstream, false)
+            ]
+        );
+        assert!(cfg.uses(ReportingOutputSelect::Upstream));
+        assert_eq!(
+            parse("output = [\"archive\", \"disabled\"]").output,
+            vec![ReportingOutputSelect::Archive]
+        );
+        assert!(!parse("output = []").is_enabled());
+    }
+
     #[test]
     fn it_parses_main_configuration() {
         let config = Configuration::new("tests/files/config/");
@@ -565,6 +1097,7 @@ mod tests {
                 listen: "127.0.0.1:3030".parse().unwrap(),
                 core_threads: None,
                 blocking_threads: 100,
+                shutdown_timeout: Duration::from_secs(10),
             },
             processing: ProcessingConfig {
                 inventory: InventoryConfig {
@@ -578,10 +1111,21 @@ mod tests {
                         frequency: Duration::from_secs(10),
                         retention: Duration::from_secs(10),
                     },
+                    retry: RetryConfig {
+                        initial_delay: Duration::from_secs(60),
+                        max_delay: Duration::from_secs(3600),
+                        max_attempts: 10,
+                        max_age: Duration::from_secs(3600 * 24),
+                    },
+                    limits: InventoryLimitsConfig {
+                        max_compressed_size: 1024 * 1024,
+                        max_uncompressed_size: 10 * 1024 * 1024,
+                    },
                 },
                 reporting: ReportingConfig {
                     directory: PathBuf::from("target/tmp/reporting/"),
-                    output: ReportingOutputSelect::Database,
+                    output: vec![ReportingOutputSelect::Database],
+                    optional_output: vec![],
                     catchup: CatchupConfig {
                         frequency: Duration::from_secs(10),
                         limit: 50,
@@ -590,7 +1134,24 @@ mod tests {
                         frequency: Duration::from_secs(30),
                         retention: Duration::from_secs(30 * 60 + 20),
                     },
+                    retry: RetryConfig {
+                        initial_delay: Duration::from_secs(1),
+                        max_delay: Duration::from_secs(10),
+                        max_attempts: 3,
+                        max_age: Duration::from_secs(3600),
+                    },
                     skip_event_types: HashSet::new(),
+                    logs: ReportLogsConfig {
+                        enabled: true,
+                        max_size_per_report: 4096,
+                        max_size_per_runlog: 65536,
+                    },
+                    limits: ReportLimitsConfig {
+                        max_compressed_size: 1024 * 1024,
+                        max_uncompressed_size: 10 * 1024 * 1024,
+                        max_reports: 10_000,
+                        max_message_size: 16384,
+                    },
                 },
             },
             output: OutputConfig {
@@ -605,14 +1166,39 @@ mod tests {
                     url: "postgres://rudderreports@127.0.0.1/rudder".to_string(),
                     password: Secret::new("PASSWORD".to_string()),
                     max_pool_size: 5,
+                    batch_size: 50,
+                    batch_window: Duration::from_millis(100),
+                },
+                archive: ArchiveConfig {
+                    directory: PathBuf::from("target/tmp/archive/"),
+                    cleanup: CleanupConfig {
+                        frequency: Duration::from_secs(60),
+                        retention: Duration::from_secs(3600 * 24),
+                    },
+                    max_size: Some(1024 * 1024),
+                },
+                circuit_breaker: CircuitBreakerConfig {
+                    failure_threshold: 3,
+                    probe_interval: Duration::from_secs(5),
                 },
             },
             remote_run: RemoteRun {
                 command: PathBuf::from("tests/api_remote_run/fake_agent.sh"),
                 use_sudo: false,
+                jobs_directory: None,
+                job_retention: Duration::from_secs(60 * 60 * 24),
+                max_output_lines: 1000,
+                batch_size: 100,
+                max_parallel_batches: 10,
+                max_parallel_runs: 10,
+                spread: Duration::from_secs(0),
+                run_timeout: Duration::from_secs(30 * 60),
+                forward_timeout: Duration::from_secs(60 * 60),
             },
             shared_files: SharedFiles {
-                path: PathBuf::from("tests/api_shared_files"),
+                path: PathBuf::from("target/tmp/api_shared_files"),
+                cleanup_frequency: Duration::from_secs(600),
+                max_size: 100 * 1024 * 1024,
             },
             shared_folder: SharedFolder {
                 path: PathBuf::from("tests/api_shared_folder"),
@@ -168,6 +168,29 @@ impl NodesList {
             .and_then(|node| node.certificates.as_ref())
     }
 
+    /// Checks a certificate presented by a node belongs to it, by comparing
+    /// its key with known certificates, or with the known key hash if there
+    /// are none
+    pub fn is_certificate_of(&self, id: &NodeIdRef, cert: &X509) -> Result<bool, Error> {
+        if Self::id_from_cert(cert)? != id {
+            return Ok(false);
+        }
+        let key = cert.public_key()?;
+
+        if let Some(certs) = self.certs(id) {
+            for known in certs {
+                if known.public_key()?.public_eq(&key) {
+                    return Ok(true);
+                }
+            }
+            return Ok(false);
+        }
+        Ok(match self.key_hash(id) {
+            Some(known_hash) => known_hash.hash_type.hash(&key.public_key_to_der()?) == known_hash,
+            None => false,
+        })
+    }
+
     fn id_from_cert(cert: &X509) -> Result<NodeId, Error> {
         Ok(cert
             .subject_name()
@@ -216,20 +239,36 @@ impl NodesList {
     // NOTE: Following methods could be made faster by pre-computing a graph in cache
 
     pub fn my_neighbors(&self) -> Vec<Host> {
+        self.my_neighbor_nodes()
+            .into_iter()
+            .map(|(_, h)| h)
+            .collect()
+    }
+
+    /// Neighbors with their ids
+    pub fn my_neighbor_nodes(&self) -> Vec<(NodeId, Host)> {
         self.list
             .data
-            .values()
-            .filter(|k| k.policy_server == self.my_id)
-            .map(|k| k.hostname.clone())
+            .iter()
+            .filter(|(_, k)| k.policy_server == self.my_id)
+            .map(|(id, k)| (id.clone(), k.hostname.clone()))
             .collect()
     }
 
     pub fn neighbors_from(&self, server: &NodeIdRef, nodes: &[NodeId]) -> Vec<Host> {
+        self.neighbor_nodes_from(server, nodes)
+            .into_iter()
+            .map(|(_, h)| h)
+            .collect()
+    }
+
+    /// Neighbors of the server among the given nodes, with their ids
+    pub fn neighbor_nodes_from(&self, server: &NodeIdRef, nodes: &[NodeId]) -> Vec<(NodeId, Host)> {
         nodes
             .iter()
-            .filter_map(|n| self.list.data.get::<str>(n))
-            .filter(|n| n.policy_server == server)
-            .map(|n| n.hostname.clone())
+            .filter_map(|n| self.list.data.get::<str>(n).map(|info| (n, info)))
+            .filter(|(_, info)| info.policy_server == server)
+            .map(|(n, info)| (n.clone(), info.hostname.clone()))
             .collect()
     }
 
@@ -237,34 +276,62 @@ impl NodesList {
         self.neighbors_from(&self.my_id, nodes)
     }
 
+    pub fn my_neighbor_nodes_from(&self, nodes: &[NodeId]) -> Vec<(NodeId, Host)> {
+        self.neighbor_nodes_from(&self.my_id, nodes)
+    }
+
     pub fn my_sub_relays(&self) -> Vec<Host> {
-        let mut relays = HashSet::new();
-        for policy_server in self
+        self.my_sub_relay_nodes()
+            .into_iter()
+            .map(|(_, h)| h)
+            .collect()
+    }
+
+    /// Sub-relays with their ids
+    pub fn my_sub_relay_nodes(&self) -> Vec<(NodeId, Host)> {
+        let mut relays = HashMap::new();
+        for (id, policy_server) in self
             .list
             .data
             .values()
-            .filter_map(|v| self.list.data.get(&v.policy_server))
-            .filter(|v| v.policy_server == self.my_id)
-            .map(|v| v.hostname.clone())
+            .filter_map(|v| self.list.data.get_key_value(&v.policy_server))
+            .filter(|(_, v)| v.policy_server == self.my_id)
         {
-            let _ = relays.insert(policy_server);
+            let _ = relays.insert(id.clone(), policy_server.hostname.clone());
         }
         relays.into_iter().collect()
     }
 
+    /// Hostname of the sub-relay to contact to reach a node, `None` if
+    /// directly connected, error if not found
+    pub fn sub_relay_for(&self, node_id: &NodeIdRef) -> Result<Option<Host>, ()> {
+        Ok(self.next_hop(node_id)?.map(|next_hop| {
+            self.list
+                .data
+                .get::<str>(&next_hop)
+                .map(|n| n.hostname.clone())
+                // We are sure it is there at this point
+                .unwrap()
+        }))
+    }
+
     /// Relays to contact to trigger given nodes, with the matching nodes
     /// Logs and ignores unknown nodes
     pub fn my_sub_relays_from(&self, nodes: &[NodeId]) -> Vec<(Host, Vec<NodeId>)> {
-        let mut relays: HashMap<Host, Vec<NodeId>> = HashMap::new();
+        self.my_sub_relay_nodes_from(nodes)
+            .into_iter()
+            .map(|(_, hostname, nodes)| (hostname, nodes))
+            .collect()
+    }
+
+    /// Relays to contact to trigger given nodes, with their ids and the
+    /// matching nodes
+    /// Logs and ignores unknown nodes
+    pub fn my_sub_relay_nodes_from(&self, nodes: &[NodeId]) -> Vec<(NodeId, Host, Vec<NodeId>)> {
+        let mut relays: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
         for node in nodes.iter() {
-            let hostname = match self.next_hop(node) {
-                Ok(Some(ref next_hop)) => self
-                    .list
-                    .data
-                    .get::<str>(next_hop)
-                    .map(|n| n.hostname.clone())
-                    // We are sure it is there at this point
-                    .unwrap(),
+            let relay = match self.next_hop(node) {
+                Ok(Some(relay)) => relay,
                 Ok(None) => continue,
                 Err(()) => {
                     error!("Unknown node {}", node);
@@ -272,14 +339,35 @@ impl NodesList {
                 }
             };
 
-            if let Some(nodes) = relays.get_mut(&hostname) {
+            if let Some(nodes) = relays.get_mut(&relay) {
                 nodes.push(node.clone());
             } else {
-                relays.insert(hostname, vec![node.clone()]);
+                relays.insert(relay, vec![node.clone()]);
             }
         }
 
-        relays.into_iter().collect()
+        relays
+            .into_iter()
+            .map(|(relay, nodes)| {
+                // We are sure it is there at this point
+                let hostname = self.hostname(&relay).unwrap();
+                (relay, hostname, nodes)
+            })
+            .collect()
+    }
+
+    /// Nodes reached through given sub-relay, sorted
+    pub fn nodes_behind(&self, relay: &NodeIdRef) -> Vec<NodeId> {
+        let mut nodes: Vec<NodeId> = self
+            .list
+            .data
+            .keys()
+            .filter(|id| self.next_hop(id).ok().flatten().as_deref() == Some(relay))
+            .filter(|id| id.as_str() != relay)
+            .cloned()
+            .collect();
+        nodes.sort();
+        nodes
     }
 }
 
@@ -375,6 +463,52 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_checks_node_certificates() {
+        let cert = |name: &str| {
+            X509::from_pem(&read(format!("tests/files/keys/{}.cert", name)).unwrap()).unwrap()
+        };
+        let node = "e745a140-40bc-4b86-b6dc-084488fc906b";
+        let relay = "37817c4d-fbf7-4850-a985-50021f4e8f41";
+
+        let with_certs = NodesList::new(
+            "root".to_string(),
+            "tests/files/nodeslist.json",
+            Some("tests/files/keys/nodescerts.pem"),
+        )
+        .unwrap();
+        assert!(with_certs.is_certificate_of(node, &cert(node)).unwrap());
+        assert!(with_certs.is_certificate_of(relay, &cert(relay)).unwrap());
+        assert!(!with_certs.is_certificate_of(node, &cert(relay)).unwrap());
+
+        let without_certs =
+            NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+        assert!(without_certs.is_certificate_of(node, &cert(node)).unwrap());
+        // Does not match the key hash
+        assert!(!without_certs
+            .is_certificate_of(relay, &cert(relay))
+            .unwrap());
+    }
+
+    #[test]
+    fn it_gets_sub_relay_for_node() {
+        let nodeslist =
+            NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+        assert_eq!(
+            nodeslist.sub_relay_for("e745a140-40bc-4b86-b6dc-084488fc906b"),
+            Ok(None)
+        );
+        assert_eq!(
+            nodeslist.sub_relay_for("c745a140-40bc-4b86-b6dc-084488fc906b"),
+            Ok(Some("node2.rudder.local".to_string()))
+        );
+        assert_eq!(
+            nodeslist.sub_relay_for("b745a140-40bc-4b86-b6dc-084488fc906b"),
+            Ok(Some("node1.rudder.local".to_string()))
+        );
+        assert_eq!(nodeslist.sub_relay_for("unknown"), Err(()));
+    }
+
     #[test]
     fn if_gets_subrelays() {
         assert!(
@@ -462,6 +596,36 @@ mod tests {
         assert_eq!(reference, actual);
     }
 
+    #[test]
+    fn it_gets_neighbor_nodes() {
+        let nodeslist =
+            NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+
+        let mut actual = nodeslist.my_neighbor_nodes();
+        actual.sort();
+        assert_eq!(
+            actual,
+            vec![
+                (
+                    "37817c4d-fbf7-4850-a985-50021f4e8f41".to_string(),
+                    "node2.rudder.local".to_string()
+                ),
+                (
+                    "e745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+                    "node1.rudder.local".to_string()
+                ),
+                ("root".to_string(), "server.rudder.local".to_string()),
+            ]
+        );
+        assert_eq!(
+            nodeslist.my_neighbor_nodes_from(&[
+                "b745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+                "root".to_string(),
+            ]),
+            vec![("root".to_string(), "server.rudder.local".to_string())]
+        );
+    }
+
     #[test]
     fn it_filters_sub_relays() {
         let mut reference = vec![(
@@ -485,5 +649,32 @@ mod tests {
         actual.sort();
 
         assert_eq!(reference, actual);
+
+        assert_eq!(
+            NodesList::new("root".to_string(), "tests/files/nodeslist.json", None)
+                .unwrap()
+                .my_sub_relay_nodes_from(&["b745a140-40bc-4b86-b6dc-084488fc906b".to_string()]),
+            vec![(
+                "e745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+                "node1.rudder.local".to_string(),
+                vec!["b745a140-40bc-4b86-b6dc-084488fc906b".to_string()],
+            )]
+        );
+    }
+
+    #[test]
+    fn it_gets_nodes_behind_sub_relays() {
+        let nodeslist =
+            NodesList::new("root".to_string(), "tests/files/nodeslist.json", None).unwrap();
+        assert_eq!(
+            nodeslist.nodes_behind("e745a140-40bc-4b86-b6dc-084488fc906b"),
+            vec![
+                "a745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+                "b745a140-40bc-4b86-b6dc-084488fc906b".to_string(),
+            ]
+        );
+        assert!(nodeslist
+            .nodes_behind("c745a140-40bc-4b86-b6dc-084488fc906b")
+            .is_empty());
     }
 }
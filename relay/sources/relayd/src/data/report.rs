@@ -1,7 +1,10 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use crate::{data::node::NodeId, output::database::schema::ruddersysevents};
+use crate::{
+    configuration::main::ReportLogsConfig, data::node::NodeId,
+    output::database::schema::ruddersysevents,
+};
 use chrono::prelude::*;
 use nom::{
     branch::alt,
@@ -11,7 +14,11 @@ use nom::{
     IResult,
 };
 use serde::{Deserialize, Serialize};
-use std::fmt::{self, Display};
+use std::{
+    cmp::min,
+    fmt::{self, Display},
+};
+use tracing::debug;
 
 type AgentLogLevel = &'static str;
 
@@ -195,21 +202,54 @@ pub struct RawReport {
 }
 
 impl RawReport {
-    pub fn into_reports(self) -> Vec<Report> {
+    /// Turns preceding log entries into `log_*` reports with the context of the report,
+    /// within the size limits of the configuration.
+    ///
+    /// `runlog_remaining` is the size still available for logs in the runlog.
+    pub fn into_reports(self, cfg: &ReportLogsConfig, runlog_remaining: &mut usize) -> Vec<Report> {
         let mut res = vec![];
-        for log in self.logs {
-            res.push(Report {
-                event_type: log.event_type.to_string(),
-                msg: log.msg,
-                execution_datetime: log.datetime,
-                ..self.report.clone()
-            })
+        if cfg.enabled {
+            let mut remaining = min(cfg.max_size_per_report, *runlog_remaining);
+            let mut skipped = 0;
+            for log in self.logs {
+                let msg = truncate(log.msg, remaining);
+                if msg.is_empty() {
+                    skipped += 1;
+                    continue;
+                }
+                remaining -= msg.len();
+                *runlog_remaining -= msg.len();
+                res.push(Report {
+                    event_type: log.event_type.to_string(),
+                    msg,
+                    execution_datetime: log.datetime,
+                    ..self.report.clone()
+                })
+            }
+            if skipped > 0 {
+                debug!(
+                    "Skipped {} log messages exceeding size limit before report {}",
+                    skipped, self.report
+                );
+            }
         }
         res.push(self.report);
         res
     }
 }
 
+/// Truncates a message to at most `max` bytes, on a char boundary
+pub fn truncate(mut msg: String, max: usize) -> String {
+    if msg.len() > max {
+        let mut end = max;
+        while !msg.is_char_boundary(end) {
+            end -= 1;
+        }
+        msg.truncate(end);
+    }
+    msg
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Queryable)]
 pub struct QueryableReport {
     pub id: i64,
@@ -521,6 +561,65 @@ mod tests {
         );
     }
 
+    #[test]
+    fn it_attaches_logs_to_reports() {
+        let report = "2018-08-24T15:55:01+00:00 CRITICAL: first log\n2018-08-24T15:55:01+00:00 R: [INFO] second log\n2018-08-24T15:55:01+00:00 R: @@Common@@result_repaired@@hasPolicyServer-root@@common-root@@0@@CRON Daemon@@None@@2018-08-24 15:55:01 +00:00##root@#Cron daemon status was repaired\r\n";
+        let raw = || maybe_report(report).unwrap().1.unwrap();
+        let cfg = ReportLogsConfig::default();
+
+        let mut remaining = 1000;
+        let reports = raw().into_reports(&cfg, &mut remaining);
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].event_type, "log_warn");
+        assert_eq!(reports[0].msg, "first log");
+        assert_eq!(reports[0].component, "CRON Daemon");
+        assert_eq!(reports[0].rule_id, "hasPolicyServer-root");
+        assert_eq!(reports[1].event_type, "log_info");
+        assert_eq!(reports[1].msg, "second log");
+        assert_eq!(reports[2].event_type, "result_repaired");
+        assert_eq!(remaining, 1000 - 19);
+
+        // Limited by report size
+        let mut remaining = 1000;
+        let reports = raw().into_reports(
+            &ReportLogsConfig {
+                max_size_per_report: 12,
+                ..cfg
+            },
+            &mut remaining,
+        );
+        assert_eq!(reports.len(), 3);
+        assert_eq!(reports[0].msg, "first log");
+        assert_eq!(reports[1].msg, "sec");
+
+        // Limited by runlog size
+        let mut remaining = 5;
+        let reports = raw().into_reports(&cfg, &mut remaining);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].msg, "first");
+        assert_eq!(remaining, 0);
+
+        // Disabled
+        let mut remaining = 1000;
+        let reports = raw().into_reports(
+            &ReportLogsConfig {
+                enabled: false,
+                ..cfg
+            },
+            &mut remaining,
+        );
+        assert_eq!(reports.len(), 1);
+        assert_eq!(remaining, 1000);
+    }
+
+    #[test]
+    fn it_truncates_on_char_boundary() {
+        assert_eq!(truncate("abc".to_string(), 5), "abc");
+        assert_eq!(truncate("abc".to_string(), 2), "ab");
+        assert_eq!(truncate("aé".to_string(), 2), "a");
+        assert_eq!(truncate("abc".to_string(), 0), "");
+    }
+
     #[test]
     fn it_parses_until_next() {
         let report = "test\n2018-08-24T15:55:01+00:00 R: @@Common@@broken\n";
@@ -2,11 +2,13 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use crate::{
+    configuration::main::ReportLogsConfig,
     data::{
-        report::{runlog, RawReport},
+        report::{runlog, truncate, RawReport},
         Report, RunInfo,
     },
     error::Error,
+    hashing::HashType,
 };
 use serde::{Deserialize, Serialize};
 use std::{
@@ -14,12 +16,16 @@ use std::{
     convert::TryFrom,
     fmt::{self, Display},
     fs::read_to_string,
+    mem,
     path::Path,
     str::FromStr,
 };
 use tracing::{debug, error, warn};
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+/// Appended to truncated messages
+pub const TRUNCATION_MARKER: &str = " [truncated]";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct RunLog {
     pub info: RunInfo,
     // Never empty vec
@@ -61,12 +67,54 @@ impl RunLog {
                 .collect(),
         }
     }
+
+    /// Refuses runlogs with more than `max_reports` reports, and truncates
+    /// messages longer than `max_message_size` bytes.
+    ///
+    /// Returns the number of truncated messages.
+    pub fn limit(&mut self, max_reports: usize, max_message_size: usize) -> Result<usize, Error> {
+        if self.reports.len() > max_reports {
+            return Err(Error::LimitExceeded("number of reports", max_reports));
+        }
+
+        let mut truncated = 0;
+        for report in self.reports.iter_mut() {
+            if report.msg.len() > max_message_size {
+                // Keep room for the marker when possible
+                let marker = if max_message_size >= TRUNCATION_MARKER.len() {
+                    TRUNCATION_MARKER
+                } else {
+                    ""
+                };
+                report.msg = truncate(mem::take(&mut report.msg), max_message_size - marker.len());
+                report.msg.push_str(marker);
+                truncated += 1;
+            }
+        }
+        Ok(truncated)
+    }
+
+    /// Digest of the runlog content (node, run info and all reports),
+    /// stored along with each inserted run
+    pub fn digest(&self) -> String {
+        HashType::Sha256
+            .hash(&serde_json::to_vec(self).expect("runlog should be serializable"))
+            .value
+    }
 }
 
 impl TryFrom<(RunInfo, &str)> for RunLog {
     type Error = Error;
 
     fn try_from(raw_reports: (RunInfo, &str)) -> Result<Self, Self::Error> {
+        RunLog::try_from((raw_reports.0, raw_reports.1, &ReportLogsConfig::default()))
+    }
+}
+
+impl TryFrom<(RunInfo, &str, &ReportLogsConfig)> for RunLog {
+    type Error = Error;
+
+    fn try_from(raw_reports: (RunInfo, &str, &ReportLogsConfig)) -> Result<Self, Self::Error> {
         match runlog(raw_reports.1) {
             Ok(raw_runlog) => {
                 debug!("Parsed runlog {:#?}", raw_runlog.1);
@@ -77,7 +125,7 @@ impl TryFrom<(RunInfo, &str)> for RunLog {
                 }
 
                 let reports: Vec<RawReport> = reports.into_iter().map(Result::unwrap).collect();
-                RunLog::try_from((raw_reports.0, reports))
+                RunLog::try_from((raw_reports.0, reports, raw_reports.2))
             }
             Err(e) => {
                 warn!("{:?}: could not parse '{}'", e, raw_reports.0);
@@ -87,15 +135,25 @@ impl TryFrom<(RunInfo, &str)> for RunLog {
     }
 }
 
-impl TryFrom<(RunInfo, Vec<RawReport>)> for RunLog {
+impl TryFrom<(RunInfo, Vec<RawReport>, &ReportLogsConfig)> for RunLog {
     type Error = Error;
 
-    fn try_from(raw_reports: (RunInfo, Vec<RawReport>)) -> Result<Self, Self::Error> {
+    fn try_from(
+        raw_reports: (RunInfo, Vec<RawReport>, &ReportLogsConfig),
+    ) -> Result<Self, Self::Error> {
+        let logs_cfg = raw_reports.2;
+        let mut runlog_remaining = logs_cfg.max_size_per_runlog;
         let reports: Vec<Report> = raw_reports
             .1
             .into_iter()
-            .flat_map(RawReport::into_reports)
+            .flat_map(|r| r.into_reports(logs_cfg, &mut runlog_remaining))
             .collect();
+        if runlog_remaining == 0 {
+            warn!(
+                "Log messages in {} runlog exceeded {} bytes and were truncated",
+                raw_reports.0, logs_cfg.max_size_per_runlog
+            );
+        }
         let info = raw_reports.0;
         let timestamp = reports
             .first()
@@ -146,6 +204,70 @@ mod tests {
         assert!(test_done > 1);
     }
 
+    #[test]
+    fn it_computes_runlog_digest() {
+        let runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        let same = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        let other = RunLog::new(
+            "tests/files/runlogs/2017-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+
+        assert_eq!(runlog.digest(), same.digest());
+        assert_ne!(runlog.digest(), other.digest());
+
+        // Only the first report is identical
+        let truncated = RunLog {
+            info: runlog.info.clone(),
+            reports: runlog.reports[..1].to_vec(),
+        };
+        assert_ne!(runlog.digest(), truncated.digest());
+    }
+
+    #[test]
+    fn it_limits_runlog_size() {
+        let mut runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        let reference = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+
+        assert!(runlog.limit(70, 1000).is_err());
+        assert_eq!(runlog.limit(71, 1000).unwrap(), 0);
+        assert_eq!(runlog, reference);
+
+        let longer = reference
+            .reports
+            .iter()
+            .filter(|r| r.msg.len() > 20)
+            .count();
+        assert!(longer > 0);
+        assert_eq!(runlog.limit(71, 20).unwrap(), longer);
+        assert!(runlog.reports.iter().all(|r| r.msg.len() <= 20));
+        assert!(runlog
+            .reports
+            .iter()
+            .any(|r| r.msg.ends_with(TRUNCATION_MARKER)));
+
+        // No room for the marker
+        let mut runlog = reference.clone();
+        let longer = reference.reports.iter().filter(|r| r.msg.len() > 5).count();
+        assert_eq!(runlog.limit(71, 5).unwrap(), longer);
+        for (report, original) in runlog.reports.iter().zip(&reference.reports) {
+            assert!(original.msg.starts_with(&report.msg));
+            assert_eq!(report.msg.len(), original.msg.len().min(5));
+        }
+    }
+
     #[test]
     fn it_detect_invalid_node_in_runlog() {
         assert!(
@@ -2,22 +2,26 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use crate::{
+    data::node::{NodeId, NodeIdRef},
     error::Error,
     hashing::{Hash, HashType},
 };
 use openssl::{
     error::ErrorStack,
-    pkey::{PKey, Public},
-    rsa::Rsa,
-    sign::Verifier,
+    nid::Nid,
+    pkey::{Id, PKey, Public},
+    rsa::{Padding, Rsa},
+    sign::{RsaPssSaltlen, Verifier},
 };
 use regex::Regex;
-use std::{collections::HashMap, fmt, path::PathBuf, str, str::FromStr};
+use std::{collections::HashMap, fmt, io::BufRead, path::PathBuf, str, str::FromStr};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum SignatureFormat {
     // "rudder-signature-v1"
     RudderV1,
+    // "rudder-signature-v2"
+    RudderV2,
 }
 
 impl FromStr for SignatureFormat {
@@ -26,6 +30,7 @@ impl FromStr for SignatureFormat {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "rudder-signature-v1" => Ok(Self::RudderV1),
+            "rudder-signature-v2" => Ok(Self::RudderV2),
             _ => Err(Error::InvalidHeader(s.to_string())),
         }
     }
@@ -38,6 +43,7 @@ impl fmt::Display for SignatureFormat {
             "{}",
             match self {
                 SignatureFormat::RudderV1 => "rudder-signature-v1",
+                SignatureFormat::RudderV2 => "rudder-signature-v2",
             }
         )
     }
@@ -52,29 +58,9 @@ pub struct SharedFile {
 
 impl SharedFile {
     pub fn new(source_id: String, target_id: String, file_id: String) -> Result<SharedFile, Error> {
-        // Validate data
-        // Only ascii alphanumeric, - and .
-        // This is the documented constraint for file_id
-        // More than enough for node ids too but we don't have a precise spec
-        let check = Regex::new(r"^[(?-u:\w)\-.]+$").unwrap();
-        if !check.is_match(&source_id) {
-            return Err(Error::InvalidSharedFile(format!(
-                "invalid source_id: {}",
-                source_id
-            )));
-        }
-        if !check.is_match(&target_id) {
-            return Err(Error::InvalidSharedFile(format!(
-                "invalid target_id: {}",
-                target_id
-            )));
-        }
-        if !check.is_match(&file_id) {
-            return Err(Error::InvalidSharedFile(format!(
-                "invalid file_id: {}",
-                source_id
-            )));
-        }
+        Self::check_id("source_id", &source_id)?;
+        Self::check_id("target_id", &target_id)?;
+        Self::check_id("file_id", &file_id)?;
         Ok(SharedFile {
             source_id,
             target_id,
@@ -82,6 +68,22 @@ impl SharedFile {
         })
     }
 
+    /// Validates a node or file id
+    pub fn check_id(name: &str, id: &str) -> Result<(), Error> {
+        // Only ascii alphanumeric, - and .
+        // This is the documented constraint for file_id
+        // More than enough for node ids too but we don't have a precise spec
+        let check = Regex::new(r"^[(?-u:\w)\-.]+$").unwrap();
+        if check.is_match(id) {
+            Ok(())
+        } else {
+            Err(Error::InvalidSharedFile(format!(
+                "invalid {}: {}",
+                name, id
+            )))
+        }
+    }
+
     pub fn path(&self) -> PathBuf {
         PathBuf::from(&self.target_id)
             .join(&self.source_id)
@@ -101,32 +103,47 @@ impl SharedFile {
 /// Represented as key-value pairs
 #[derive(Debug, PartialEq, Eq)]
 pub struct Metadata {
-    format: SignatureFormat,
     pub digest: String,
     pub hash: Hash,
-    // TODO Use proper public key type
-    // Currently exposed though the `pubkey` method
-    short_pubkey: String,
+    key: SigningKey,
     // ttl may not be there in all contexts
     pub expires: Option<i64>,
-    // These fields are currently not used
-    hostname: String,
-    key_date: String,
-    key_id: String,
+}
+
+/// Signing key and signed information, depending on the signature format
+#[derive(Debug, PartialEq, Eq)]
+enum SigningKey {
+    /// RSA PKCS#1 v1.5 signature of the file content
+    V1 {
+        // TODO Use proper public key type
+        // Currently exposed though the `pubkey` method
+        short_pubkey: String,
+        // These fields are currently not used
+        hostname: String,
+        key_date: String,
+        key_id: String,
+    },
+    /// Signature of the metadata, which contains the hash of the file content
+    ///
+    /// RSA (with PSS padding), ECDSA P-256 and Ed25519 keys are supported.
+    V2 {
+        /// SubjectPublicKeyInfo, as PEM without begin and end markers
+        pubkey: String,
+        /// Signature timestamp
+        ///
+        /// Not checked, the same file can be uploaded again to its target node
+        /// as long as its signature is valid.
+        timestamp: i64,
+        /// Node the file is shared with, so that it can't be replayed
+        /// to another node
+        target_id: NodeId,
+    },
 }
 
 impl fmt::Display for Metadata {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "header={}", &self.format)?;
-        writeln!(f, "algorithm={}", &self.hash.hash_type)?;
-        writeln!(f, "digest={}", &self.digest)?;
-        writeln!(f, "hash_value={}", &self.hash.value)?;
-        writeln!(f, "short_pubkey={}", &self.short_pubkey)?;
-        writeln!(f, "hostname={}", &self.hostname)?;
-        writeln!(f, "keydate={}", &self.key_date)?;
-        writeln!(f, "keyid={}", &self.key_id)?;
-        if let Some(expires) = self.expires {
-            writeln!(f, "expires={}", expires)?;
+        for (key, value) in self.fields() {
+            writeln!(f, "{}={}", key, value)?;
         }
         Ok(())
     }
@@ -165,13 +182,34 @@ impl FromStr for Metadata {
         // Validate hexadecimal string
         hex::decode(&digest).map_err(|_| Error::InvalidHeader(digest.clone()))?;
 
-        let short_pubkey = extract(&parsed, "short_pubkey")?.to_string();
-        // validate public key
-        Self::parse_pubkey(&short_pubkey)?;
-
-        let hostname = extract(&parsed, "hostname")?.to_string();
-        let key_date = extract(&parsed, "keydate")?.to_string();
-        let key_id = extract(&parsed, "keyid")?.to_string();
+        let key = match format {
+            SignatureFormat::RudderV1 => {
+                let short_pubkey = extract(&parsed, "short_pubkey")?.to_string();
+                // validate public key
+                Self::parse_pubkey(&short_pubkey)?;
+
+                SigningKey::V1 {
+                    short_pubkey,
+                    hostname: extract(&parsed, "hostname")?.to_string(),
+                    key_date: extract(&parsed, "keydate")?.to_string(),
+                    key_id: extract(&parsed, "keyid")?.to_string(),
+                }
+            }
+            SignatureFormat::RudderV2 => {
+                let pubkey = extract(&parsed, "pubkey")?.to_string();
+                // validate public key
+                Self::check_key_type(&Self::parse_spki(&pubkey)?)?;
+
+                let target_id = extract(&parsed, "target")?.to_string();
+                SharedFile::check_id("target", &target_id)?;
+
+                SigningKey::V2 {
+                    pubkey,
+                    timestamp: extract(&parsed, "timestamp")?.parse::<i64>()?,
+                    target_id,
+                }
+            }
+        };
 
         let expires = match parsed.get("expires") {
             Some(ttl) => Some(ttl.parse::<i64>()?),
@@ -179,19 +217,28 @@ impl FromStr for Metadata {
         };
 
         Ok(Metadata {
-            format,
             digest,
             hash,
-            short_pubkey,
-            hostname,
-            key_date,
-            key_id,
+            key,
             expires,
         })
     }
 }
 
 impl Metadata {
+    /// Reads metadata at the beginning of a stream, until the empty line
+    /// separating it from file content
+    pub fn from_reader<R: BufRead>(stream: &mut R) -> Result<Self, Error> {
+        let mut raw_meta = String::new();
+        // Here we cannot iterate on lines as the file content may not be valid UTF-8.
+        let mut read = 2;
+        // Let's read while we find an empty line.
+        while read > 1 {
+            read = stream.read_line(&mut raw_meta)?;
+        }
+        Self::from_str(&raw_meta)
+    }
+
     fn parse_pubkey(short_key: &str) -> Result<PKey<Public>, ErrorStack> {
         PKey::from_rsa(Rsa::public_key_from_pem_pkcs1(
             format!(
@@ -202,9 +249,102 @@ impl Metadata {
         )?)
     }
 
+    fn parse_spki(key: &str) -> Result<PKey<Public>, ErrorStack> {
+        PKey::public_key_from_pem(
+            format!(
+                "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+                key
+            )
+            .as_bytes(),
+        )
+    }
+
+    /// Only allow key types with a well defined signature algorithm
+    fn check_key_type(key: &PKey<Public>) -> Result<(), Error> {
+        match key.id() {
+            Id::RSA | Id::ED25519 => Ok(()),
+            Id::EC if key.ec_key()?.group().curve_name() == Some(Nid::X9_62_PRIME256V1) => Ok(()),
+            _ => Err(Error::UnsupportedKeyType),
+        }
+    }
+
+    pub fn format(&self) -> SignatureFormat {
+        match self.key {
+            SigningKey::V1 { .. } => SignatureFormat::RudderV1,
+            SigningKey::V2 { .. } => SignatureFormat::RudderV2,
+        }
+    }
+
+    /// Node the file was signed for, only known with `rudder-signature-v2`
+    pub fn target_id(&self) -> Option<&NodeIdRef> {
+        match self.key {
+            SigningKey::V1 { .. } => None,
+            SigningKey::V2 { ref target_id, .. } => Some(target_id),
+        }
+    }
+
+    /// Whether the file has expired at the given timestamp
+    pub fn has_expired(&self, now: i64) -> bool {
+        self.expires.map(|expires| expires < now).unwrap_or(false)
+    }
+
+    /// Key-value pairs, in the order of the serialized format
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![
+            ("header", self.format().to_string()),
+            ("algorithm", self.hash.hash_type.to_string()),
+            ("digest", self.digest.clone()),
+            ("hash_value", self.hash.value.clone()),
+        ];
+        match self.key {
+            SigningKey::V1 {
+                ref short_pubkey,
+                ref hostname,
+                ref key_date,
+                ref key_id,
+            } => fields.extend(vec![
+                ("short_pubkey", short_pubkey.clone()),
+                ("hostname", hostname.clone()),
+                ("keydate", key_date.clone()),
+                ("keyid", key_id.clone()),
+            ]),
+            SigningKey::V2 {
+                ref pubkey,
+                timestamp,
+                ref target_id,
+            } => fields.extend(vec![
+                ("pubkey", pubkey.clone()),
+                ("timestamp", timestamp.to_string()),
+                ("target", target_id.clone()),
+            ]),
+        }
+        if let Some(expires) = self.expires {
+            fields.push(("expires", expires.to_string()));
+        }
+        fields
+    }
+
+    /// Data signed in `rudder-signature-v2`
+    ///
+    /// The `header`, `algorithm`, `hash_value`, `pubkey`, `timestamp` and `target`
+    /// lines, in this order and each ending with `\n`, regardless of the order they
+    /// were received in. Unknown lines are not signed and are not kept.
+    fn signed_data(&self) -> String {
+        self.fields()
+            .into_iter()
+            .filter(|(key, _)| *key != "digest" && *key != "expires")
+            .map(|(key, value)| format!("{}={}\n", key, value))
+            .collect()
+    }
+
     /// Get public key from metadata
     pub fn pubkey(&self) -> Result<PKey<Public>, ErrorStack> {
-        Self::parse_pubkey(&self.short_pubkey)
+        match self.key {
+            SigningKey::V1 {
+                ref short_pubkey, ..
+            } => Self::parse_pubkey(short_pubkey),
+            SigningKey::V2 { ref pubkey, .. } => Self::parse_spki(pubkey),
+        }
     }
 
     fn validate_signature_key(
@@ -218,20 +358,98 @@ impl Metadata {
         verifier.verify(digest)
     }
 
+    /// Validates a `rudder-signature-v2` signature of the metadata, using the
+    /// signature algorithm of the key type
+    fn validate_metadata_signature_key(
+        pubkey: PKey<Public>,
+        data: &[u8],
+        hash_type: HashType,
+        digest: &[u8],
+    ) -> Result<bool, ErrorStack> {
+        if pubkey.id() == Id::ED25519 {
+            // No separate digest
+            let mut verifier = Verifier::new_without_digest(&pubkey)?;
+            return verifier.verify_oneshot(digest, data);
+        }
+
+        let mut verifier = Verifier::new(hash_type.to_openssl_hash(), &pubkey)?;
+        if pubkey.id() == Id::RSA {
+            verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+            verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+        }
+        verifier.update(data)?;
+        verifier.verify(digest)
+    }
+
     pub fn validate_signature(
         &self,
         data: &[u8],
         hash_type: HashType,
         digest: &[u8],
     ) -> Result<bool, ErrorStack> {
-        Self::validate_signature_key(self.pubkey()?, data, hash_type, digest)
+        match self.key {
+            SigningKey::V1 { .. } => {
+                Self::validate_signature_key(self.pubkey()?, data, hash_type, digest)
+            }
+            SigningKey::V2 { .. } => {
+                let mut hasher = hash_type.hasher();
+                hasher.input(data);
+                self.validate_signature_digest(&hasher.result(), hash_type, digest)
+            }
+        }
+    }
+
+    fn validate_signature_digest_key(
+        pubkey: PKey<Public>,
+        data_digest: &[u8],
+        hash_type: HashType,
+        digest: &[u8],
+    ) -> Result<bool, ErrorStack> {
+        let rsa = pubkey.rsa()?;
+        let mut decrypted = vec![0; rsa.size() as usize];
+        let len = match rsa.public_decrypt(digest, &mut decrypted, Padding::PKCS1) {
+            Ok(len) => len,
+            // Not signed with this key
+            Err(_) => return Ok(false),
+        };
+        let mut expected = hash_type.digest_info_prefix().to_vec();
+        expected.extend_from_slice(data_digest);
+        Ok(decrypted[..len] == expected[..])
+    }
+
+    /// Validates the signature against the already computed digest of the data,
+    /// to avoid keeping the data in memory
+    pub fn validate_signature_digest(
+        &self,
+        data_digest: &[u8],
+        hash_type: HashType,
+        digest: &[u8],
+    ) -> Result<bool, ErrorStack> {
+        match self.key {
+            SigningKey::V1 { .. } => {
+                Self::validate_signature_digest_key(self.pubkey()?, data_digest, hash_type, digest)
+            }
+            // The signature covers the hash of the data
+            SigningKey::V2 { .. } => Ok(hash_type == self.hash.hash_type
+                && hex::encode(data_digest).eq_ignore_ascii_case(&self.hash.value)
+                && Self::validate_metadata_signature_key(
+                    self.pubkey()?,
+                    self.signed_data().as_bytes(),
+                    hash_type,
+                    digest,
+                )?),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use openssl::sign::Signer;
+    use openssl::{
+        ec::{EcGroup, EcKey},
+        pkey::Private,
+        sign::Signer,
+    };
 
     #[test]
     fn it_checks_shared_file() {
@@ -259,17 +477,18 @@ mod tests {
     #[test]
     fn it_writes_and_parses_the_metadata() {
         let metadata = Metadata {
-            format: SignatureFormat::RudderV1,
             hash: Hash::new_with_type(
                 HashType::Sha256,
                 "a75fda39a7af33eb93ab1c74874dcf66d5761ad30977368cf0c4788cf5bfd34f".to_string(),
             )
             .unwrap(),
             digest: "8ca9efc5752e133e2e80e2661c176fa50f".to_string(),
-            short_pubkey: "MIICCgKCAgEAuok8JTvRssiupO0IfH4OGnWFqQg5dmI/4JsCiPEUf78iFBwFFpwuNXDJXCKaHtpjuc3DAy9l7fmZ+bQmkfde+Qo3yAd2ZsId80TBZOy6uFQyl4ASLNgY8RKIFxD6+AsutI27KexSnL3QLCgywnheRv4Ur31a6MVY1xfSQnADruBBad+5SaF3hTpEcAMg2hDQsIcyR32MPRy9MOVmvBlgI2hZsgh9QQf9wTLxGuMw/pJKOPRwwFkk/5bhFBve2sL1OI0pRsM6i7SxNXRhM6NWlmObhP+Z7C6N7TY00Z+tizgETmYJ35llyInjc1i+0bWaj5p3cbSCVdQ5zomZ3L9XbsWmjl0P/cw06qqNPuLR799K+R1XgA94nUUzo2pVigPh6sj2XMS8FOWXMXy2TNEOA+NQV5+vYwIlUizvB/HHSc3WKqNGgCifdJBmJJ8QTg5cJE6s+91O99eMMAQ0Ecj+nY5QEYkbIn4gjNpojam3jyS72o0J4nlj4ECbR/rj6L5b+kj5F3DbYqSdLC+crKUIoBZH1msCuJcQ9Zk/YHw87iVyWoZOVtJUUaw3n8vH/YCWPBQRzZp+4zlyIYJIIz+V/FJZX5YNW9XgoeRG8Q0mOmLy0FbQUS/klYlpeW3PKLSQmcSLvrgZnhKMyhEohC0zOSqJU0ui4VUWY5tv1bhbTo8CAwEAAQ==".to_string(),
-            hostname: "ubuntu-18-04-64".to_string(),
-            key_date: "2018-10-3118:21:43.653257143".to_string(),
-            key_id: "B29D02BB".to_string(),
+            key: SigningKey::V1 {
+                short_pubkey: "MIICCgKCAgEAuok8JTvRssiupO0IfH4OGnWFqQg5dmI/4JsCiPEUf78iFBwFFpwuNXDJXCKaHtpjuc3DAy9l7fmZ+bQmkfde+Qo3yAd2ZsId80TBZOy6uFQyl4ASLNgY8RKIFxD6+AsutI27KexSnL3QLCgywnheRv4Ur31a6MVY1xfSQnADruBBad+5SaF3hTpEcAMg2hDQsIcyR32MPRy9MOVmvBlgI2hZsgh9QQf9wTLxGuMw/pJKOPRwwFkk/5bhFBve2sL1OI0pRsM6i7SxNXRhM6NWlmObhP+Z7C6N7TY00Z+tizgETmYJ35llyInjc1i+0bWaj5p3cbSCVdQ5zomZ3L9XbsWmjl0P/cw06qqNPuLR799K+R1XgA94nUUzo2pVigPh6sj2XMS8FOWXMXy2TNEOA+NQV5+vYwIlUizvB/HHSc3WKqNGgCifdJBmJJ8QTg5cJE6s+91O99eMMAQ0Ecj+nY5QEYkbIn4gjNpojam3jyS72o0J4nlj4ECbR/rj6L5b+kj5F3DbYqSdLC+crKUIoBZH1msCuJcQ9Zk/YHw87iVyWoZOVtJUUaw3n8vH/YCWPBQRzZp+4zlyIYJIIz+V/FJZX5YNW9XgoeRG8Q0mOmLy0FbQUS/klYlpeW3PKLSQmcSLvrgZnhKMyhEohC0zOSqJU0ui4VUWY5tv1bhbTo8CAwEAAQ==".to_string(),
+                hostname: "ubuntu-18-04-64".to_string(),
+                key_date: "2018-10-3118:21:43.653257143".to_string(),
+                key_id: "B29D02BB".to_string(),
+            },
             expires: None,
         };
         let serialized = "header=rudder-signature-v1\nalgorithm=sha256\ndigest=8ca9efc5752e133e2e80e2661c176fa50f\nhash_value=a75fda39a7af33eb93ab1c74874dcf66d5761ad30977368cf0c4788cf5bfd34f\nshort_pubkey=MIICCgKCAgEAuok8JTvRssiupO0IfH4OGnWFqQg5dmI/4JsCiPEUf78iFBwFFpwuNXDJXCKaHtpjuc3DAy9l7fmZ+bQmkfde+Qo3yAd2ZsId80TBZOy6uFQyl4ASLNgY8RKIFxD6+AsutI27KexSnL3QLCgywnheRv4Ur31a6MVY1xfSQnADruBBad+5SaF3hTpEcAMg2hDQsIcyR32MPRy9MOVmvBlgI2hZsgh9QQf9wTLxGuMw/pJKOPRwwFkk/5bhFBve2sL1OI0pRsM6i7SxNXRhM6NWlmObhP+Z7C6N7TY00Z+tizgETmYJ35llyInjc1i+0bWaj5p3cbSCVdQ5zomZ3L9XbsWmjl0P/cw06qqNPuLR799K+R1XgA94nUUzo2pVigPh6sj2XMS8FOWXMXy2TNEOA+NQV5+vYwIlUizvB/HHSc3WKqNGgCifdJBmJJ8QTg5cJE6s+91O99eMMAQ0Ecj+nY5QEYkbIn4gjNpojam3jyS72o0J4nlj4ECbR/rj6L5b+kj5F3DbYqSdLC+crKUIoBZH1msCuJcQ9Zk/YHw87iVyWoZOVtJUUaw3n8vH/YCWPBQRzZp+4zlyIYJIIz+V/FJZX5YNW9XgoeRG8Q0mOmLy0FbQUS/klYlpeW3PKLSQmcSLvrgZnhKMyhEohC0zOSqJU0ui4VUWY5tv1bhbTo8CAwEAAQ==\nhostname=ubuntu-18-04-64\nkeydate=2018-10-3118:21:43.653257143\nkeyid=B29D02BB\n";
@@ -281,17 +500,18 @@ mod tests {
     #[test]
     fn it_writes_and_parses_the_metadata_with_expired() {
         let metadata = Metadata {
-            format: SignatureFormat::RudderV1,
             hash: Hash::new_with_type(
                 HashType::Sha256,
                 "a75fda39a7af33eb93ab1c74874dcf66d5761ad30977368cf0c4788cf5bfd34f".to_string(),
             )
             .unwrap(),
             digest: "8ca9efc5752e133e2e80e2661c176fa50f".to_string(),
-            short_pubkey: "MIICCgKCAgEAuok8JTvRssiupO0IfH4OGnWFqQg5dmI/4JsCiPEUf78iFBwFFpwuNXDJXCKaHtpjuc3DAy9l7fmZ+bQmkfde+Qo3yAd2ZsId80TBZOy6uFQyl4ASLNgY8RKIFxD6+AsutI27KexSnL3QLCgywnheRv4Ur31a6MVY1xfSQnADruBBad+5SaF3hTpEcAMg2hDQsIcyR32MPRy9MOVmvBlgI2hZsgh9QQf9wTLxGuMw/pJKOPRwwFkk/5bhFBve2sL1OI0pRsM6i7SxNXRhM6NWlmObhP+Z7C6N7TY00Z+tizgETmYJ35llyInjc1i+0bWaj5p3cbSCVdQ5zomZ3L9XbsWmjl0P/cw06qqNPuLR799K+R1XgA94nUUzo2pVigPh6sj2XMS8FOWXMXy2TNEOA+NQV5+vYwIlUizvB/HHSc3WKqNGgCifdJBmJJ8QTg5cJE6s+91O99eMMAQ0Ecj+nY5QEYkbIn4gjNpojam3jyS72o0J4nlj4ECbR/rj6L5b+kj5F3DbYqSdLC+crKUIoBZH1msCuJcQ9Zk/YHw87iVyWoZOVtJUUaw3n8vH/YCWPBQRzZp+4zlyIYJIIz+V/FJZX5YNW9XgoeRG8Q0mOmLy0FbQUS/klYlpeW3PKLSQmcSLvrgZnhKMyhEohC0zOSqJU0ui4VUWY5tv1bhbTo8CAwEAAQ==".to_string(),
-            hostname: "ubuntu-18-04-64".to_string(),
-            key_date: "2018-10-3118:21:43.653257143".to_string(),
-            key_id: "B29D02BB".to_string(),
+            key: SigningKey::V1 {
+                short_pubkey: "MIICCgKCAgEAuok8JTvRssiupO0IfH4OGnWFqQg5dmI/4JsCiPEUf78iFBwFFpwuNXDJXCKaHtpjuc3DAy9l7fmZ+bQmkfde+Qo3yAd2ZsId80TBZOy6uFQyl4ASLNgY8RKIFxD6+AsutI27KexSnL3QLCgywnheRv4Ur31a6MVY1xfSQnADruBBad+5SaF3hTpEcAMg2hDQsIcyR32MPRy9MOVmvBlgI2hZsgh9QQf9wTLxGuMw/pJKOPRwwFkk/5bhFBve2sL1OI0pRsM6i7SxNXRhM6NWlmObhP+Z7C6N7TY00Z+tizgETmYJ35llyInjc1i+0bWaj5p3cbSCVdQ5zomZ3L9XbsWmjl0P/cw06qqNPuLR799K+R1XgA94nUUzo2pVigPh6sj2XMS8FOWXMXy2TNEOA+NQV5+vYwIlUizvB/HHSc3WKqNGgCifdJBmJJ8QTg5cJE6s+91O99eMMAQ0Ecj+nY5QEYkbIn4gjNpojam3jyS72o0J4nlj4ECbR/rj6L5b+kj5F3DbYqSdLC+crKUIoBZH1msCuJcQ9Zk/YHw87iVyWoZOVtJUUaw3n8vH/YCWPBQRzZp+4zlyIYJIIz+V/FJZX5YNW9XgoeRG8Q0mOmLy0FbQUS/klYlpeW3PKLSQmcSLvrgZnhKMyhEohC0zOSqJU0ui4VUWY5tv1bhbTo8CAwEAAQ==".to_string(),
+                hostname: "ubuntu-18-04-64".to_string(),
+                key_date: "2018-10-3118:21:43.653257143".to_string(),
+                key_id: "B29D02BB".to_string(),
+            },
             expires: Some(1580941341),
         };
         let serialized = "header=rudder-signature-v1\nalgorithm=sha256\ndigest=8ca9efc5752e133e2e80e2661c176fa50f\nhash_value=a75fda39a7af33eb93ab1c74874dcf66d5761ad30977368cf0c4788cf5bfd34f\nshort_pubkey=MIICCgKCAgEAuok8JTvRssiupO0IfH4OGnWFqQg5dmI/4JsCiPEUf78iFBwFFpwuNXDJXCKaHtpjuc3DAy9l7fmZ+bQmkfde+Qo3yAd2ZsId80TBZOy6uFQyl4ASLNgY8RKIFxD6+AsutI27KexSnL3QLCgywnheRv4Ur31a6MVY1xfSQnADruBBad+5SaF3hTpEcAMg2hDQsIcyR32MPRy9MOVmvBlgI2hZsgh9QQf9wTLxGuMw/pJKOPRwwFkk/5bhFBve2sL1OI0pRsM6i7SxNXRhM6NWlmObhP+Z7C6N7TY00Z+tizgETmYJ35llyInjc1i+0bWaj5p3cbSCVdQ5zomZ3L9XbsWmjl0P/cw06qqNPuLR799K+R1XgA94nUUzo2pVigPh6sj2XMS8FOWXMXy2TNEOA+NQV5+vYwIlUizvB/HHSc3WKqNGgCifdJBmJJ8QTg5cJE6s+91O99eMMAQ0Ecj+nY5QEYkbIn4gjNpojam3jyS72o0J4nlj4ECbR/rj6L5b+kj5F3DbYqSdLC+crKUIoBZH1msCuJcQ9Zk/YHw87iVyWoZOVtJUUaw3n8vH/YCWPBQRzZp+4zlyIYJIIz+V/FJZX5YNW9XgoeRG8Q0mOmLy0FbQUS/klYlpeW3PKLSQmcSLvrgZnhKMyhEohC0zOSqJU0ui4VUWY5tv1bhbTo8CAwEAAQ==\nhostname=ubuntu-18-04-64\nkeydate=2018-10-3118:21:43.653257143\nkeyid=B29D02BB\nexpires=1580941341\n";
@@ -318,8 +538,146 @@ mod tests {
 
         let signature = signer.sign_to_vec().unwrap();
 
-        assert!(
-            Metadata::validate_signature_key(keypub, data, HashType::Sha512, &signature).unwrap()
+        assert!(Metadata::validate_signature_key(
+            keypub.clone(),
+            data,
+            HashType::Sha512,
+            &signature
+        )
+        .unwrap());
+        let mut hasher = HashType::Sha512.hasher();
+        hasher.input(data);
+        let data_digest = hasher.result();
+        assert!(Metadata::validate_signature_digest_key(
+            keypub.clone(),
+            &data_digest,
+            HashType::Sha512,
+            &signature
+        )
+        .unwrap());
+        assert!(!Metadata::validate_signature_digest_key(
+            keypub.clone(),
+            &HashType::Sha256.hasher().result(),
+            HashType::Sha256,
+            &signature
+        )
+        .unwrap());
+        assert!(!Metadata::validate_signature_digest_key(
+            keypub,
+            &data_digest,
+            HashType::Sha512,
+            b"not a signature"
+        )
+        .unwrap());
+    }
+
+    fn signed_v2_metadata(
+        key: &PKey<Private>,
+        hash_type: HashType,
+        data: &[u8],
+        target_id: &str,
+    ) -> Metadata {
+        let pem = String::from_utf8(key.public_key_to_pem().unwrap()).unwrap();
+        let mut metadata = Metadata {
+            digest: String::new(),
+            hash: hash_type.hash(data),
+            key: SigningKey::V2 {
+                pubkey: pem.lines().filter(|l| !l.starts_with("-----")).collect(),
+                timestamp: 1_600_000_000,
+                target_id: target_id.to_string(),
+            },
+            expires: None,
+        };
+        let signed_data = metadata.signed_data();
+
+        let signature = if key.id() == Id::ED25519 {
+            let mut signer = Signer::new_without_digest(key).unwrap();
+            signer.sign_oneshot_to_vec(signed_data.as_bytes()).unwrap()
+        } else {
+            let mut signer = Signer::new(hash_type.to_openssl_hash(), key).unwrap();
+            if key.id() == Id::RSA {
+                signer.set_rsa_padding(Padding::PKCS1_PSS).unwrap();
+                signer
+                    .set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)
+                    .unwrap();
+            }
+            signer.update(signed_data.as_bytes()).unwrap();
+            signer.sign_to_vec().unwrap()
+        };
+        metadata.digest = hex::encode(signature);
+        metadata
+    }
+
+    #[test]
+    pub fn it_validates_v2_signatures() {
+        let data = b"hello, world!";
+        let p256 = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let keys = vec![
+            PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(),
+            PKey::from_ec_key(EcKey::generate(&p256).unwrap()).unwrap(),
+            PKey::generate_ed25519().unwrap(),
+        ];
+
+        for key in keys {
+            let metadata: Metadata = signed_v2_metadata(&key, HashType::Sha256, data, "target")
+                .to_string()
+                .parse()
+                .unwrap();
+            assert_eq!(metadata.format(), SignatureFormat::RudderV2);
+            assert_eq!(metadata.target_id(), Some("target"));
+            let digest = hex::decode(&metadata.digest).unwrap();
+            assert!(metadata
+                .validate_signature(data, HashType::Sha256, &digest)
+                .unwrap());
+            assert!(!metadata
+                .validate_signature(b"hello", HashType::Sha256, &digest)
+                .unwrap());
+
+            // Replayed to another node
+            let replayed: Metadata = metadata
+                .to_string()
+                .replace("target=target", "target=other")
+                .parse()
+                .unwrap();
+            assert!(!replayed
+                .validate_signature(data, HashType::Sha256, &digest)
+                .unwrap());
+        }
+
+        let p384 = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&p384).unwrap()).unwrap();
+        assert!(signed_v2_metadata(&key, HashType::Sha256, data, "target")
+            .to_string()
+            .parse::<Metadata>()
+            .is_err());
+    }
+
+    #[test]
+    pub fn it_signs_v2_metadata_in_canonical_form() {
+        let data = b"hello, world!";
+        let key = PKey::generate_ed25519().unwrap();
+        let metadata = signed_v2_metadata(&key, HashType::Sha256, data, "target");
+        let pubkey = match metadata.key {
+            SigningKey::V2 { ref pubkey, .. } => pubkey.clone(),
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            metadata.signed_data(),
+            format!(
+                "header=rudder-signature-v2\nalgorithm=sha256\nhash_value={}\npubkey={}\ntimestamp=1600000000\ntarget=target\n",
+                metadata.hash.value, pubkey
+            )
         );
+
+        // Received in another order, with an unknown line
+        let mut lines: Vec<String> = metadata.to_string().lines().map(String::from).collect();
+        lines.reverse();
+        lines.push("unknown=value".to_string());
+        let received: Metadata = lines.join("\n").parse().unwrap();
+        assert_eq!(received, metadata);
+        let digest = hex::decode(&received.digest).unwrap();
+        assert!(received
+            .validate_signature(data, HashType::Sha256, &digest)
+            .unwrap());
     }
 }
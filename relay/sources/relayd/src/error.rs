@@ -5,7 +5,7 @@ use crate::data::node::NodeId;
 use chrono;
 use diesel;
 use serde_json;
-use std::{io, num, path::PathBuf};
+use std::{io, num, path::PathBuf, sync::Arc};
 use thiserror::Error;
 use toml;
 
@@ -31,6 +31,20 @@ pub enum Error {
     MissingCertificateForNode(NodeId),
     #[error("unknown node: {0}")]
     UnknownNode(NodeId),
+    #[error("invalid inventory: {0}")]
+    InvalidInventory(String),
+    #[error("{0} processing is disabled")]
+    DisabledProcessing(&'static str),
+    #[error("{0} exceeds the limit of {1}")]
+    LimitExceeded(&'static str, usize),
+    #[error("unknown failed file: {0}")]
+    UnknownFailedFile(String),
+    #[error("unknown processing type: {0}")]
+    UnknownProcessingType(String),
+    #[error("{0} output is unavailable")]
+    OutputUnavailable(&'static str),
+    #[error("batch insertion error: {0}")]
+    BatchInsertion(Arc<Error>),
     #[error("database error: {0}")]
     Database(#[from] diesel::result::Error),
     #[error("database connection error: {0}")]
@@ -71,6 +85,12 @@ pub enum Error {
     GlobalLogger(#[from] tracing::dispatcher::SetGlobalDefaultError),
     #[error("logger setting error: {0}")]
     SetLogLogger(#[from] log::SetLoggerError),
+    #[error("timer error: {0}")]
+    Timer(#[from] tokio::timer::Error),
+    #[error("unknown remote run job: {0}")]
+    UnknownRemoteRunJob(String),
+    #[error("invalid output format: {0}")]
+    InvalidOutputFormat(String),
     #[error("missing target nodes")]
     MissingTargetNodes,
     #[error("invalid hash type provided {invalid:} (available hash types: {valid:})")]
@@ -90,12 +110,22 @@ pub enum Error {
     MissingHeader(String),
     #[error("HTTP error: {0}")]
     HttpClient(#[from] reqwest::Error),
+    #[error("HTTP server error: {0}")]
+    HttpServer(#[from] warp::Error),
     #[error("Invalid duration: {0}")]
     InvalidDuration(#[from] humantime::DurationError),
     #[error("Invalid hexadecimal: {0}")]
     InvalidHexadecimalValue(#[from] hex::FromHexError),
+    #[error("invalid query string: {0}")]
+    InvalidQuery(String),
+    #[error("unsupported public key type")]
+    UnsupportedKeyType,
     #[error("invalid shared file: {0}")]
     InvalidSharedFile(String),
+    #[error("missing client certificate")]
+    MissingClientCertificate,
+    #[error("client certificate does not belong to {0}")]
+    InvalidClientCertificate(NodeId),
     #[error("could not extract zip file: {0}")]
     Zip(#[from] zip::result::ZipError),
 }
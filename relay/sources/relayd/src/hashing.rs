@@ -95,23 +95,58 @@ impl fmt::Display for HashType {
     }
 }
 
+/// Incremental hash computation, for data that does not fit in memory
+#[derive(Clone)]
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn input(&mut self, bytes: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.input(bytes),
+            Hasher::Sha512(hasher) => hasher.input(bytes),
+        }
+    }
+
+    /// Raw digest of the data
+    pub fn result(self) -> Vec<u8> {
+        match self {
+            Hasher::Sha256(hasher) => hasher.result().to_vec(),
+            Hasher::Sha512(hasher) => hasher.result().to_vec(),
+        }
+    }
+}
+
 impl HashType {
     pub fn hash(self, bytes: &[u8]) -> Hash {
-        let value = match self {
-            HashType::Sha256 => {
-                let mut hasher = Sha256::new();
-                hasher.input(bytes);
-                format!("{:x}", hasher.result())
-            }
-            HashType::Sha512 => {
-                let mut hasher = Sha512::new();
-                hasher.input(bytes);
-                format!("{:x}", hasher.result())
-            }
-        };
+        let mut hasher = self.hasher();
+        hasher.input(bytes);
         Hash {
             hash_type: self,
-            value,
+            value: hex::encode(hasher.result()),
+        }
+    }
+
+    pub fn hasher(self) -> Hasher {
+        match self {
+            HashType::Sha256 => Hasher::Sha256(Sha256::new()),
+            HashType::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    /// DER encoded `DigestInfo` preceding the digest in PKCS#1 v1.5 signatures
+    pub fn digest_info_prefix(self) -> &'static [u8] {
+        match self {
+            HashType::Sha256 => &[
+                0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x01, 0x05, 0x00, 0x04, 0x20,
+            ],
+            HashType::Sha512 => &[
+                0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+                0x03, 0x05, 0x00, 0x04, 0x40,
+            ],
         }
     }
 
@@ -187,6 +222,14 @@ mod tests {
 
         let sha512 = HashType::Sha512;
         assert_eq!(sha512.hash("test".as_bytes()).value, "ee26b0dd4af7e749aa1a8ee3c10ae9923f618980772e473f8819a5d4940e0db27ac185f8a0e1d5f84f88bc887fd67b143732c304cc5fa9ad8e6f57f50028a8ff");
+
+        let mut hasher = sha512.hasher();
+        hasher.input(b"te");
+        hasher.input(b"st");
+        assert_eq!(
+            hex::encode(hasher.result()),
+            sha512.hash("test".as_bytes()).value
+        );
     }
 
     #[test]
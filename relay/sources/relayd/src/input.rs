@@ -12,36 +12,81 @@ use openssl::{
 };
 use std::{
     ffi::OsStr,
-    fs::read,
+    fs::{metadata, read},
     io::{Cursor, Read},
     path::Path,
+    usize,
 };
 use tracing::debug;
 use zip::read::ZipArchive;
 
-pub fn read_compressed_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
+/// Reads and extracts a file, refusing files larger than given sizes
+pub fn read_compressed_file<P: AsRef<Path>>(
+    path: P,
+    max_compressed_size: usize,
+    max_uncompressed_size: usize,
+) -> Result<Vec<u8>, Error> {
     let path = path.as_ref();
+    let data = read_file_with_limit(path, max_compressed_size)?;
+    uncompress_with_limits(data, path, max_compressed_size, max_uncompressed_size)
+}
+
+/// Reads a file as is, refusing files larger than `max_compressed_size`
+pub fn read_file_with_limit<P: AsRef<Path>>(
+    path: P,
+    max_compressed_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let path = path.as_ref();
+
+    // Avoid reading huge files
+    if metadata(path)?.len() > max_compressed_size as u64 {
+        return Err(Error::LimitExceeded("compressed size", max_compressed_size));
+    }
 
     debug!("Reading {:#?} content", path);
-    let data = read(path)?;
+    Ok(read(path)?)
+}
+
+/// Extracts data based on the extension of the given file name
+pub fn uncompress<P: AsRef<Path>>(data: Vec<u8>, name: P) -> Result<Vec<u8>, Error> {
+    uncompress_with_limits(data, name, usize::MAX, usize::MAX)
+}
+
+/// Extracts data based on the extension of the given file name, refusing data larger
+/// than given sizes.
+///
+/// Extracted size is checked while extracting to avoid exhausting memory.
+pub fn uncompress_with_limits<P: AsRef<Path>>(
+    data: Vec<u8>,
+    name: P,
+    max_compressed_size: usize,
+    max_uncompressed_size: usize,
+) -> Result<Vec<u8>, Error> {
+    let path = name.as_ref();
+
+    if data.len() > max_compressed_size {
+        return Err(Error::LimitExceeded("compressed size", max_compressed_size));
+    }
 
     Ok(match path.extension().and_then(OsStr::to_str) {
         Some("gz") => {
             debug!("{:?} has .gz extension, extracting", path);
-            let mut gz = GzDecoder::new(data.as_slice());
-            let mut uncompressed_data = vec![];
-            gz.read_to_end(&mut uncompressed_data)?;
-            uncompressed_data
+            read_bounded(GzDecoder::new(data.as_slice()), max_uncompressed_size)?
         }
         Some("zip") => {
             debug!("{:?} has .zip extension, extracting", path);
             let mut zip = ZipArchive::new(Cursor::new(data))?;
             // Considering only the first file in the zip
             // There should be only one anyway
-            let mut first_file = zip.by_index(0)?;
-            let mut uncompressed_data: Vec<u8> = Vec::new();
-            let _ = first_file.read_to_end(&mut uncompressed_data);
-            uncompressed_data
+            let first_file = zip.by_index(0)?;
+            // Announced size can be wrong, it is only a shortcut
+            if first_file.size() > max_uncompressed_size as u64 {
+                return Err(Error::LimitExceeded(
+                    "uncompressed size",
+                    max_uncompressed_size,
+                ));
+            }
+            read_bounded(first_file, max_uncompressed_size)?
         }
         // Let's assume everything else is a text file
         _ => {
@@ -49,11 +94,29 @@ pub fn read_compressed_file<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, Error> {
                 "{:?} has no compressed file extension, no extraction needed",
                 path
             );
+            if data.len() > max_uncompressed_size {
+                return Err(Error::LimitExceeded(
+                    "uncompressed size",
+                    max_uncompressed_size,
+                ));
+            }
             data
         }
     })
 }
 
+/// Reads at most `limit` bytes, and fails if there is more data
+fn read_bounded<R: Read>(reader: R, limit: usize) -> Result<Vec<u8>, Error> {
+    let mut data = vec![];
+    let _ = reader
+        .take((limit as u64).saturating_add(1))
+        .read_to_end(&mut data)?;
+    if data.len() > limit {
+        return Err(Error::LimitExceeded("uncompressed size", limit));
+    }
+    Ok(data)
+}
+
 /// Parses an S/MIME message as an UTF-8 string and validates the signature with the given
 /// certificates.
 /// * `input` is the signed content we want to check
@@ -104,7 +167,7 @@ mod tests {
     fn it_reads_gzipped_files() {
         let reference = read("tests/files/gz/normal.log").unwrap();
         assert_eq!(
-            read_compressed_file("tests/files/gz/normal.log.gz").unwrap(),
+            read_compressed_file("tests/files/gz/normal.log.gz", 100_000, 100_000).unwrap(),
             reference
         );
     }
@@ -113,7 +176,7 @@ mod tests {
     fn it_reads_zipped_files() {
         let reference = read("tests/files/gz/normal.log").unwrap();
         assert_eq!(
-            read_compressed_file("tests/files/gz/normal.log.zip").unwrap(),
+            read_compressed_file("tests/files/gz/normal.log.zip", 100_000, 100_000).unwrap(),
             reference
         );
     }
@@ -122,11 +185,31 @@ mod tests {
     fn it_reads_plain_files() {
         let reference = read("tests/files/gz/normal.log").unwrap();
         assert_eq!(
-            read_compressed_file("tests/files/gz/normal.log").unwrap(),
+            read_compressed_file("tests/files/gz/normal.log", 100_000, 100_000).unwrap(),
             reference
         );
     }
 
+    #[test]
+    fn it_refuses_too_large_files() {
+        let size = read("tests/files/gz/normal.log").unwrap().len();
+        for file in &[
+            "tests/files/gz/normal.log",
+            "tests/files/gz/normal.log.gz",
+            "tests/files/gz/normal.log.zip",
+        ] {
+            assert!(read_compressed_file(file, 100_000, size).is_ok());
+            match read_compressed_file(file, 100_000, size - 1) {
+                Err(Error::LimitExceeded("uncompressed size", _)) => (),
+                res => panic!("unexpected result {:?} for {}", res, file),
+            }
+            match read_compressed_file(file, 10, size) {
+                Err(Error::LimitExceeded("compressed size", _)) => (),
+                res => panic!("unexpected result {:?} for {}", res, file),
+            }
+        }
+    }
+
     #[test]
     fn it_reads_signed_content() {
         // unix2dos normal.log
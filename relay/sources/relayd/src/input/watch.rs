@@ -4,6 +4,7 @@
 use crate::{
     configuration::main::{CatchupConfig, CleanupConfig, WatchedDirectory},
     processing::ReceivedFile,
+    stats::Queue,
     JobConfig,
 };
 use futures::{
@@ -65,22 +66,50 @@ pub fn watch(
     path: &WatchedDirectory,
     job_config: &Arc<JobConfig>,
     tx: &mpsc::Sender<ReceivedFile>,
+    queue: Queue,
 ) {
     info!("Starting file watcher on {:#?}", &path);
     let report_span = span!(Level::TRACE, "watcher");
     let _report_enter = report_span.enter();
-    tokio::spawn(list_files(
+    // Senders are dropped when watchers stop, which ends processing loops
+    tokio::spawn(job_config.until_shutdown(list_files(
         path.clone(),
         job_config.cfg.processing.reporting.catchup,
         tx.clone(),
-    ));
-    tokio::spawn(watch_files(path.clone(), tx.clone()));
+        job_config.clone(),
+        queue,
+    )));
+    tokio::spawn(job_config.until_shutdown(watch_files(
+        path.clone(),
+        tx.clone(),
+        job_config.clone(),
+        queue,
+    )));
+}
+
+/// Sends a file to the processing loop, keeping track of queue depth
+fn enqueue(
+    file: ReceivedFile,
+    tx: mpsc::Sender<ReceivedFile>,
+    job_config: Arc<JobConfig>,
+    queue: Queue,
+) -> impl Future<Item = (), Error = ()> {
+    // before sending, as the file can be dequeued right away
+    job_config.queues.enqueued(queue);
+    tx.send(file)
+        .map_err(move |e| {
+            job_config.queues.dequeued(queue);
+            warn!("send error: {}", e)
+        })
+        .map(|_| ())
 }
 
 fn list_files(
     path: WatchedDirectory,
     cfg: CatchupConfig,
     tx: mpsc::Sender<ReceivedFile>,
+    job_config: Arc<JobConfig>,
+    queue: Queue,
 ) -> impl Future<Item = (), Error = ()> {
     Interval::new(Instant::now(), cfg.frequency)
         .map_err(|e| warn!("interval error: {}", e))
@@ -88,6 +117,7 @@ fn list_files(
             debug!("listing {:?}", path);
 
             let tx = tx.clone();
+            let job_config = job_config.clone();
             let sys_time = SystemTime::now();
 
             read_dir(path.clone())
@@ -113,10 +143,7 @@ fn list_files(
                 .for_each(move |entry| {
                     let path = entry.path();
                     debug!("list: {:?}", path);
-                    tx.clone()
-                        .send(path)
-                        .map_err(|e| warn!("list error: {}", e))
-                        .map(|_| ())
+                    enqueue(path, tx.clone(), job_config.clone(), queue)
                 })
         })
 }
@@ -141,6 +168,8 @@ fn watch_stream<P: AsRef<Path>>(path: P) -> inotify::EventStream<Vec<u8>> {
 fn watch_files<P: AsRef<Path>>(
     path: P,
     tx: mpsc::Sender<ReceivedFile>,
+    job_config: Arc<JobConfig>,
+    queue: Queue,
 ) -> impl Future<Item = (), Error = ()> {
     let path_prefix = path.as_ref().to_path_buf();
     watch_stream(&path)
@@ -157,12 +186,7 @@ fn watch_files<P: AsRef<Path>>(
             debug!("inotify: {:?}", path.as_ref());
             full_path
         })
-        .for_each(move |entry| {
-            tx.clone()
-                .send(entry)
-                .map_err(|e| warn!("watch send error: {}", e))
-                .map(|_| ())
-        })
+        .for_each(move |entry| enqueue(entry, tx.clone(), job_config.clone(), queue))
 }
 
 #[cfg(test)]
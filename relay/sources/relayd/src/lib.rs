@@ -17,16 +17,25 @@ pub mod processing;
 pub mod stats;
 
 use crate::{
+    api::RemoteRunJobs,
     configuration::{
-        cli::CliConfiguration,
+        cli::{ArchiveQuery, CliConfiguration, ReplayOptions},
         logging::LogConfig,
         main::{Configuration, InventoryOutputSelect, OutputSelect, ReportingOutputSelect},
     },
     data::node::NodesList,
     error::Error,
-    output::database::{pg_pool, PgPool},
-    processing::{inventory, reporting},
-    stats::Stats,
+    output::{
+        archive,
+        circuit_breaker::{self, CircuitBreakers},
+        database::{pg_pool, PgPool},
+    },
+    processing::{
+        inventory,
+        replay::{replay, ReplaySummary},
+        reporting, shared_files,
+    },
+    stats::{QueueDepths, Stats},
 };
 use futures::{
     future::{lazy, Future},
@@ -36,14 +45,17 @@ use futures::{
 use reqwest::r#async::Client;
 use std::{
     fs::create_dir_all,
+    io,
     path::Path,
     process::exit,
     string::ToString,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
+    thread,
 };
 use structopt::clap::crate_version;
+use tokio::sync::watch;
 use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 use tracing_log::LogTracer;
 use tracing_subscriber::{
     filter::EnvFilter,
@@ -70,6 +82,8 @@ use tracing_subscriber::{
 pub enum ExitStatus {
     /// Expected shutdown
     Shutdown,
+    /// Shutdown requested but processing could not complete in time
+    ForcedShutdown,
     /// Unexpected crash (=panic in tokio)
     Crash,
     /// Could not start properly due to an error
@@ -84,6 +98,7 @@ impl ExitStatus {
             ExitStatus::Crash => 1,
             ExitStatus::StartError(Error::ConfigurationParsing(_)) => 2,
             ExitStatus::StartError(_) => 3,
+            ExitStatus::ForcedShutdown => 4,
         }
     }
 }
@@ -114,6 +129,26 @@ pub fn check_configuration(cfg_dir: &Path) -> Result<(), Error> {
     Ok(())
 }
 
+/// Prints the archived runlogs matching the query to stdout
+pub fn query_archive(cfg_dir: &Path, query: &ArchiveQuery) -> Result<usize, Error> {
+    let paths = if query.paths.is_empty() {
+        vec![Configuration::new(&cfg_dir)?.output.archive.directory]
+    } else {
+        query.paths.clone()
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    archive::query(&paths, query, &mut out)
+}
+
+/// Inserts runlogs into the database, printing progress to stdout
+pub fn replay_runlogs(cfg_dir: &Path, options: &ReplayOptions) -> Result<ReplaySummary, Error> {
+    let cfg = Configuration::new(&cfg_dir)?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    replay(&cfg, options, &mut out)
+}
+
 #[allow(clippy::cognitive_complexity)]
 pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(), Error> {
     // Start by setting log config
@@ -135,26 +170,39 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
 
     debug!("Setup signal handlers");
 
-    // SIGINT or SIGTERM: immediate shutdown
-    // TODO: graceful shutdown
+    // SIGINT or SIGTERM: graceful shutdown
+    //
+    // Watchers and API stop accepting new work, already started processing
+    // is allowed to complete until `shutdown_timeout` is reached.
+    let job_config_shutdown = job_config.clone();
     let shutdown = Signal::new(SIGINT)
         .flatten_stream()
         .select(Signal::new(SIGTERM).flatten_stream())
         .into_future()
-        .map(|_sig| {
+        .map(move |_sig| {
             info!("Signal received: shutdown requested");
-            exit(ExitStatus::Shutdown.code());
+            job_config_shutdown.shutdown();
+
+            // Exit anyway once the deadline is reached
+            let timeout = job_config_shutdown.cfg.general.shutdown_timeout;
+            thread::spawn(move || {
+                thread::sleep(timeout);
+                warn!("Could not complete shutdown in {:?}, exiting", timeout);
+                exit(ExitStatus::ForcedShutdown.code());
+            });
         })
         .map_err(|e| error!("signal error {}", e.0));
 
     // SIGHUP: reload logging configuration + nodes list
     let job_config_reload = job_config.clone();
 
-    let reload = Signal::new(SIGHUP)
-        .flatten_stream()
-        .map_err(|e| e.into())
-        .for_each(move |_signal| job_config_reload.reload())
-        .map_err(|e| error!("signal error {}", e));
+    let reload = job_config.until_shutdown(
+        Signal::new(SIGHUP)
+            .flatten_stream()
+            .map_err(|e| e.into())
+            .for_each(move |_signal| job_config_reload.reload())
+            .map_err(|e| error!("signal error {}", e)),
+    );
 
     // ---- Start server ----
 
@@ -168,39 +216,63 @@ pub fn start(cli_cfg: CliConfiguration, reload_handle: LogHandle) -> Result<(),
         .panic_handler(|_| exit(ExitStatus::Crash.code()))
         .build()?;
 
+    let job_config_run = job_config.clone();
     // don't use block_on_all as it panics on main future panic but not others
     runtime.spawn(lazy(move || {
+        let job_config = job_config_run;
         tokio::spawn(reload);
         tokio::spawn(shutdown);
+        tokio::spawn(job_config.until_shutdown(circuit_breaker::probe(job_config.clone())));
 
         let (tx_stats, rx_stats) = mpsc::channel(1_024);
 
-        tokio::spawn(Stats::receiver(stats.clone(), rx_stats));
+        // Ends once all senders are dropped, i.e. when all processing is over
+        let stats_final = stats.clone();
+        tokio::spawn(Stats::receiver(stats.clone(), rx_stats).map(move |_| {
+            info!(
+                "Final statistics: {:?}",
+                *stats_final.read().expect("could not read stats")
+            )
+        }));
+        let batch = if job_config.cfg.processing.reporting.is_enabled() {
+            reporting::start(&job_config, &tx_stats)
+        } else {
+            info!("Skipping reporting as it is disabled");
+            None
+        };
+
         tokio::spawn(api::run(
             job_config.cfg.general.listen,
             job_config.clone(),
+            batch,
             stats.clone(),
+            tx_stats.clone(),
         ));
 
-        if job_config.cfg.processing.reporting.output.is_enabled() {
-            reporting::start(&job_config, &tx_stats);
-        } else {
-            info!("Skipping reporting as it is disabled");
-        }
-
         if job_config.cfg.processing.inventory.output.is_enabled() {
             inventory::start(&job_config, &tx_stats);
         } else {
             info!("Skipping inventory as it is disabled");
         }
 
+        shared_files::start(&job_config, &tx_stats);
+        tokio::spawn(
+            job_config.until_shutdown(job_config.remote_run_jobs.clone().cleanup_expired()),
+        );
+
         info!("Server started");
         Ok(())
     }));
 
     // waits for completion of all futures
     runtime.shutdown_on_idle().wait().expect("shutdown failed");
-    panic!("Server halted unexpectedly");
+
+    if job_config.is_shutting_down() {
+        info!("Shutdown complete");
+        Ok(())
+    } else {
+        panic!("Server halted unexpectedly");
+    }
 }
 
 pub struct JobConfig {
@@ -209,7 +281,15 @@ pub struct JobConfig {
     pub nodes: RwLock<NodesList>,
     pub pool: Option<PgPool>,
     pub client: Client,
+    pub queues: QueueDepths,
+    /// Availability of outputs
+    pub breakers: CircuitBreakers,
+    pub remote_run_jobs: Arc<RemoteRunJobs>,
+    /// Serializes storage and removal of shared files
+    pub shared_files_lock: Arc<Mutex<()>>,
     handle: LogHandle,
+    shutdown_tx: Mutex<watch::Sender<bool>>,
+    shutdown_rx: watch::Receiver<bool>,
 }
 
 impl JobConfig {
@@ -228,13 +308,26 @@ impl JobConfig {
                     .join("accepted-nodes-updates"),
             )?;
             create_dir_all(cfg.processing.inventory.directory.join("failed"))?;
+            create_dir_all(cfg.processing.inventory.directory.join("retry"))?;
         }
-        if cfg.processing.reporting.output != ReportingOutputSelect::Disabled {
+        if cfg.processing.reporting.is_enabled() {
             create_dir_all(cfg.processing.reporting.directory.join("incoming"))?;
             create_dir_all(cfg.processing.reporting.directory.join("failed"))?;
+            create_dir_all(cfg.processing.reporting.directory.join("retry"))?;
+        }
+        if cfg
+            .processing
+            .reporting
+            .uses(ReportingOutputSelect::Archive)
+        {
+            create_dir_all(&cfg.output.archive.directory)?;
         }
 
-        let pool = if cfg.processing.reporting.output == ReportingOutputSelect::Database {
+        let pool = if cfg
+            .processing
+            .reporting
+            .uses(ReportingOutputSelect::Database)
+        {
             Some(pg_pool(&cfg.output.database)?)
         } else {
             None
@@ -250,6 +343,10 @@ impl JobConfig {
             Some(&cfg.general.nodes_certs_file),
         )?);
 
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let breakers = CircuitBreakers::new(cfg.output.circuit_breaker);
+        let remote_run_jobs = Arc::new(RemoteRunJobs::new(&cfg.remote_run)?);
+
         Ok(Arc::new(Self {
             cli_cfg,
             cfg,
@@ -257,6 +354,12 @@ impl JobConfig {
             pool,
             handle,
             client,
+            queues: QueueDepths::default(),
+            breakers,
+            remote_run_jobs,
+            shared_files_lock: Arc::new(Mutex::new(())),
+            shutdown_tx: Mutex::new(shutdown_tx),
+            shutdown_rx,
         }))
     }
 
@@ -287,4 +390,43 @@ impl JobConfig {
                 e
             })
     }
+
+    /// Notify all running tasks that they should stop
+    pub fn shutdown(&self) {
+        if self
+            .shutdown_tx
+            .lock()
+            .expect("could not lock shutdown sender")
+            .broadcast(true)
+            .is_err()
+        {
+            warn!("no task is listening to shutdown requests");
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown_rx.get_ref()
+    }
+
+    /// Resolves once a shutdown has been requested
+    pub fn shutdown_requested(&self) -> impl Future<Item = (), Error = ()> + Send {
+        self.shutdown_rx
+            .clone()
+            .skip_while(|shutdown| Ok(!*shutdown))
+            .into_future()
+            // The sender is never dropped before receivers, but if it happens
+            // there is nothing left to wait for.
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+
+    /// Runs the given task until it completes or a shutdown is requested
+    pub fn until_shutdown<F>(&self, task: F) -> impl Future<Item = (), Error = ()> + Send
+    where
+        F: Future<Item = (), Error = ()> + Send,
+    {
+        task.select(self.shutdown_requested())
+            .map(|_| ())
+            .map_err(|_| ())
+    }
 }
@@ -1,5 +1,8 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
+pub mod archive;
+pub mod batch;
+pub mod circuit_breaker;
 pub mod database;
 pub mod upstream;
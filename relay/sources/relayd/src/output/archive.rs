@@ -0,0 +1,278 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    configuration::{cli::ArchiveQuery, main::ArchiveConfig},
+    data::RunLog,
+    error::Error,
+};
+use chrono::{NaiveDate, Utc};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+use futures::{
+    future::{poll_fn, Future},
+    Stream,
+};
+use std::{
+    fs::{create_dir_all, read_dir, remove_file, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tokio::timer::Interval;
+use tokio_threadpool::blocking;
+use tracing::{debug, error, info, warn};
+
+const ARCHIVE_PREFIX: &str = "runlogs-";
+const ARCHIVE_EXTENSION: &str = ".ndjson.gz";
+
+/// Archive file containing the runlogs received on the given day
+pub fn archive_file(directory: &Path, day: NaiveDate) -> PathBuf {
+    directory.join(format!(
+        "{}{}{}",
+        ARCHIVE_PREFIX,
+        day.format("%Y-%m-%d"),
+        ARCHIVE_EXTENSION
+    ))
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.starts_with(ARCHIVE_PREFIX) && n.ends_with(ARCHIVE_EXTENSION))
+        .unwrap_or(false)
+}
+
+/// Archive files in the directory, oldest first
+pub fn archive_files(directory: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut files = vec![];
+    for entry in read_dir(directory)? {
+        let path = entry?.path();
+        if is_archive(&path) {
+            files.push(path);
+        }
+    }
+    // Dates in names sort chronologically
+    files.sort();
+    Ok(files)
+}
+
+/// Appends the runlog to the archive of the current day, as a JSON line
+///
+/// Each runlog is written as a separate gzip member, in a single append,
+/// so that concurrent writes do not interleave and an interrupted write
+/// only affects the last runlog.
+pub fn archive_runlog(directory: &Path, runlog: &RunLog) -> Result<(), Error> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    serde_json::to_writer(&mut encoder, runlog)?;
+    encoder.write_all(b"\n")?;
+    let member = encoder.finish()?;
+
+    let path = archive_file(directory, Utc::now().date().naive_utc());
+    debug!("archiving {} runlog into {:?}", runlog.info, path);
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(&member))
+        // Probably a full disk, it may succeed later
+        .map_err(|e| {
+            error!("could not write {:?}: {}", path, e);
+            Error::OutputUnavailable("archive")
+        })
+}
+
+/// Reads the runlogs contained in an archive file
+pub fn read_archive(path: &Path) -> Result<impl Iterator<Item = Result<RunLog, Error>>, Error> {
+    let reader = BufReader::new(MultiGzDecoder::new(File::open(path)?));
+    Ok(reader
+        .lines()
+        .map(|line| -> Result<RunLog, Error> { Ok(serde_json::from_str(&line?)?) }))
+}
+
+/// Writes the archived runlogs matching the query, in the order of the archives
+///
+/// Returns the number of matching runlogs.
+pub fn query<W: Write>(
+    paths: &[PathBuf],
+    query: &ArchiveQuery,
+    out: &mut W,
+) -> Result<usize, Error> {
+    let mut count = 0;
+    for path in paths {
+        let files = if path.is_dir() {
+            archive_files(path)?
+        } else {
+            vec![path.clone()]
+        };
+        for file in files {
+            for runlog in read_archive(&file)? {
+                let runlog = runlog?;
+                if !query.filter.matches(&runlog.info) {
+                    continue;
+                }
+                if query.text {
+                    write!(out, "{}", runlog)?;
+                } else {
+                    serde_json::to_writer(&mut *out, &runlog)?;
+                    writeln!(out)?;
+                }
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Checks the archive directory is usable
+pub fn ping(directory: &Path) -> Result<(), Error> {
+    create_dir_all(directory)?;
+    if directory.metadata()?.permissions().readonly() {
+        return Err(Error::OutputUnavailable("archive"));
+    }
+    Ok(())
+}
+
+/// Removes the oldest archives until their total size is below the limit
+///
+/// The archive of the current day is never removed.
+pub fn enforce_max_size(directory: &Path, max_size: u64) -> Result<(), Error> {
+    let files = archive_files(directory)?;
+    let current = archive_file(directory, Utc::now().date().naive_utc());
+
+    let mut sizes = Vec::with_capacity(files.len());
+    for file in &files {
+        sizes.push(file.metadata()?.len());
+    }
+    let mut total: u64 = sizes.iter().sum();
+
+    for (file, size) in files.into_iter().zip(sizes) {
+        if total <= max_size {
+            break;
+        }
+        if file == current {
+            continue;
+        }
+        info!("removing {:?} as archives exceed {} bytes", file, max_size);
+        remove_file(&file)?;
+        total -= size;
+    }
+    Ok(())
+}
+
+/// Periodically enforces the size limit of the archives, if any
+///
+/// Age based retention is handled by the generic cleanup.
+pub fn cleanup(cfg: ArchiveConfig) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), cfg.cleanup.frequency)
+        .map_err(|e| warn!("interval error: {}", e))
+        .for_each(move |_instant| {
+            let directory = cfg.directory.clone();
+            let max_size = cfg.max_size;
+            poll_fn(move || {
+                blocking(|| match max_size {
+                    Some(max_size) => enforce_max_size(&directory, max_size),
+                    None => Ok(()),
+                })
+            })
+            .then(|res| res.expect("the thread pool shut down"))
+            .map_err(|e| warn!("archive cleanup error: {}", e))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::cli::RunlogFilter;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_archives_runlogs() {
+        let dir = tempdir().unwrap();
+        let runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        let other_runlog = RunLog::new(
+            "tests/files/runlogs/2017-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+
+        archive_runlog(dir.path(), &runlog).unwrap();
+        archive_runlog(dir.path(), &other_runlog).unwrap();
+
+        let files = archive_files(dir.path()).unwrap();
+        assert_eq!(
+            files,
+            vec![archive_file(dir.path(), Utc::now().date().naive_utc())]
+        );
+        let archived: Vec<RunLog> = read_archive(&files[0])
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(archived, vec![runlog, other_runlog]);
+    }
+
+    #[test]
+    fn it_queries_archives() {
+        let dir = tempdir().unwrap();
+        let runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        let other_runlog = RunLog::new(
+            "tests/files/runlogs/2017-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        archive_runlog(dir.path(), &runlog).unwrap();
+        archive_runlog(dir.path(), &other_runlog).unwrap();
+        let paths = vec![dir.path().to_path_buf()];
+
+        let mut out = vec![];
+        let all = ArchiveQuery::default();
+        assert_eq!(query(&paths, &all, &mut out).unwrap(), 2);
+        assert_eq!(String::from_utf8(out).unwrap().lines().count(), 2);
+
+        let mut out = vec![];
+        let recent = ArchiveQuery {
+            filter: RunlogFilter {
+                from: Some("2018-01-01T00:00:00+00:00".parse().unwrap()),
+                ..Default::default()
+            },
+            text: true,
+            ..Default::default()
+        };
+        assert_eq!(query(&paths, &recent, &mut out).unwrap(), 1);
+        assert_eq!(String::from_utf8(out).unwrap(), runlog.to_string());
+
+        let unknown = ArchiveQuery {
+            filter: RunlogFilter {
+                node_id: Some("unknown".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(query(&paths, &unknown, &mut vec![]).unwrap(), 0);
+    }
+
+    #[test]
+    fn it_removes_oldest_archives() {
+        let dir = tempdir().unwrap();
+        let old = archive_file(dir.path(), NaiveDate::from_ymd(2020, 1, 1));
+        let recent = archive_file(dir.path(), NaiveDate::from_ymd(2020, 1, 2));
+        let current = archive_file(dir.path(), Utc::now().date().naive_utc());
+        fs::write(&old, vec![0; 100]).unwrap();
+        fs::write(&recent, vec![0; 100]).unwrap();
+        fs::write(&current, vec![0; 100]).unwrap();
+        fs::write(dir.path().join("other"), vec![0; 100]).unwrap();
+
+        enforce_max_size(dir.path(), 250).unwrap();
+        assert_eq!(
+            archive_files(dir.path()).unwrap(),
+            vec![recent, current.clone()]
+        );
+
+        enforce_max_size(dir.path(), 0).unwrap();
+        assert_eq!(archive_files(dir.path()).unwrap(), vec![current]);
+    }
+}
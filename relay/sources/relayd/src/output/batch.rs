@@ -0,0 +1,149 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    data::RunLog,
+    error::Error,
+    output::database::{insert_runlog, insert_runlogs, InsertionBehavior, RunlogInsertion},
+    processing::OutputError,
+    JobConfig,
+};
+use futures::{
+    future::{ok, poll_fn, Either, Future},
+    sync::oneshot,
+    Stream,
+};
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+};
+use tokio::timer::Interval;
+use tokio_threadpool::blocking;
+use tracing::{debug, warn};
+
+struct PendingRunlog {
+    runlog: RunLog,
+    result: oneshot::Sender<Result<RunlogInsertion, Error>>,
+}
+
+/// Accumulates runlogs to insert them into the database together
+pub struct RunlogBatch {
+    job_config: Arc<JobConfig>,
+    pending: Mutex<Vec<PendingRunlog>>,
+}
+
+impl RunlogBatch {
+    pub fn new(job_config: Arc<JobConfig>) -> Arc<Self> {
+        Arc::new(Self {
+            job_config,
+            pending: Mutex::new(vec![]),
+        })
+    }
+
+    /// Resolves once the runlog is inserted
+    pub fn insert(
+        self: Arc<Self>,
+        runlog: RunLog,
+    ) -> impl Future<Item = RunlogInsertion, Error = Error> {
+        let (tx, rx) = oneshot::channel();
+        let full = {
+            let mut pending = self.pending.lock().expect("could not lock pending runlogs");
+            pending.push(PendingRunlog { runlog, result: tx });
+            pending.len() >= self.job_config.cfg.output.database.batch_size
+        };
+        // Periodic flush stops on shutdown
+        if full || self.job_config.is_shutting_down() {
+            tokio::spawn(self.flush());
+        }
+        rx.then(|res| res.expect("pending runlog was dropped"))
+    }
+
+    /// Periodically inserts pending runlogs, until shutdown
+    pub fn run(self: Arc<Self>) -> impl Future<Item = (), Error = ()> {
+        let batch = self.clone();
+        self.job_config
+            .until_shutdown(
+                Interval::new_interval(self.job_config.cfg.output.database.batch_window)
+                    .map_err(|e| warn!("interval error: {}", e))
+                    .for_each(move |_instant| batch.clone().flush()),
+            )
+            .then(move |_| self.flush())
+    }
+
+    fn flush(self: Arc<Self>) -> impl Future<Item = (), Error = ()> {
+        let batch = mem::take(&mut *self.pending.lock().expect("could not lock pending runlogs"));
+        if batch.is_empty() {
+            return Either::A(ok::<(), ()>(()));
+        }
+        let mut batch = Some(batch);
+        Either::B(
+            poll_fn(move || {
+                blocking(|| self.insert_batch(batch.take().expect("batch already inserted")))
+            })
+            .map_err(|_| panic!("the thread pool shut down")),
+        )
+    }
+
+    fn insert_batch(&self, batch: Vec<PendingRunlog>) {
+        let pool = self
+            .job_config
+            .pool
+            .as_ref()
+            .expect("output uses database but no config provided");
+        debug!("inserting {} runlogs", batch.len());
+
+        let (runlogs, senders): (Vec<_>, Vec<_>) =
+            batch.into_iter().map(|p| (p.runlog, p.result)).unzip();
+
+        let breaker = &self.job_config.breakers.database;
+        match insert_runlogs(pool, &runlogs, InsertionBehavior::SkipDuplicate) {
+            Ok(results) => {
+                breaker.success();
+                for (sender, result) in senders.into_iter().zip(results) {
+                    let _ = sender.send(Ok(result));
+                }
+            }
+            // The whole batch can be retried later
+            Err(e) if OutputError::from(&e) == OutputError::Transient => {
+                warn!("could not insert {} runlogs: {}", runlogs.len(), e);
+                // A single failure for the whole batch
+                breaker.failure();
+                let e = Arc::new(e);
+                for sender in senders {
+                    let _ = sender.send(Err(Error::BatchInsertion(e.clone())));
+                }
+            }
+            // Find the culprit
+            Err(e) => {
+                warn!(
+                    "could not insert {} runlogs: {}, inserting them separately",
+                    runlogs.len(),
+                    e
+                );
+                let mut unavailable: Option<Arc<Error>> = None;
+                for (sender, runlog) in senders.into_iter().zip(runlogs) {
+                    let res = match unavailable {
+                        // No need to try the remaining runlogs
+                        Some(ref e) => Err(Error::BatchInsertion(e.clone())),
+                        None => {
+                            match insert_runlog(pool, &runlog, InsertionBehavior::SkipDuplicate) {
+                                Err(e) if OutputError::from(&e) == OutputError::Transient => {
+                                    warn!("could not insert {} runlog: {}", runlog.info, e);
+                                    let e = Arc::new(e);
+                                    unavailable = Some(e.clone());
+                                    Err(Error::BatchInsertion(e))
+                                }
+                                res => res,
+                            }
+                        }
+                    };
+                    let _ = sender.send(res);
+                }
+                match unavailable {
+                    Some(_) => breaker.failure(),
+                    None => breaker.success(),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,228 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    configuration::main::CircuitBreakerConfig,
+    error::Error,
+    output::{archive, database, upstream},
+    processing::OutputError,
+    stats::Output,
+    JobConfig,
+};
+use chrono::{DateTime, Utc};
+use futures::{
+    future::{ok, poll_fn, Either, Future},
+    Stream,
+};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use tokio::timer::Interval;
+use tokio_threadpool::blocking;
+use tracing::{debug, info, warn};
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum BreakerState {
+    /// Files are sent to the output
+    Closed,
+    /// Output is considered unavailable, files stay in place until
+    /// a probe succeeds
+    Open,
+}
+
+impl Default for BreakerState {
+    fn default() -> Self {
+        BreakerState::Closed
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct BreakerStatus {
+    pub state: BreakerState,
+    pub consecutive_failures: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub open_since: Option<DateTime<Utc>>,
+}
+
+/// Tracks availability of an output, shared by all processing tasks using it
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: &'static str,
+    failure_threshold: u32,
+    status: Mutex<BreakerStatus>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &'static str, failure_threshold: u32) -> Self {
+        Self {
+            name,
+            failure_threshold,
+            status: Mutex::new(BreakerStatus::default()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn status(&self) -> BreakerStatus {
+        *self.status.lock().expect("could not lock breaker status")
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.status().state == BreakerState::Open
+    }
+
+    pub fn success(&self) {
+        let mut status = self.status.lock().expect("could not lock breaker status");
+        if status.state == BreakerState::Open {
+            info!(
+                "{} output is available again, resuming processing",
+                self.name
+            );
+        }
+        *status = BreakerStatus::default();
+    }
+
+    pub fn failure(&self) {
+        let mut status = self.status.lock().expect("could not lock breaker status");
+        status.consecutive_failures = status.consecutive_failures.saturating_add(1);
+        if status.state == BreakerState::Closed
+            && status.consecutive_failures >= self.failure_threshold
+        {
+            warn!(
+                "{} output failed {} times in a row, pausing processing",
+                self.name, status.consecutive_failures
+            );
+            status.state = BreakerState::Open;
+            status.open_since = Some(Utc::now());
+        }
+    }
+
+    /// Only transient errors indicate an unavailable output
+    pub fn record<T>(&self, result: &Result<T, Error>) {
+        match result {
+            Ok(_) => self.success(),
+            Err(e) => match OutputError::from(e) {
+                OutputError::Transient => self.failure(),
+                OutputError::Permanent => (),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CircuitBreakers {
+    pub database: CircuitBreaker,
+    pub upstream: CircuitBreaker,
+    pub archive: CircuitBreaker,
+}
+
+impl CircuitBreakers {
+    pub fn new(cfg: CircuitBreakerConfig) -> Self {
+        Self {
+            database: CircuitBreaker::new("database", cfg.failure_threshold),
+            upstream: CircuitBreaker::new("upstream", cfg.failure_threshold),
+            archive: CircuitBreaker::new("archive", cfg.failure_threshold),
+        }
+    }
+
+    pub fn get(&self, output: Output) -> &CircuitBreaker {
+        match output {
+            Output::ReportDatabase => &self.database,
+            Output::ReportUpstream | Output::InventoryUpstream => &self.upstream,
+            Output::ReportArchive => &self.archive,
+        }
+    }
+}
+
+/// Periodically checks if unavailable outputs are back
+pub fn probe(job_config: Arc<JobConfig>) -> impl Future<Item = (), Error = ()> {
+    Interval::new_interval(job_config.cfg.output.circuit_breaker.probe_interval)
+        .map_err(|e| warn!("interval error: {}", e))
+        .for_each(move |_instant| {
+            probe_database(job_config.clone())
+                .join3(
+                    probe_upstream(job_config.clone()),
+                    probe_archive(job_config.clone()),
+                )
+                .map(|_| ())
+        })
+}
+
+fn probe_database(job_config: Arc<JobConfig>) -> impl Future<Item = (), Error = ()> {
+    let pool = match job_config.pool.clone() {
+        Some(pool) if job_config.breakers.database.is_open() => pool,
+        _ => return Either::A(ok::<(), ()>(())),
+    };
+    debug!("probing database output");
+    Either::B(
+        poll_fn(move || blocking(|| database::ping(&pool)))
+            .then(|res| res.expect("the thread pool shut down"))
+            .then(move |res| {
+                probed(&job_config.breakers.database, res);
+                Ok(())
+            }),
+    )
+}
+
+fn probe_upstream(job_config: Arc<JobConfig>) -> impl Future<Item = (), Error = ()> {
+    if !job_config.breakers.upstream.is_open() {
+        return Either::A(ok::<(), ()>(()));
+    }
+    debug!("probing upstream output");
+    Either::B(upstream::ping(job_config.clone()).then(move |res| {
+        probed(&job_config.breakers.upstream, res);
+        Ok(())
+    }))
+}
+
+fn probe_archive(job_config: Arc<JobConfig>) -> impl Future<Item = (), Error = ()> {
+    if !job_config.breakers.archive.is_open() {
+        return Either::A(ok::<(), ()>(()));
+    }
+    debug!("probing archive output");
+    let directory = job_config.cfg.output.archive.directory.clone();
+    Either::B(
+        poll_fn(move || blocking(|| archive::ping(&directory)))
+            .then(|res| res.expect("the thread pool shut down"))
+            .then(move |res| {
+                probed(&job_config.breakers.archive, res);
+                Ok(())
+            }),
+    )
+}
+
+fn probed(breaker: &CircuitBreaker, result: Result<(), Error>) {
+    match result {
+        Ok(()) => breaker.success(),
+        Err(e) => debug!("{} output is still unavailable: {}", breaker.name, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::new("test", 3);
+
+        breaker.failure();
+        breaker.failure();
+        breaker.record::<()>(&Err(Error::EmptyRunlog));
+        breaker.success();
+        breaker.failure();
+        breaker.failure();
+        assert!(!breaker.is_open());
+        assert_eq!(breaker.status().consecutive_failures, 2);
+
+        breaker.failure();
+        assert!(breaker.is_open());
+        assert!(breaker.status().open_since.is_some());
+
+        breaker.record(&Ok(()));
+        assert_eq!(breaker.status(), BreakerStatus::default());
+    }
+}
@@ -12,8 +12,15 @@ use diesel::{
     prelude::*,
     r2d2::{ConnectionManager, Pool},
 };
+use std::{collections::HashSet, slice};
 use tracing::{debug, error, span, trace, Level};
 
+/// PostgreSQL accepts at most 65535 parameters by query,
+/// and a report has 11 columns.
+const MAX_REPORTS_PER_INSERT: usize = 4096;
+/// A run has 3 columns
+const MAX_RUNLOGS_PER_INSERT: usize = 16384;
+
 pub mod schema {
     table! {
         use diesel::sql_types::*;
@@ -34,6 +41,18 @@ pub mod schema {
             serial -> Integer,
         }
     }
+
+    table! {
+        use diesel::sql_types::*;
+
+        // Needs to be kept in sync with the database schema
+        relaydrunlogs (nodeid, executiontimestamp) {
+            nodeid -> Text,
+            executiontimestamp -> Timestamptz,
+            digest -> Nullable<Text>,
+            insertiondate -> Timestamptz,
+        }
+    }
 }
 
 pub type PgPool = Pool<ConnectionManager<PgConnection>>;
@@ -76,58 +95,86 @@ pub fn insert_runlog(
     runlog: &RunLog,
     behavior: InsertionBehavior,
 ) -> Result<RunlogInsertion, Error> {
-    use self::schema::ruddersysevents::dsl::*;
+    Ok(insert_runlogs(pool, slice::from_ref(runlog), behavior)?[0])
+}
+
+/// Inserts runlogs in a single transaction, using multi-row inserts
+///
+/// Runs are identified by their node and execution timestamp, stored along with the
+/// reports in the same transaction, so that a runlog is inserted exactly once.
+/// A digest of the runlog content is stored with them.
+///
+/// Returns the result for each runlog, in the same order.
+pub fn insert_runlogs(
+    pool: &PgPool,
+    runlogs: &[RunLog],
+    behavior: InsertionBehavior,
+) -> Result<Vec<RunlogInsertion>, Error> {
+    use self::schema::{relaydrunlogs, ruddersysevents};
     let report_span = span!(Level::TRACE, "database");
     let _report_enter = report_span.enter();
 
-    let connection = &*pool.get()?;
+    if runlogs.is_empty() {
+        return Ok(vec![]);
+    }
 
-    let first_report = runlog
-        .reports
-        .first()
-        .expect("a runlog should never be empty");
+    let digests: Vec<String> = runlogs.iter().map(RunLog::digest).collect();
+    let connection = &*pool.get()?;
 
-    trace!(
-        "Checking if first report {} is in the database",
-        first_report
-    );
     connection.transaction::<_, Error, _>(|| {
-        let new_runlog = ruddersysevents
-            .filter(
-                component
-                    .eq(&first_report.component)
-                    .and(nodeid.eq(&first_report.node_id))
-                    .and(keyvalue.eq(&first_report.key_value))
-                    .and(eventtype.eq(&first_report.event_type))
-                    .and(msg.eq(&first_report.msg))
-                    .and(policy.eq(&first_report.policy))
-                    .and(executiontimestamp.eq(&first_report.start_datetime))
-                    .and(executiondate.eq(&first_report.execution_datetime))
-                    .and(serial.eq(&first_report.serial))
-                    .and(ruleid.eq(&first_report.rule_id))
-                    .and(directiveid.eq(&first_report.directive_id)),
-            )
-            .limit(1)
-            .load::<QueryableReport>(connection)?
-            .is_empty();
+        // Digests of the runs which were not already present
+        let mut inserted: HashSet<String> = HashSet::new();
+        for (runlog_chunk, digest_chunk) in runlogs
+            .chunks(MAX_RUNLOGS_PER_INSERT)
+            .zip(digests.chunks(MAX_RUNLOGS_PER_INSERT))
+        {
+            let values: Vec<_> = runlog_chunk
+                .iter()
+                .zip(digest_chunk)
+                .map(|(runlog, digest)| {
+                    (
+                        relaydrunlogs::nodeid.eq(&runlog.info.node_id),
+                        relaydrunlogs::executiontimestamp.eq(runlog.info.timestamp),
+                        relaydrunlogs::digest.eq(digest),
+                    )
+                })
+                .collect();
+            // Only one row is inserted for runs present several times in the batch
+            inserted.extend(
+                insert_into(relaydrunlogs::table)
+                    .values(&values)
+                    .on_conflict_do_nothing()
+                    .returning(relaydrunlogs::digest)
+                    .get_results::<Option<String>>(connection)?
+                    .into_iter()
+                    .flatten(),
+            );
+        }
+
+        let mut results = Vec::with_capacity(runlogs.len());
+        let mut reports = vec![];
+        for (runlog, digest) in runlogs.iter().zip(&digests) {
+            // Removing the digest also skips duplicates inside the batch
+            if inserted.remove(digest) || behavior == InsertionBehavior::AllowDuplicate {
+                trace!("Inserting runlog {:#?}", runlog);
+                reports.extend(runlog.reports.iter().cloned());
+                results.push(RunlogInsertion::Inserted);
+            } else {
+                error!(
+                    "The {} runlog was already there, skipping insertion",
+                    runlog.info
+                );
+                debug!("The digest of the skipped runlog is: {}", digest);
+                results.push(RunlogInsertion::AlreadyThere);
+            }
+        }
 
-        if behavior == InsertionBehavior::AllowDuplicate || new_runlog {
-            trace!("Inserting runlog {:#?}", runlog);
-            insert_into(ruddersysevents)
-                .values(&runlog.reports)
+        for chunk in reports.chunks(MAX_REPORTS_PER_INSERT) {
+            insert_into(ruddersysevents::table)
+                .values(chunk)
                 .execute(connection)?;
-            Ok(RunlogInsertion::Inserted)
-        } else {
-            error!(
-                "The {} runlog was already there, skipping insertion",
-                runlog.info
-            );
-            debug!(
-                "The report that was already present in database is: {}",
-                first_report
-            );
-            Ok(RunlogInsertion::AlreadyThere)
         }
+        Ok(results)
     })
 }
 
@@ -135,8 +182,9 @@ pub fn insert_runlog(
 mod tests {
     use super::*;
     use crate::{
-        configuration::Secret, data::report::QueryableReport,
-        output::database::schema::ruddersysevents::dsl::*,
+        configuration::Secret,
+        data::report::QueryableReport,
+        output::database::schema::{relaydrunlogs, ruddersysevents::dsl::*},
     };
     use diesel;
 
@@ -145,6 +193,7 @@ mod tests {
             url: "postgres://rudderreports:@127.0.0.1/rudder".to_string(),
             password: Secret::new("PASSWORD".to_string()),
             max_pool_size: 5,
+            ..Default::default()
         };
         pg_pool(&db_config).unwrap()
     }
@@ -155,6 +204,7 @@ mod tests {
         let db = &*pool.get().unwrap();
 
         diesel::delete(ruddersysevents).execute(db).unwrap();
+        diesel::delete(relaydrunlogs::table).execute(db).unwrap();
         let results = ruddersysevents
             .limit(1)
             .load::<QueryableReport>(db)
@@ -191,5 +241,70 @@ mod tests {
             .load::<QueryableReport>(db)
             .unwrap();
         assert_eq!(results.len(), 71);
+
+        // Test inserting another runlog for the same run
+
+        let partial = RunLog {
+            info: runlog.info.clone(),
+            reports: runlog.reports[..1].to_vec(),
+        };
+        assert_eq!(
+            insert_runlog(&pool, &partial, InsertionBehavior::SkipDuplicate).unwrap(),
+            RunlogInsertion::AlreadyThere
+        );
+
+        let results = ruddersysevents
+            .limit(100)
+            .load::<QueryableReport>(db)
+            .unwrap();
+        assert_eq!(results.len(), 71);
+    }
+
+    #[test]
+    fn it_inserts_runlogs_in_batch() {
+        let pool = db();
+        let db = &*pool.get().unwrap();
+
+        diesel::delete(ruddersysevents).execute(db).unwrap();
+        diesel::delete(relaydrunlogs::table).execute(db).unwrap();
+
+        let runlog = RunLog::new(
+            "tests/files/runlogs/2018-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        let other_runlog = RunLog::new(
+            "tests/files/runlogs/2017-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+
+        assert_eq!(
+            insert_runlog(&pool, &runlog, InsertionBehavior::SkipDuplicate).unwrap(),
+            RunlogInsertion::Inserted
+        );
+
+        let duplicate = RunLog::new(
+            "tests/files/runlogs/2017-08-24T15:55:01+00:00@e745a140-40bc-4b86-b6dc-084488fc906b.log",
+        )
+        .unwrap();
+        let runlogs = vec![runlog, other_runlog, duplicate];
+        assert_eq!(
+            insert_runlogs(&pool, &runlogs, InsertionBehavior::SkipDuplicate).unwrap(),
+            vec![
+                RunlogInsertion::AlreadyThere,
+                RunlogInsertion::Inserted,
+                RunlogInsertion::AlreadyThere
+            ]
+        );
+
+        let results = ruddersysevents
+            .limit(200)
+            .load::<QueryableReport>(db)
+            .unwrap();
+        assert_eq!(results.len(), 142);
+
+        assert_eq!(
+            insert_runlogs(&pool, &[], InsertionBehavior::SkipDuplicate).unwrap(),
+            vec![]
+        );
     }
 }
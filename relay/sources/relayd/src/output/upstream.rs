@@ -3,20 +3,24 @@
 
 use crate::{configuration::Secret, processing::inventory::InventoryType, Error, JobConfig};
 use futures::Future;
+use reqwest::StatusCode;
 use std::{path::PathBuf, sync::Arc};
 use tracing::{debug, span, Level};
 
+/// Sends a report as received, from a file or through the API
 pub fn send_report(
     job_config: Arc<JobConfig>,
-    path: PathBuf,
-) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    file_name: String,
+    content: Vec<u8>,
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send> {
     let report_span = span!(Level::TRACE, "upstream");
     let _report_enter = report_span.enter();
-    Box::new(forward_file(
+    Box::new(forward_content(
         job_config.clone(),
         "reports",
-        path,
+        file_name,
         job_config.cfg.output.upstream.password.clone(),
+        content,
     ))
 }
 
@@ -24,50 +28,107 @@ pub fn send_inventory(
     job_config: Arc<JobConfig>,
     path: PathBuf,
     inventory_type: InventoryType,
-) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send> {
     let report_span = span!(Level::TRACE, "upstream");
     let _report_enter = report_span.enter();
     Box::new(forward_file(
         job_config.clone(),
-        match inventory_type {
-            InventoryType::New => "inventories",
-            InventoryType::Update => "inventory-updates",
-        },
+        inventory_endpoint(inventory_type),
         path,
-        match inventory_type {
-            InventoryType::New => job_config.cfg.output.upstream.default_password.clone(),
-            InventoryType::Update => job_config.cfg.output.upstream.password.clone(),
-        },
+        inventory_password(&job_config, inventory_type),
+    ))
+}
+
+/// Sends an inventory file received in memory (i.e. not from a file)
+pub fn send_inventory_content(
+    job_config: Arc<JobConfig>,
+    file_name: String,
+    content: Vec<u8>,
+    inventory_type: InventoryType,
+) -> Box<dyn Future<Item = StatusCode, Error = Error> + Send> {
+    let report_span = span!(Level::TRACE, "upstream");
+    let _report_enter = report_span.enter();
+    Box::new(forward_content(
+        job_config.clone(),
+        inventory_endpoint(inventory_type),
+        file_name,
+        inventory_password(&job_config, inventory_type),
+        content,
     ))
 }
 
+fn inventory_endpoint(inventory_type: InventoryType) -> &'static str {
+    match inventory_type {
+        InventoryType::New => "inventories",
+        InventoryType::Update => "inventory-updates",
+    }
+}
+
+fn inventory_password(job_config: &JobConfig, inventory_type: InventoryType) -> Secret {
+    match inventory_type {
+        InventoryType::New => job_config.cfg.output.upstream.default_password.clone(),
+        InventoryType::Update => job_config.cfg.output.upstream.password.clone(),
+    }
+}
+
 fn forward_file(
     job_config: Arc<JobConfig>,
-    endpoint: &str,
+    endpoint: &'static str,
     path: PathBuf,
     password: Secret,
-) -> impl Future<Item = (), Error = Error> + '_ {
+) -> impl Future<Item = StatusCode, Error = Error> {
     tokio::fs::read(path.clone())
         .map_err(|e| e.into())
         .and_then(move |d| {
-            job_config
-                .client
-                .clone()
-                .put(&format!(
-                    "{}/{}/{}",
-                    job_config.cfg.output.upstream.url,
-                    endpoint,
-                    path.file_name().expect("not a file").to_string_lossy()
-                ))
-                .basic_auth(
-                    &job_config.cfg.output.upstream.user,
-                    Some(&password.value()),
-                )
-                .body(d)
-                .send()
-                // HTTP error -> Err()
-                .and_then(|r| r.error_for_status())
-                .map(|r| debug!("Server response: {:#?}", r))
-                .map_err(|e| e.into())
+            forward_content(
+                job_config,
+                endpoint,
+                path.file_name()
+                    .expect("not a file")
+                    .to_string_lossy()
+                    .to_string(),
+                password,
+                d,
+            )
+        })
+}
+
+fn forward_content(
+    job_config: Arc<JobConfig>,
+    endpoint: &'static str,
+    file_name: String,
+    password: Secret,
+    content: Vec<u8>,
+) -> impl Future<Item = StatusCode, Error = Error> {
+    job_config
+        .client
+        .clone()
+        .put(&format!(
+            "{}/{}/{}",
+            job_config.cfg.output.upstream.url, endpoint, file_name
+        ))
+        .basic_auth(
+            &job_config.cfg.output.upstream.user,
+            Some(&password.value()),
+        )
+        .body(content)
+        .send()
+        // HTTP error -> Err()
+        .and_then(|r| r.error_for_status())
+        .map(|r| {
+            debug!("Server response: {:#?}", r);
+            r.status()
         })
+        .map_err(|e| e.into())
+}
+
+/// Checks the upstream server is reachable, whatever the response status
+pub fn ping(job_config: Arc<JobConfig>) -> impl Future<Item = (), Error = Error> {
+    job_config
+        .client
+        .clone()
+        .head(&job_config.cfg.output.upstream.url)
+        .send()
+        .map(|r| debug!("Server response: {:#?}", r))
+        .map_err(|e| e.into())
 }
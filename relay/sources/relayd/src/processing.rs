@@ -1,40 +1,80 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
-use crate::{error::Error, stats::Event};
-use futures::{future::Future, sync::mpsc};
-use std::path::PathBuf;
+use crate::{
+    configuration::main::RetryConfig,
+    error::Error,
+    processing::{failed::FailureReason, retry::RetryState},
+    stats::{Event, Output},
+    JobConfig,
+};
+use chrono::Utc;
+use futures::{future::Future, stream, sync::mpsc};
+use reqwest::StatusCode;
+use std::{collections::BTreeSet, path::PathBuf, time::Instant};
 use tokio::{
     fs::{remove_file, rename},
     prelude::*,
 };
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 
+pub mod failed;
 pub mod inventory;
+pub mod replay;
 pub mod reporting;
+pub mod retry;
+pub mod shared_files;
 
 pub type ReceivedFile = PathBuf;
 pub type RootDirectory = PathBuf;
 
-#[derive(Debug, Copy, Clone)]
-enum OutputError {
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputError {
+    /// Processing can be retried later
     Transient,
+    /// Input is refused
     Permanent,
 }
 
 impl From<Error> for OutputError {
     fn from(err: Error) -> Self {
+        Self::from(&err)
+    }
+}
+
+impl From<&Error> for OutputError {
+    fn from(err: &Error) -> Self {
         match err {
-            Error::Database(_) | Error::DatabaseConnection(_) | Error::HttpClient(_) => {
-                OutputError::Transient
-            }
+            Error::Database(_)
+            | Error::DatabaseConnection(_)
+            | Error::Pool(_)
+            | Error::HttpClient(_)
+            | Error::OutputUnavailable(_) => OutputError::Transient,
+            Error::BatchInsertion(e) => OutputError::from(&**e),
             _ => OutputError::Permanent,
         }
     }
 }
 
+/// Retry state of a file, if previous attempts failed
+fn previous_attempt(file: &ReceivedFile, directory: &RootDirectory) -> Option<RetryState> {
+    // worst case is an early retry
+    RetryState::load(directory, file).unwrap_or_else(|e| {
+        warn!("could not read retry state of {:#?}: {}", file, e);
+        None
+    })
+}
+
+/// Forgets previous failed attempts, once the file is processed
+fn clear_retry(file: &ReceivedFile, directory: &RootDirectory) {
+    if let Err(e) = RetryState::remove(directory, file) {
+        warn!("could not remove retry state of {:#?}: {}", file, e);
+    }
+}
+
 fn success(
     file: ReceivedFile,
+    directory: RootDirectory,
     event: Event,
     stats: mpsc::Sender<Event>,
 ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
@@ -42,20 +82,45 @@ fn success(
         stats
             .send(event)
             .map_err(|e| error!("send error: {}", e))
-            .then(|_| {
-                remove_file(file.clone())
-                    .map(move |_| debug!("deleted: {:#?}", file))
-                    .map_err(|e| error!("error: {}", e))
-            }),
+            .then(|_| processed(file, directory)),
+    )
+}
+
+/// Removes a file once processed by all outputs
+fn processed(
+    file: ReceivedFile,
+    directory: RootDirectory,
+) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    clear_retry(&file, &directory);
+    Box::new(
+        remove_file(file.clone())
+            .map(move |_| debug!("deleted: {:#?}", file))
+            .map_err(|e| error!("error: {}", e)),
     )
 }
 
+pub fn send_events(
+    events: Vec<Event>,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    stream::iter_ok::<_, mpsc::SendError<Event>>(events)
+        .forward(stats)
+        .map(|_| ())
+        .map_err(|e| error!("send error: {}", e))
+}
+
+/// Moves a file to `failed`, along with the reason
 fn failure(
     file: ReceivedFile,
     directory: RootDirectory,
+    reason: FailureReason,
     event: Event,
     stats: mpsc::Sender<Event>,
 ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    clear_retry(&file, &directory);
+    if let Err(e) = reason.save(&directory, &file) {
+        error!("could not save failure reason of {:#?}: {}", file, e);
+    }
     Box::new(
         stats
             .send(event)
@@ -82,3 +147,124 @@ fn failure(
             .and_then(|_| Box::new(futures::future::err::<(), ()>(()))),
     )
 }
+
+/// Schedules a new attempt after a transient error, or moves the file to
+/// `failed` (with the reason) when retries are exhausted
+fn schedule_retry(
+    file: ReceivedFile,
+    directory: RootDirectory,
+    cfg: RetryConfig,
+    error: &Error,
+    acknowledged: BTreeSet<Output>,
+    event: Event,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    let now = Utc::now();
+    let state = RetryState::failed(
+        previous_attempt(&file, &directory),
+        error.to_string(),
+        acknowledged,
+        &cfg,
+        now,
+    );
+
+    if state.is_expired(&cfg, now) {
+        error!(
+            "giving up on {:#?} after {} attempts since {}",
+            file, state.attempts, state.first_failure
+        );
+        failure(
+            file,
+            directory,
+            FailureReason::gave_up(&state, now),
+            event,
+            stats,
+        )
+    } else {
+        info!(
+            "transient error, next attempt after {} (attempt {})",
+            state.next_attempt, state.attempts
+        );
+        if let Err(e) = state.save(&directory, &file) {
+            error!("could not save retry state of {:#?}: {}", file, e);
+        }
+        Box::new(futures::future::err(()))
+    }
+}
+
+/// Records the time spent processing a file in the given output
+fn timed<F>(
+    output: Output,
+    stats: mpsc::Sender<Event>,
+    treat_file: F,
+) -> Box<dyn Future<Item = F::Item, Error = F::Error> + Send>
+where
+    F: Future + Send + 'static,
+    F::Item: Send + 'static,
+    F::Error: Send + 'static,
+{
+    let start = Instant::now();
+    Box::new(treat_file.then(move |res| {
+        stats
+            .send(Event::OutputDuration(output, start.elapsed()))
+            .map_err(|e| error!("send error: {}", e))
+            .then(move |_| res)
+    }))
+}
+
+/// Updates the availability of the output with the result of a processing
+fn record_output<T>(
+    job_config: &JobConfig,
+    output: Output,
+    res: Result<T, Error>,
+) -> Result<T, Error> {
+    job_config.breakers.get(output).record(&res);
+    res
+}
+
+/// Records the status of the upstream server response, if any
+fn upstream_response(
+    res: Result<StatusCode, Error>,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    let status = match &res {
+        Ok(status) => Some(*status),
+        Err(Error::HttpClient(e)) => e.status(),
+        Err(_) => None,
+    };
+    let res = res.map(|_| ());
+    match status {
+        Some(status) => Box::new(
+            stats
+                .send(Event::UpstreamResponse(status.as_u16()))
+                .map_err(|e| error!("send error: {}", e))
+                .then(move |_| res),
+        ),
+        // connection error, etc.
+        None => Box::new(futures::future::result(res)),
+    }
+}
+
+/// Records the outcome of the processing of a file received through the API,
+/// given the events matching a successful processing
+fn received(
+    res: Result<Vec<Event>, Error>,
+    refused: Event,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = Error> {
+    let events = match &res {
+        Ok(events) => events.clone(),
+        Err(e) => match OutputError::from(e) {
+            OutputError::Permanent => {
+                error!("refused: {}", e);
+                vec![refused]
+            }
+            OutputError::Transient => {
+                error!("output error: {}", e);
+                vec![]
+            }
+        },
+    };
+    let res = res.map(|_| ());
+    send_events(events, stats).then(move |_| res)
+}
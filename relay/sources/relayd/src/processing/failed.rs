@@ -0,0 +1,214 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    error::Error,
+    processing::{retry::RetryState, ReceivedFile, RootDirectory},
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+use tracing::warn;
+
+const REASON_EXTENSION: &str = "reason";
+
+/// Why a file was moved to the `failed` directory
+///
+/// Stored as JSON next to the failed file, with an added `.reason` extension.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct FailureReason {
+    pub error: String,
+    pub date: DateTime<Utc>,
+    /// Directory the file was received in, relative to the root directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<String>,
+    /// Failed attempts, when giving up after transient errors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attempts: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub first_failure: Option<DateTime<Utc>>,
+}
+
+impl FailureReason {
+    pub fn new(error: &Error, now: DateTime<Utc>) -> Self {
+        Self {
+            error: error.to_string(),
+            date: now,
+            origin: None,
+            attempts: None,
+            first_failure: None,
+        }
+    }
+
+    /// Retries are exhausted
+    pub fn gave_up(state: &RetryState, now: DateTime<Utc>) -> Self {
+        Self {
+            error: state.last_error.clone(),
+            date: now,
+            origin: None,
+            attempts: Some(state.attempts),
+            first_failure: Some(state.first_failure),
+        }
+    }
+
+    fn path(directory: &Path, name: &str) -> PathBuf {
+        directory
+            .join("failed")
+            .join(format!("{}.{}", name, REASON_EXTENSION))
+    }
+
+    pub fn save(&self, directory: &RootDirectory, file: &ReceivedFile) -> Result<(), Error> {
+        let reason = Self {
+            origin: file
+                .parent()
+                .and_then(|p| p.file_name())
+                .map(|p| p.to_string_lossy().to_string()),
+            ..self.clone()
+        };
+        fs::write(
+            Self::path(
+                directory,
+                &file.file_name().expect("not a file").to_string_lossy(),
+            ),
+            serde_json::to_string(&reason)?,
+        )?;
+        Ok(())
+    }
+
+    pub fn load(directory: &RootDirectory, name: &str) -> Result<Option<Self>, Error> {
+        match fs::read_to_string(Self::path(directory, name)) {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn remove(directory: &RootDirectory, name: &str) -> Result<(), Error> {
+        match fs::remove_file(Self::path(directory, name)) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+pub struct FailedFile {
+    pub name: String,
+    pub size: u64,
+    /// Missing for files failed before reasons were recorded
+    pub reason: Option<FailureReason>,
+}
+
+fn is_reason(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e == REASON_EXTENSION)
+        .unwrap_or(false)
+}
+
+/// Path of a failed file, refusing names outside of the `failed` directory
+fn failed_file(directory: &RootDirectory, name: &str) -> Result<PathBuf, Error> {
+    let path = directory.join("failed").join(name);
+    if name.contains('/') || name.starts_with('.') || is_reason(&path) || !path.is_file() {
+        return Err(Error::UnknownFailedFile(name.to_string()));
+    }
+    Ok(path)
+}
+
+/// Failed files with their reasons, sorted by name
+pub fn list(directory: &RootDirectory) -> Result<Vec<FailedFile>, Error> {
+    let mut files = vec![];
+    for entry in fs::read_dir(directory.join("failed"))? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if !metadata.is_file() || is_reason(&path) {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let reason = FailureReason::load(directory, &name).unwrap_or_else(|e| {
+            warn!("could not read failure reason of {}: {}", name, e);
+            None
+        });
+        files.push(FailedFile {
+            name,
+            size: metadata.len(),
+            reason,
+        });
+    }
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(files)
+}
+
+/// Moves a failed file back to the directory it was received in
+pub fn retry(directory: &RootDirectory, name: &str) -> Result<(), Error> {
+    let path = failed_file(directory, name)?;
+    let origin = FailureReason::load(directory, name)
+        .ok()
+        .and_then(|r| r.and_then(|r| r.origin))
+        .filter(|o| !o.contains('/') && !o.starts_with('.'))
+        .unwrap_or_else(|| "incoming".to_string());
+    FailureReason::remove(directory, name)?;
+    fs::rename(path, directory.join(origin).join(name))?;
+    Ok(())
+}
+
+pub fn remove(directory: &RootDirectory, name: &str) -> Result<(), Error> {
+    let path = failed_file(directory, name)?;
+    FailureReason::remove(directory, name)?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_manages_failed_files() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        fs::create_dir(root.join("incoming")).unwrap();
+        fs::create_dir(root.join("failed")).unwrap();
+        fs::write(root.join("failed").join("old.log"), "old").unwrap();
+        fs::write(root.join("failed").join("new.log"), "new").unwrap();
+
+        let now = Utc::now();
+        let reason = FailureReason::new(&Error::EmptyRunlog, now);
+        reason
+            .save(&root, &root.join("incoming").join("new.log"))
+            .unwrap();
+
+        assert_eq!(
+            list(&root).unwrap(),
+            vec![
+                FailedFile {
+                    name: "new.log".to_string(),
+                    size: 3,
+                    reason: Some(FailureReason {
+                        origin: Some("incoming".to_string()),
+                        ..reason
+                    }),
+                },
+                FailedFile {
+                    name: "old.log".to_string(),
+                    size: 3,
+                    reason: None,
+                },
+            ]
+        );
+
+        assert!(retry(&root, "new.log.reason").is_err());
+        assert!(retry(&root, "../incoming").is_err());
+        assert!(remove(&root, "unknown.log").is_err());
+
+        retry(&root, "new.log").unwrap();
+        assert!(root.join("incoming").join("new.log").exists());
+        remove(&root, "old.log").unwrap();
+        assert_eq!(list(&root).unwrap(), vec![]);
+    }
+}
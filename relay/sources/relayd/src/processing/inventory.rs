@@ -2,18 +2,30 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use crate::{
-    configuration::main::InventoryOutputSelect,
-    input::watch::*,
-    output::upstream::send_inventory,
-    processing::{failure, success, OutputError, ReceivedFile},
-    stats::Event,
+    configuration::main::{InventoryLimitsConfig, InventoryOutputSelect},
+    data::shared_file::Metadata,
+    error::Error,
+    input::{uncompress_with_limits, watch::*},
+    output::upstream::{send_inventory, send_inventory_content},
+    processing::{
+        failed::FailureReason, failure, previous_attempt, received, record_output, schedule_retry,
+        success, timed, upstream_response, OutputError, ReceivedFile,
+    },
+    stats::{Event, Output, Queue},
     JobConfig,
 };
-use futures::{future::Future, lazy, sync::mpsc, Stream};
+use chrono::Utc;
+use futures::{
+    future::{poll_fn, Future},
+    lazy,
+    sync::mpsc,
+    Stream,
+};
 use md5::{Digest, Md5};
-use std::{os::unix::ffi::OsStrExt, sync::Arc};
+use std::{collections::BTreeSet, io::Cursor, os::unix::ffi::OsStrExt, path::Path, sync::Arc};
 use tokio::prelude::*;
-use tracing::{debug, error, info, span, Level};
+use tokio_threadpool::blocking;
+use tracing::{debug, error, span, Level};
 
 static INVENTORY_EXTENSIONS: &[&str] = &["gz", "xml", "sign"];
 
@@ -23,6 +35,15 @@ pub enum InventoryType {
     Update,
 }
 
+impl InventoryType {
+    fn queue(self) -> Queue {
+        match self {
+            InventoryType::New => Queue::InventoryNew,
+            InventoryType::Update => Queue::InventoryUpdate,
+        }
+    }
+}
+
 pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
     let span = span!(Level::TRACE, "inventory");
     let _enter = span.enter();
@@ -41,11 +62,16 @@ pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
         InventoryType::New,
         stats.clone(),
     ));
-    tokio::spawn(cleanup(
+    tokio::spawn(job_config.until_shutdown(cleanup(
         incoming_path.clone(),
         job_config.cfg.processing.inventory.cleanup,
-    ));
-    watch(&incoming_path, &job_config, &sender);
+    )));
+    watch(
+        &incoming_path,
+        &job_config,
+        &sender,
+        InventoryType::New.queue(),
+    );
 
     let updates_path = job_config
         .cfg
@@ -60,11 +86,22 @@ pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
         InventoryType::Update,
         stats.clone(),
     ));
-    tokio::spawn(cleanup(
+    tokio::spawn(job_config.until_shutdown(cleanup(
         updates_path.clone(),
         job_config.cfg.processing.inventory.cleanup,
-    ));
-    watch(&updates_path, &job_config, &sender);
+    )));
+    watch(
+        &updates_path,
+        &job_config,
+        &sender,
+        InventoryType::Update.queue(),
+    );
+
+    // Remove states of files removed by cleanup
+    tokio::spawn(job_config.until_shutdown(cleanup(
+        job_config.cfg.processing.inventory.directory.join("retry"),
+        job_config.cfg.processing.inventory.cleanup,
+    )));
 }
 
 fn serve(
@@ -74,6 +111,24 @@ fn serve(
     stats: mpsc::Sender<Event>,
 ) -> impl Future<Item = (), Error = ()> {
     rx.for_each(move |file| {
+        job_config.queues.dequeued(inventory_type.queue());
+
+        // queued files stay in place and will be picked up on next start
+        if job_config.is_shutting_down() {
+            debug!("shutdown in progress, skipping {:#?}", file);
+            return Ok(());
+        }
+
+        // files stay in place and will be picked up by catchup
+        if job_config.breakers.get(Output::InventoryUpstream).is_open() {
+            debug!(
+                "{} output is unavailable, skipping {:#?}",
+                Output::InventoryUpstream,
+                file
+            );
+            return Ok(());
+        }
+
         // allows skipping temporary .dav files
         if !file
             .extension()
@@ -102,13 +157,24 @@ fn serve(
         );
         let _enter = span.enter();
 
-        let stat_event = stats
-            .clone()
-            .send(Event::InventoryReceived)
-            .map_err(|e| error!("receive error: {}", e))
-            .map(|_| ());
-        // FIXME: no need for a spawn
-        tokio::spawn(lazy(|| stat_event));
+        let retry = previous_attempt(&file, &job_config.cfg.processing.inventory.directory);
+        match retry {
+            Some(ref state) if !state.is_due(Utc::now()) => {
+                debug!("skipping {:#?} until {}", file, state.next_attempt);
+                return Ok(());
+            }
+            Some(_) => (),
+            // Only count first attempt
+            None => {
+                let stat_event = stats
+                    .clone()
+                    .send(Event::InventoryReceived)
+                    .map_err(|e| error!("receive error: {}", e))
+                    .map(|_| ());
+                // FIXME: no need for a spawn
+                tokio::spawn(lazy(|| stat_event));
+            }
+        }
 
         debug!("received: {:?}", file);
 
@@ -118,9 +184,11 @@ fn serve(
             .inventory
             .output
         {
-            InventoryOutputSelect::Upstream => {
-                output_inventory_upstream(file, inventory_type, job_config.clone(), stats.clone())
-            }
+            InventoryOutputSelect::Upstream => timed(
+                Output::InventoryUpstream,
+                stats.clone(),
+                output_inventory_upstream(file, inventory_type, job_config.clone(), stats.clone()),
+            ),
             // The job should not be started in this case
             InventoryOutputSelect::Disabled => unreachable!("Inventory server should be disabled"),
         };
@@ -130,39 +198,305 @@ fn serve(
     })
 }
 
+/// Processes an inventory received through the API
+///
+/// The body contains the signature metadata (i.e. the `.sign` file content)
+/// followed by an empty line and the inventory.
+/// Both files are forwarded only if the signature matches the content, refused
+/// inventories are not kept.
+pub fn receive(
+    job_config: Arc<JobConfig>,
+    file_name: String,
+    inventory_type: InventoryType,
+    body: Vec<u8>,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    let span = span!(
+        Level::INFO,
+        "inventory",
+        file_name = %file_name,
+    );
+    let _enter = span.enter();
+
+    let stats_clone = stats.clone();
+    Box::new(
+        stats
+            .clone()
+            .send(Event::InventoryReceived)
+            .map_err(|e| error!("receive error: {}", e))
+            .then(move |_| receive_inner(job_config, file_name, inventory_type, body, stats))
+            .then(move |res| {
+                received(
+                    res.map(|event| vec![event]),
+                    Event::InventoryRefused,
+                    stats_clone,
+                )
+            }),
+    )
+}
+
+/// Returns the event corresponding to the successful output
+fn receive_inner(
+    job_config: Arc<JobConfig>,
+    file_name: String,
+    inventory_type: InventoryType,
+    body: Vec<u8>,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = Event, Error = Error> + Send> {
+    let limits = job_config.cfg.processing.inventory.limits;
+    let checked_name = file_name.clone();
+    // Hashing, signature check and extraction are blocking
+    Box::new(
+        poll_fn(move || {
+            blocking(|| {
+                check_inventory(&checked_name, &body, limits)
+                    .map(|(signature, inventory)| (signature.to_vec(), inventory.to_vec()))
+            })
+        })
+        .then(|res| res.expect("the thread pool shut down"))
+        .and_then(move |(signature, inventory)| {
+            output_received(
+                job_config,
+                file_name,
+                inventory_type,
+                signature,
+                inventory,
+                stats,
+            )
+        }),
+    )
+}
+
+/// Sends a checked inventory to the output
+fn output_received(
+    job_config: Arc<JobConfig>,
+    file_name: String,
+    inventory_type: InventoryType,
+    signature: Vec<u8>,
+    inventory: Vec<u8>,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = Event, Error = Error> + Send> {
+    debug!("received: {} from API", file_name);
+
+    match job_config.cfg.processing.inventory.output {
+        InventoryOutputSelect::Upstream => {
+            let breaker = job_config.breakers.get(Output::InventoryUpstream);
+            if breaker.is_open() {
+                return Box::new(futures::future::err(Error::OutputUnavailable(
+                    breaker.name(),
+                )));
+            }
+
+            let job_config_clone = job_config.clone();
+            let job_config_clone2 = job_config.clone();
+            let stats_clone = stats.clone();
+            let stats_clone2 = stats.clone();
+            let signature_name = format!("{}.sign", file_name);
+            timed(
+                Output::InventoryUpstream,
+                stats,
+                send_inventory_content(job_config, file_name, inventory, inventory_type)
+                    .then(move |res| upstream_response(res, stats_clone))
+                    .and_then(move |_| {
+                        send_inventory_content(
+                            job_config_clone,
+                            signature_name,
+                            signature,
+                            inventory_type,
+                        )
+                        .then(move |res| upstream_response(res, stats_clone2))
+                    })
+                    .then(move |res| {
+                        record_output(&job_config_clone2, Output::InventoryUpstream, res)
+                    })
+                    .map(|_| Event::InventorySent),
+            )
+        }
+        InventoryOutputSelect::Disabled => {
+            Box::new(futures::future::err(Error::DisabledProcessing("inventory")))
+        }
+    }
+}
+
+/// Validates an inventory received with its signature, and returns
+/// the `.sign` file and inventory content.
+///
+/// This is only an integrity check: the signature is verified with the public
+/// key included in the `.sign` file, as the sender may be a new node
+/// unknown to the relay. Authentication is done by the server when accepting
+/// the node.
+fn check_inventory<'a>(
+    file_name: &str,
+    body: &'a [u8],
+    limits: InventoryLimitsConfig,
+) -> Result<(&'a [u8], &'a [u8]), Error> {
+    if body.len() > limits.max_compressed_size {
+        return Err(Error::LimitExceeded(
+            "compressed size",
+            limits.max_compressed_size,
+        ));
+    }
+
+    // Only a file name with a known extension, without path
+    if file_name.starts_with('.')
+        || !file_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+    {
+        return Err(Error::InvalidInventory(format!(
+            "invalid file name {}",
+            file_name
+        )));
+    }
+    let extension = Path::new(file_name)
+        .extension()
+        .map(|e| e.to_string_lossy().to_string());
+    match extension.as_deref() {
+        Some("gz") | Some("xml") => (),
+        _ => {
+            return Err(Error::InvalidInventory(format!(
+                "unknown extension for {}",
+                file_name
+            )))
+        }
+    }
+
+    let mut cursor = Cursor::new(body);
+    let metadata = Metadata::from_reader(&mut cursor)?;
+    let (signature, inventory) = body.split_at(cursor.position() as usize);
+    // Remove the separator
+    let signature = &signature[..signature.len().saturating_sub(1)];
+
+    if metadata.hash.hash_type.hash(inventory) != metadata.hash {
+        return Err(Error::InvalidInventory(
+            "hash does not match content".to_string(),
+        ));
+    }
+    if !metadata.validate_signature(
+        inventory,
+        metadata.hash.hash_type,
+        &hex::decode(&metadata.digest)?,
+    )? {
+        return Err(Error::InvalidInventory("invalid signature".to_string()));
+    }
+    // Check content can be extracted
+    uncompress_with_limits(
+        inventory.to_vec(),
+        file_name,
+        limits.max_compressed_size,
+        limits.max_uncompressed_size,
+    )?;
+
+    Ok((signature, inventory))
+}
+
 fn output_inventory_upstream(
     path: ReceivedFile,
     inventory_type: InventoryType,
     job_config: Arc<JobConfig>,
     stats: mpsc::Sender<Event>,
 ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
-    let job_config_clone = job_config.clone();
     let path_clone2 = path.clone();
     let stats_clone = stats.clone();
+    let stats_clone2 = stats.clone();
+    let cfg = job_config.cfg.processing.inventory.clone();
+    let directory = cfg.directory.clone();
+    let job_config_clone = job_config.clone();
     Box::new(
         send_inventory(job_config, path.clone(), inventory_type)
+            .then(move |res| upstream_response(res, stats_clone2))
+            .then(move |res| record_output(&job_config_clone, Output::InventoryUpstream, res))
             .map_err(|e| {
                 error!("output error: {}", e);
-                OutputError::from(e)
+                e
             })
-            .or_else(move |e| match e {
+            .or_else(move |e| match OutputError::from(&e) {
                 OutputError::Permanent => failure(
-                    path_clone2.clone(),
-                    job_config_clone
-                        .clone()
-                        .cfg
-                        .processing
-                        .inventory
-                        .directory
-                        .clone(),
+                    path_clone2,
+                    cfg.directory.clone(),
+                    FailureReason::new(&e, Utc::now()),
+                    Event::InventoryRefused,
+                    stats,
+                ),
+                OutputError::Transient => schedule_retry(
+                    path_clone2,
+                    cfg.directory.clone(),
+                    cfg.retry,
+                    &e,
+                    BTreeSet::new(),
                     Event::InventoryRefused,
                     stats,
                 ),
-                OutputError::Transient => {
-                    info!("transient error, skipping");
-                    Box::new(futures::future::err::<(), ()>(()))
-                }
             })
-            .and_then(move |_| success(path.clone(), Event::InventorySent, stats_clone)),
+            .and_then(move |_| success(path, directory, Event::InventorySent, stats_clone)),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing::HashType;
+    use openssl::{pkey::PKey, rsa::Rsa, sign::Signer};
+
+    fn signed_body(inventory: &[u8]) -> Vec<u8> {
+        let key = Rsa::generate(2048).unwrap();
+        let pubkey = String::from_utf8(key.public_key_to_pem_pkcs1().unwrap()).unwrap();
+        let short_pubkey = pubkey
+            .lines()
+            .filter(|l| !l.starts_with("-----"))
+            .collect::<String>();
+
+        let mut signer = Signer::new(
+            HashType::Sha256.to_openssl_hash(),
+            &PKey::from_rsa(key).unwrap(),
+        )
+        .unwrap();
+        signer.update(inventory).unwrap();
+        let digest = hex::encode(signer.sign_to_vec().unwrap());
+
+        let mut body = format!(
+            "header=rudder-signature-v1\nalgorithm=sha256\ndigest={}\nhash_value={}\nshort_pubkey={}\nhostname=node.example.com\nkeydate=2020-01-24 12:17:59.014153459 +0100\nkeyid=B29D02BB\n\n",
+            digest,
+            HashType::Sha256.hash(inventory).value,
+            short_pubkey
+        )
+        .into_bytes();
+        body.extend_from_slice(inventory);
+        body
+    }
+
+    #[test]
+    fn it_checks_received_inventories() {
+        let inventory = b"<REQUEST></REQUEST>\n";
+        let body = signed_body(inventory);
+        let limits = InventoryLimitsConfig::default();
+
+        let (signature, content) = check_inventory("node-1234.xml", &body, limits).unwrap();
+        assert_eq!(content, inventory);
+        assert!(String::from_utf8(signature.to_vec())
+            .unwrap()
+            .ends_with("keyid=B29D02BB\n"));
+
+        // Modified content
+        let mut modified = body.clone();
+        modified.pop();
+        assert!(check_inventory("node-1234.xml", &modified, limits).is_err());
+
+        // Invalid names
+        assert!(check_inventory("node-1234.sign", &body, limits).is_err());
+        assert!(check_inventory("..", &body, limits).is_err());
+        assert!(check_inventory(".node-1234.xml", &body, limits).is_err());
+
+        // Too large
+        let small = InventoryLimitsConfig {
+            max_compressed_size: 1024 * 1024,
+            max_uncompressed_size: 10,
+        };
+        assert!(check_inventory("node-1234.xml", &body, small).is_err());
+        let small = InventoryLimitsConfig {
+            max_compressed_size: 100,
+            max_uncompressed_size: 1024 * 1024,
+        };
+        assert!(check_inventory("node-1234.xml", &body, small).is_err());
+    }
+}
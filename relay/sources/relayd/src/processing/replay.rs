@@ -0,0 +1,201 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    configuration::{cli::ReplayOptions, main::Configuration},
+    data::{node::NodesList, RunInfo, RunLog},
+    error::Error,
+    output::{
+        archive::{is_archive, read_archive},
+        database::{insert_runlog, pg_pool, InsertionBehavior, PgPool, RunlogInsertion},
+    },
+    processing::{reporting::read_report, OutputError},
+};
+use std::{
+    convert::TryFrom,
+    fmt,
+    fs::read_dir,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// Progress is displayed every n runlogs
+const PROGRESS_INTERVAL: usize = 100;
+
+static RUNLOG_EXTENSIONS: &[&str] = &[".log", ".log.gz", ".zip"];
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct ReplaySummary {
+    pub inserted: usize,
+    /// Skipped as they were already in the database
+    pub already_there: usize,
+    /// Could not be read or inserted
+    pub failed: usize,
+}
+
+impl ReplaySummary {
+    pub fn total(&self) -> usize {
+        self.inserted + self.already_there + self.failed
+    }
+
+    /// Inserts a runlog, failing if the database is unavailable as following
+    /// insertions would fail too
+    fn insert<W: Write>(
+        &mut self,
+        pool: &PgPool,
+        runlog: &RunLog,
+        behavior: InsertionBehavior,
+        out: &mut W,
+    ) -> Result<(), Error> {
+        match insert_runlog(pool, runlog, behavior) {
+            Ok(RunlogInsertion::Inserted) => self.inserted += 1,
+            Ok(RunlogInsertion::AlreadyThere) => self.already_there += 1,
+            Err(e) if OutputError::from(&e) == OutputError::Transient => {
+                writeln!(out, "stopping after {}", self)?;
+                return Err(e);
+            }
+            Err(e) => {
+                writeln!(out, "could not insert {} runlog: {}", runlog.info, e)?;
+                self.failed += 1;
+            }
+        }
+        if self.total() % PROGRESS_INTERVAL == 0 {
+            writeln!(out, "{} runlogs processed", self.total())?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ReplaySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} runlogs inserted, {} already there, {} failed",
+            self.inserted, self.already_there, self.failed
+        )
+    }
+}
+
+/// Runlog files, other files in directories (failure reasons, retry states, etc.)
+/// are skipped
+fn is_runlog(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| RUNLOG_EXTENSIONS.iter().any(|e| n.ends_with(e)))
+        .unwrap_or(false)
+}
+
+/// Files to replay, in name order for each directory
+fn files(paths: &[PathBuf]) -> Result<Vec<PathBuf>, Error> {
+    let mut res = vec![];
+    for path in paths {
+        if path.is_dir() {
+            let mut files = vec![];
+            for entry in read_dir(path)? {
+                let file = entry?.path();
+                if file.is_file() && (is_runlog(&file) || is_archive(&file)) {
+                    files.push(file);
+                }
+            }
+            files.sort();
+            res.extend(files);
+        } else {
+            res.push(path.clone());
+        }
+    }
+    Ok(res)
+}
+
+/// Inserts runlogs from runlog files and archives into the database
+///
+/// Runlog files are checked and parsed like received ones, archived runlogs
+/// are inserted as is. Progress and errors are written to `out`.
+pub fn replay<W: Write>(
+    cfg: &Configuration,
+    options: &ReplayOptions,
+    out: &mut W,
+) -> Result<ReplaySummary, Error> {
+    let pool = pg_pool(&cfg.output.database)?;
+    let nodes = NodesList::new(
+        cfg.general.node_id.to_string(),
+        &cfg.general.nodes_list_file,
+        Some(&cfg.general.nodes_certs_file),
+    )?;
+    let behavior = if options.force {
+        InsertionBehavior::AllowDuplicate
+    } else {
+        InsertionBehavior::SkipDuplicate
+    };
+
+    let mut summary = ReplaySummary::default();
+    for file in files(&options.paths)? {
+        if is_archive(&file) {
+            for runlog in read_archive(&file)? {
+                match runlog {
+                    Ok(runlog) => {
+                        if options.filter.matches(&runlog.info) {
+                            summary.insert(&pool, &runlog, behavior, out)?;
+                        }
+                    }
+                    // Following runlogs can't be read either
+                    Err(e) => {
+                        writeln!(out, "could not read {:?}: {}", file, e)?;
+                        summary.failed += 1;
+                        break;
+                    }
+                }
+            }
+        } else {
+            let info = match RunInfo::try_from(file.as_path()) {
+                Ok(info) => info,
+                Err(e) => {
+                    writeln!(out, "skipping {:?}: {}", file, e)?;
+                    continue;
+                }
+            };
+            if !options.filter.matches(&info) {
+                continue;
+            }
+            match read_report(&file, &info, &cfg.processing.reporting, &nodes) {
+                Ok((runlog, _truncated)) => summary.insert(&pool, &runlog, behavior, out)?,
+                Err(e) => {
+                    writeln!(out, "could not read {:?}: {}", file, e)?;
+                    summary.failed += 1;
+                }
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_lists_files_to_replay() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("b.log"), "").unwrap();
+        fs::write(dir.path().join("a.log"), "").unwrap();
+        fs::write(dir.path().join("b.log.reason"), "").unwrap();
+        fs::write(dir.path().join("b.log.json"), "").unwrap();
+        fs::write(dir.path().join("runlogs-2020-04-07.ndjson.gz"), "").unwrap();
+        fs::create_dir(dir.path().join("c")).unwrap();
+
+        assert_eq!(
+            files(&[
+                dir.path().to_path_buf(),
+                Path::new("tests/files/config/main.conf").to_path_buf()
+            ])
+            .unwrap(),
+            vec![
+                dir.path().join("a.log"),
+                dir.path().join("b.log"),
+                dir.path().join("runlogs-2020-04-07.ndjson.gz"),
+                Path::new("tests/files/config/main.conf").to_path_buf()
+            ]
+        );
+    }
+}
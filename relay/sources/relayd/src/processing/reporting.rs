@@ -2,33 +2,44 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use crate::{
-    configuration::main::ReportingOutputSelect,
-    data::{RunInfo, RunLog},
+    configuration::main::{ReportingConfig, ReportingOutputSelect},
+    data::{node::NodesList, RunInfo, RunLog},
     error::Error,
-    input::{read_compressed_file, signature, watch::*},
+    input::{
+        read_compressed_file, read_file_with_limit, signature, uncompress_with_limits, watch::*,
+    },
     output::{
-        database::{insert_runlog, InsertionBehavior},
+        archive::{self, archive_runlog},
+        batch::RunlogBatch,
         upstream::send_report,
     },
-    processing::{failure, success, OutputError, ReceivedFile},
-    stats::Event,
+    processing::{
+        failed::FailureReason, failure, previous_attempt, processed, received, record_output,
+        schedule_retry, send_events, timed, upstream_response, OutputError, ReceivedFile,
+    },
+    stats::{Event, Output, Queue},
     JobConfig,
 };
+use chrono::Utc;
 use futures::{
-    future::{poll_fn, Future},
+    future::{join_all, ok, poll_fn, result, Either, Future},
     lazy,
     sync::mpsc,
     Stream,
 };
 use md5::{Digest, Md5};
-use std::{convert::TryFrom, os::unix::ffi::OsStrExt, sync::Arc};
+use std::{
+    collections::BTreeSet, convert::TryFrom, os::unix::ffi::OsStrExt, path::Path, sync::Arc,
+};
 use tokio::prelude::*;
 use tokio_threadpool::blocking;
-use tracing::{debug, error, info, span, warn, Level};
+use tracing::{debug, error, span, warn, Level};
 
 static REPORT_EXTENSIONS: &[&str] = &["gz", "zip", "log"];
 
-pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
+/// Returns the batch used for database insertions, if any, to be shared
+/// with reports received through the API
+pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) -> Option<Arc<RunlogBatch>> {
     let span = span!(Level::TRACE, "reporting");
     let _enter = span.enter();
 
@@ -39,21 +50,64 @@ pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
         .directory
         .join("incoming");
 
+    let batch = if job_config
+        .cfg
+        .processing
+        .reporting
+        .uses(ReportingOutputSelect::Database)
+    {
+        let batch = RunlogBatch::new(job_config.clone());
+        tokio::spawn(batch.clone().run());
+        Some(batch)
+    } else {
+        None
+    };
+
     let (sender, receiver) = mpsc::channel(1_024);
-    tokio::spawn(serve(job_config.clone(), receiver, stats.clone()));
-    tokio::spawn(cleanup(
+    tokio::spawn(serve(
+        job_config.clone(),
+        receiver,
+        batch.clone(),
+        stats.clone(),
+    ));
+    tokio::spawn(job_config.until_shutdown(cleanup(
         path.clone(),
         job_config.cfg.processing.reporting.cleanup,
-    ));
-    watch(&path, &job_config, &sender);
+    )));
+    // Remove states of files removed by cleanup
+    tokio::spawn(job_config.until_shutdown(cleanup(
+        job_config.cfg.processing.reporting.directory.join("retry"),
+        job_config.cfg.processing.reporting.cleanup,
+    )));
+    if job_config
+        .cfg
+        .processing
+        .reporting
+        .uses(ReportingOutputSelect::Archive)
+    {
+        let cfg = &job_config.cfg.output.archive;
+        tokio::spawn(job_config.until_shutdown(cleanup(cfg.directory.clone(), cfg.cleanup)));
+        tokio::spawn(job_config.until_shutdown(archive::cleanup(cfg.clone())));
+    }
+    watch(&path, &job_config, &sender, Queue::Reporting);
+    batch
 }
 
 fn serve(
     job_config: Arc<JobConfig>,
     rx: mpsc::Receiver<ReceivedFile>,
+    batch: Option<Arc<RunlogBatch>>,
     stats: mpsc::Sender<Event>,
 ) -> impl Future<Item = (), Error = ()> {
     rx.for_each(move |file| {
+        job_config.queues.dequeued(Queue::Reporting);
+
+        // queued files stay in place and will be picked up on next start
+        if job_config.is_shutting_down() {
+            debug!("shutdown in progress, skipping {:#?}", file);
+            return Ok(());
+        }
+
         // allows skipping temporary .dav files
         if !file
             .extension()
@@ -82,13 +136,50 @@ fn serve(
         );
         let _enter = span.enter();
 
-        let stat_event = stats
-            .clone()
-            .send(Event::ReportReceived)
-            .map_err(|e| error!("receive error: {}", e))
-            .map(|_| ());
-        // FIXME: no need for a spawn
-        tokio::spawn(lazy(|| stat_event));
+        let retry = previous_attempt(&file, &job_config.cfg.processing.reporting.directory);
+        if let Some(ref state) = retry {
+            if !state.is_due(Utc::now()) {
+                debug!("skipping {:#?} until {}", file, state.next_attempt);
+                return Ok(());
+            }
+        }
+
+        // Outputs which already processed the file during a previous attempt
+        let acknowledged = retry
+            .as_ref()
+            .map(|state| state.acknowledged.clone())
+            .unwrap_or_default();
+        let outputs: Vec<(Output, bool)> = job_config
+            .cfg
+            .processing
+            .reporting
+            .outputs()
+            .into_iter()
+            .map(|(output, required)| (stats_output(output), required))
+            .filter(|(output, _)| !acknowledged.contains(output))
+            .collect();
+        // files stay in place and will be picked up by catchup
+        if let Some((output, _)) = outputs
+            .iter()
+            .find(|(output, required)| *required && job_config.breakers.get(*output).is_open())
+        {
+            debug!("{} output is unavailable, skipping {:#?}", output, file);
+            return Ok(());
+        }
+
+        match retry {
+            Some(_) => (),
+            // Only count first attempt
+            None => {
+                let stat_event = stats
+                    .clone()
+                    .send(Event::ReportReceived)
+                    .map_err(|e| error!("receive error: {}", e))
+                    .map(|_| ());
+                // FIXME: no need for a spawn
+                tokio::spawn(lazy(|| stat_event));
+            }
+        }
 
         // Check run info
         let info = RunInfo::try_from(file.as_ref()).map_err(|e| warn!("received: {}", e))?;
@@ -109,6 +200,7 @@ fn serve(
             let fail = failure(
                 file,
                 job_config.cfg.processing.reporting.directory.clone(),
+                FailureReason::new(&Error::UnknownNode(info.node_id.clone()), Utc::now()),
                 Event::ReportRefused,
                 stats.clone(),
             );
@@ -122,152 +214,507 @@ fn serve(
 
         debug!("received: {:?}", file);
 
-        let treat_file: Box<dyn Future<Item = (), Error = ()> + Send> =
-            match job_config.cfg.processing.reporting.output {
-                ReportingOutputSelect::Database => {
-                    output_report_database(file, info, job_config.clone(), stats.clone())
-                }
-                ReportingOutputSelect::Upstream => {
-                    output_report_upstream(file, job_config.clone(), stats.clone())
-                }
-                // The job should not be started in this case
-                ReportingOutputSelect::Disabled => unreachable!("Report server should be disabled"),
-            };
+        let treat_file = output_report_file(
+            file,
+            info,
+            job_config.clone(),
+            batch.clone(),
+            outputs,
+            acknowledged,
+            stats.clone(),
+        );
 
         tokio::spawn(lazy(|| treat_file));
         Ok(())
     })
 }
 
-fn output_report_database(
+/// Processes a report received through the API
+///
+/// Unlike reports received in `incoming/`, refused reports are not kept in
+/// `failed/`, the error is returned to the sender instead.
+pub fn receive(
+    job_config: Arc<JobConfig>,
+    batch: Option<Arc<RunlogBatch>>,
+    file_name: String,
+    content: Vec<u8>,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    let span = span!(
+        Level::INFO,
+        "report",
+        file_name = %file_name,
+    );
+    let _enter = span.enter();
+
+    let stats_clone = stats.clone();
+    Box::new(
+        stats
+            .clone()
+            .send(Event::ReportReceived)
+            .map_err(|e| error!("receive error: {}", e))
+            .then(move |_| receive_inner(job_config, batch, file_name, content, stats))
+            .then(move |res| received(res, Event::ReportRefused, stats_clone)),
+    )
+}
+
+/// Returns the events corresponding to the successful outputs
+fn receive_inner(
+    job_config: Arc<JobConfig>,
+    batch: Option<Arc<RunlogBatch>>,
+    file_name: String,
+    content: Vec<u8>,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = Vec<Event>, Error = Error> + Send> {
+    let info = match file_name.parse::<RunInfo>() {
+        Ok(info) => info,
+        Err(e) => return Box::new(futures::future::err(e)),
+    };
+
+    if !job_config
+        .nodes
+        .read()
+        .expect("Cannot read nodes list")
+        .is_subnode(&info.node_id)
+    {
+        return Box::new(futures::future::err(Error::UnknownNode(info.node_id)));
+    }
+
+    debug!("received: {} from API", file_name);
+
+    let outputs: Vec<(Output, bool)> = job_config
+        .cfg
+        .processing
+        .reporting
+        .outputs()
+        .into_iter()
+        .map(|(output, required)| (stats_output(output), required))
+        .collect();
+    if outputs.is_empty() {
+        return Box::new(futures::future::err(Error::DisabledProcessing("reporting")));
+    }
+    if let Some((output, _)) = outputs
+        .iter()
+        .find(|(output, required)| *required && job_config.breakers.get(*output).is_open())
+    {
+        return Box::new(futures::future::err(Error::OutputUnavailable(
+            job_config.breakers.get(*output).name(),
+        )));
+    }
+
+    Box::new(
+        output_report(file_name, content, info, job_config, batch, outputs, stats).and_then(
+            |(done, error)| match error {
+                Some(e) => Err(e),
+                None => Ok(done.into_iter().map(|(_, event)| event).collect::<Vec<_>>()),
+            },
+        ),
+    )
+}
+
+/// Sends a report file to the given outputs, and removes it once processed by all
+/// required outputs
+fn output_report_file(
     path: ReceivedFile,
-    run_info: RunInfo,
+    info: RunInfo,
     job_config: Arc<JobConfig>,
+    batch: Option<Arc<RunlogBatch>>,
+    outputs: Vec<(Output, bool)>,
+    acknowledged: BTreeSet<Output>,
     stats: mpsc::Sender<Event>,
 ) -> Box<dyn Future<Item = (), Error = ()> + Send> {
-    // Everything here is blocking: reading on disk or inserting into database
-    // We could use tokio::fs but it works the same and only makes things
-    // more complicated.
-    // We can switch to it once we also have stream (i.e. not on disk) input.
-    let job_config_clone = job_config.clone();
-    let path_clone = path.clone();
-    let path_clone2 = path.clone();
+    let limits = job_config.cfg.processing.reporting.limits;
+    let read_path = path.clone();
     let stats_clone = stats.clone();
     Box::new(
+        // Everything here is blocking: reading on disk or inserting into database
+        // We could use tokio::fs but it works the same and only makes things
+        // more complicated.
+        poll_fn(move || blocking(|| read_file_with_limit(&read_path, limits.max_compressed_size)))
+            .then(|res| res.expect("the thread pool shut down"))
+            .then(move |res| report_too_large(res, stats_clone))
+            .and_then({
+                let path = path.clone();
+                let job_config = job_config.clone();
+                let stats = stats.clone();
+                move |content| {
+                    let file_name = path
+                        .file_name()
+                        .expect("not a file")
+                        .to_string_lossy()
+                        .into_owned();
+                    output_report(file_name, content, info, job_config, batch, outputs, stats)
+                }
+            })
+            .then(move |res| {
+                let directory = job_config.cfg.processing.reporting.directory.clone();
+                match res {
+                    Ok((done, error)) => {
+                        let mut acknowledged = acknowledged;
+                        let mut events = vec![];
+                        for (output, event) in done {
+                            acknowledged.insert(output);
+                            events.push(event);
+                        }
+                        Either::A(
+                            send_events(events, stats.clone()).then(move |_| match error {
+                                None => processed(path, directory),
+                                Some(e) => output_error(path, &job_config, &e, acknowledged, stats),
+                            }),
+                        )
+                    }
+                    Err(e) => {
+                        error!("output error: {}", e);
+                        Either::B(output_error(path, &job_config, &e, acknowledged, stats))
+                    }
+                }
+            }),
+    )
+}
+
+/// Checks a report and sends it to the given outputs
+///
+/// The report is parsed once for all outputs using the runlog, and size limits
+/// apply to all outputs. Unavailable optional outputs are skipped.
+///
+/// Fails if the report is refused before reaching the outputs, otherwise returns
+/// the successful outputs with their events, and the error of the failed required
+/// outputs, if any.
+fn output_report(
+    file_name: String,
+    content: Vec<u8>,
+    info: RunInfo,
+    job_config: Arc<JobConfig>,
+    batch: Option<Arc<RunlogBatch>>,
+    outputs: Vec<(Output, bool)>,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (Vec<(Output, Event)>, Option<Error>), Error = Error> + Send> {
+    let outputs: Vec<(Output, bool)> = outputs
+        .into_iter()
+        .filter(|(output, required)| {
+            let skip = !required && job_config.breakers.get(*output).is_open();
+            if skip {
+                debug!("optional {} output is unavailable, skipping it", output);
+            }
+            !skip
+        })
+        .collect();
+
+    let parse_runlog = outputs.iter().any(|(output, _)| uses_runlog(*output));
+    let check = {
+        let job_config = job_config.clone();
+        let file_name = file_name.clone();
+        let content = content.clone();
+        let stats = stats.clone();
         poll_fn(move || {
             blocking(|| {
-                output_report_database_inner(&path_clone.clone(), &run_info, &job_config).map_err(
-                    |e| {
-                        error!("output error: {}", e);
-                        OutputError::from(e)
-                    },
-                )
-            })
-            .map_err(|_| {
-                panic!("the thread pool shut down");
-                // Temp hack to fix typing
-                #[allow(unreachable_code)]
-                OutputError::Transient
+                let cfg = &job_config.cfg.processing.reporting;
+                let uncompressed = uncompress_with_limits(
+                    content.clone(),
+                    &file_name,
+                    cfg.limits.max_compressed_size,
+                    cfg.limits.max_uncompressed_size,
+                )?;
+                if parse_runlog {
+                    parse_report(
+                        &uncompressed,
+                        &info,
+                        cfg,
+                        &job_config.nodes.read().expect("read nodes"),
+                    )
+                    .map(Some)
+                } else {
+                    Ok(None)
+                }
             })
         })
-        .flatten()
-        .or_else(move |e| match e {
-            OutputError::Permanent => failure(
-                path_clone2.clone(),
-                job_config_clone
-                    .clone()
-                    .cfg
-                    .processing
-                    .reporting
-                    .directory
-                    .clone(),
-                Event::ReportRefused,
-                stats,
-            ),
-            OutputError::Transient => {
-                info!("transient error, skipping");
-                Box::new(futures::future::err::<(), ()>(()))
+        .then(|res| res.expect("the thread pool shut down"))
+        .then(move |res| parsed(res, stats))
+    };
+
+    Box::new(check.then(move |res| {
+        let runlog = match res {
+            Ok(runlog) => runlog,
+            Err(e) => {
+                if is_too_large(&e)
+                    || outputs
+                        .iter()
+                        .any(|(output, required)| *required && uses_runlog(*output))
+                {
+                    return Either::A(futures::future::err(e));
+                }
+                warn!("optional outputs failed: {}", e);
+                None
             }
+        };
+
+        let treat_outputs = outputs
+            .into_iter()
+            .filter_map(|(output, required)| {
+                let treat_output = match (output, runlog.clone()) {
+                    (Output::ReportDatabase, Some(runlog)) => timed(
+                        output,
+                        stats.clone(),
+                        output_report_database(
+                            runlog,
+                            batch.clone().expect("database output without batch"),
+                        ),
+                    ),
+                    (Output::ReportArchive, Some(runlog)) => timed(
+                        output,
+                        stats.clone(),
+                        output_report_archive(runlog, job_config.clone()),
+                    ),
+                    // Optional outputs of an invalid runlog
+                    (Output::ReportDatabase, None) | (Output::ReportArchive, None) => return None,
+                    (Output::ReportUpstream, _) => timed(
+                        output,
+                        stats.clone(),
+                        output_report_upstream(
+                            file_name.clone(),
+                            content.clone(),
+                            job_config.clone(),
+                            stats.clone(),
+                        ),
+                    ),
+                    (Output::InventoryUpstream, _) => unreachable!("not a reporting output"),
+                };
+                Some(treat_output.then(move |res| Ok::<_, Error>((output, required, res))))
+            })
+            .collect::<Vec<_>>();
+
+        Either::B(join_all(treat_outputs).map(|results| {
+            let mut done = vec![];
+            let mut error: Option<Error> = None;
+            for (output, required, res) in results {
+                match res {
+                    Ok(event) => done.push((output, event)),
+                    Err(e) if !required => warn!("optional {} output failed: {}", output, e),
+                    Err(e) => {
+                        // Permanent errors take precedence
+                        let permanent = error
+                            .as_ref()
+                            .map(|previous| OutputError::from(previous) == OutputError::Permanent)
+                            .unwrap_or(false);
+                        if !permanent {
+                            error = Some(e);
+                        }
+                    }
+                }
+            }
+            (done, error)
+        }))
+    }))
+}
+
+/// Records the size issues of a parsed runlog
+fn parsed(
+    res: Result<Option<(RunLog, usize)>, Error>,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = Option<Arc<RunLog>>, Error = Error> {
+    let stats_clone = stats.clone();
+    report_too_large(res, stats).and_then(move |parsed| match parsed {
+        Some((runlog, truncated)) => Either::A(
+            report_truncated(truncated, stats_clone).then(move |_| Ok(Some(Arc::new(runlog)))),
+        ),
+        None => Either::B(ok(None)),
+    })
+}
+
+/// Output availability is recorded by the batch, once for all its runlogs
+fn output_report_database(
+    runlog: Arc<RunLog>,
+    batch: Arc<RunlogBatch>,
+) -> Box<dyn Future<Item = Event, Error = Error> + Send> {
+    Box::new(
+        // Inserted along with other runlogs
+        batch
+            .insert((*runlog).clone())
+            .map_err(|e| {
+                error!("output error: {}", e);
+                e
+            })
+            .map(|_| Event::ReportInserted),
+    )
+}
+
+fn output_report_archive(
+    runlog: Arc<RunLog>,
+    job_config: Arc<JobConfig>,
+) -> Box<dyn Future<Item = Event, Error = Error> + Send> {
+    let job_config_clone = job_config.clone();
+    Box::new(
+        poll_fn(move || {
+            blocking(|| archive_runlog(&job_config.cfg.output.archive.directory, &runlog))
+        })
+        .then(|res| res.expect("the thread pool shut down"))
+        .map_err(|e| {
+            error!("output error: {}", e);
+            e
         })
-        .and_then(move |_| success(path.clone(), Event::ReportInserted, stats_clone)),
+        .then(move |res| record_output(&job_config_clone, Output::ReportArchive, res))
+        .map(|_| Event::ReportArchived),
     )
 }
 
+/// Sends the report content as received
 fn output_report_upstream(
-    path: ReceivedFile,
+    file_name: String,
+    content: Vec<u8>,
     job_config: Arc<JobConfig>,
     stats: mpsc::Sender<Event>,
-) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+) -> Box<dyn Future<Item = Event, Error = Error> + Send> {
     let job_config_clone = job_config.clone();
-    let path_clone2 = path.clone();
-    let stats_clone = stats.clone();
     Box::new(
-        send_report(job_config, path.clone())
+        send_report(job_config, file_name, content)
+            .then(move |res| upstream_response(res, stats))
+            .then(move |res| record_output(&job_config_clone, Output::ReportUpstream, res))
             .map_err(|e| {
                 error!("output error: {}", e);
-                OutputError::from(e)
-            })
-            .or_else(move |e| match e {
-                OutputError::Permanent => failure(
-                    path_clone2.clone(),
-                    job_config_clone
-                        .clone()
-                        .cfg
-                        .processing
-                        .reporting
-                        .directory
-                        .clone(),
-                    Event::ReportRefused,
-                    stats,
-                ),
-                OutputError::Transient => {
-                    info!("transient error, skipping");
-                    Box::new(futures::future::err::<(), ()>(()))
-                }
+                e
             })
-            .and_then(move |_| success(path.clone(), Event::ReportSent, stats_clone)),
+            .map(|_| Event::ReportSent),
     )
 }
 
-fn output_report_database_inner(
-    path: &ReceivedFile,
+/// Refuses the report or schedules a new attempt, depending on the error
+fn output_error(
+    path: ReceivedFile,
+    job_config: &JobConfig,
+    error: &Error,
+    acknowledged: BTreeSet<Output>,
+    stats: mpsc::Sender<Event>,
+) -> Box<dyn Future<Item = (), Error = ()> + Send> {
+    let cfg = &job_config.cfg.processing.reporting;
+    match OutputError::from(error) {
+        OutputError::Permanent => failure(
+            path,
+            cfg.directory.clone(),
+            FailureReason::new(error, Utc::now()),
+            Event::ReportRefused,
+            stats,
+        ),
+        OutputError::Transient => schedule_retry(
+            path,
+            cfg.directory.clone(),
+            cfg.retry,
+            error,
+            acknowledged,
+            Event::ReportRefused,
+            stats,
+        ),
+    }
+}
+
+fn stats_output(output: ReportingOutputSelect) -> Output {
+    match output {
+        ReportingOutputSelect::Database => Output::ReportDatabase,
+        ReportingOutputSelect::Upstream => Output::ReportUpstream,
+        ReportingOutputSelect::Archive => Output::ReportArchive,
+        ReportingOutputSelect::Disabled => unreachable!("disabled is not an output"),
+    }
+}
+
+/// Whether the output needs the parsed runlog, or only the report file
+fn uses_runlog(output: Output) -> bool {
+    match output {
+        Output::ReportDatabase | Output::ReportArchive => true,
+        Output::ReportUpstream | Output::InventoryUpstream => false,
+    }
+}
+
+/// Reports over the size limits are refused whatever the outputs
+fn is_too_large(error: &Error) -> bool {
+    match error {
+        Error::LimitExceeded(_, _) => true,
+        _ => false,
+    }
+}
+
+/// Counts reports refused because of size limits
+fn report_too_large<T>(
+    res: Result<T, Error>,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = T, Error = Error> {
+    match res {
+        Err(e @ Error::LimitExceeded(_, _)) => Either::A(
+            stats
+                .send(Event::ReportTooLarge)
+                .map_err(|e| error!("send error: {}", e))
+                .then(move |_| Err(e)),
+        ),
+        res => Either::B(result(res)),
+    }
+}
+
+/// Counts reports with truncated messages
+fn report_truncated(
+    truncated: usize,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    if truncated == 0 {
+        return Either::A(ok::<(), ()>(()));
+    }
+    Either::B(
+        stats
+            .send(Event::ReportTruncated)
+            .map(|_| ())
+            .map_err(|e| error!("send error: {}", e)),
+    )
+}
+
+/// Reads and parses a report file, returning the runlog and the number
+/// of truncated messages
+pub fn read_report(
+    path: &Path,
     run_info: &RunInfo,
-    job_config: &Arc<JobConfig>,
-) -> Result<(), Error> {
-    debug!("Starting insertion of {:#?}", path);
+    cfg: &ReportingConfig,
+    nodes: &NodesList,
+) -> Result<(RunLog, usize), Error> {
+    debug!("Starting parsing of {:#?}", path);
+    parse_report(
+        &read_compressed_file(
+            &path,
+            cfg.limits.max_compressed_size,
+            cfg.limits.max_uncompressed_size,
+        )?,
+        run_info,
+        cfg,
+        nodes,
+    )
+}
 
+/// Checks signature and parses an uncompressed report, within configured limits
+///
+/// Returns the runlog and the number of truncated messages.
+pub fn parse_report(
+    content: &[u8],
+    run_info: &RunInfo,
+    cfg: &ReportingConfig,
+    nodes: &NodesList,
+) -> Result<(RunLog, usize), Error> {
     let signed_runlog = signature(
-        &read_compressed_file(&path)?,
-        job_config
-            .clone()
-            .nodes
-            .read()
-            .expect("read nodes")
+        content,
+        nodes
             .certs(&run_info.node_id)
             .ok_or_else(|| Error::MissingCertificateForNode(run_info.node_id.clone()))?,
     )?;
 
-    let parsed_runlog = RunLog::try_from((run_info.clone(), signed_runlog.as_ref()))?;
+    let parsed_runlog = RunLog::try_from((run_info.clone(), signed_runlog.as_ref(), &cfg.logs))?;
 
-    let filtered_runlog = if !job_config
-        .cfg
-        .processing
-        .reporting
-        .skip_event_types
-        .is_empty()
-    {
-        parsed_runlog.without_types(&job_config.cfg.processing.reporting.skip_event_types)
+    let mut runlog = if !cfg.skip_event_types.is_empty() {
+        parsed_runlog.without_types(&cfg.skip_event_types)
     } else {
         parsed_runlog
     };
 
-    let _inserted = insert_runlog(
-        &job_config
-            .pool
-            .clone()
-            .expect("output uses database but no config provided"),
-        &filtered_runlog,
-        InsertionBehavior::SkipDuplicate,
-    )?;
-    Ok(())
+    let limits = cfg.limits;
+    let truncated = runlog.limit(limits.max_reports, limits.max_message_size)?;
+    if truncated > 0 {
+        warn!(
+            "Truncated {} messages longer than {} bytes in {} runlog",
+            truncated, limits.max_message_size, run_info
+        );
+    }
+    Ok((runlog, truncated))
 }
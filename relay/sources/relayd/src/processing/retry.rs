@@ -0,0 +1,203 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    configuration::main::RetryConfig,
+    error::Error,
+    processing::{ReceivedFile, RootDirectory},
+    stats::Output,
+};
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::min,
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// Processing attempts of a file which could not be processed
+/// because of transient errors.
+///
+/// Stored as JSON in the `retry` directory, next to `incoming` and `failed`,
+/// as it needs to survive restarts.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct RetryState {
+    pub attempts: u32,
+    pub first_failure: DateTime<Utc>,
+    pub next_attempt: DateTime<Utc>,
+    pub last_error: String,
+    /// Outputs which already processed the file, and will be skipped
+    /// on next attempts
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub acknowledged: BTreeSet<Output>,
+}
+
+impl RetryState {
+    fn path(directory: &Path, file: &Path) -> PathBuf {
+        directory.join("retry").join(format!(
+            "{}.json",
+            file.file_name().expect("not a file").to_string_lossy()
+        ))
+    }
+
+    pub fn load(directory: &RootDirectory, file: &ReceivedFile) -> Result<Option<Self>, Error> {
+        match fs::read_to_string(Self::path(directory, file)) {
+            Ok(content) => Ok(Some(serde_json::from_str(&content)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(&self, directory: &RootDirectory, file: &ReceivedFile) -> Result<(), Error> {
+        fs::write(Self::path(directory, file), serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn remove(directory: &RootDirectory, file: &ReceivedFile) -> Result<(), Error> {
+        match fs::remove_file(Self::path(directory, file)) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Records a failed attempt, along with outputs which succeeded
+    pub fn failed(
+        previous: Option<Self>,
+        error: String,
+        mut acknowledged: BTreeSet<Output>,
+        cfg: &RetryConfig,
+        now: DateTime<Utc>,
+    ) -> Self {
+        let (attempts, first_failure) = match previous {
+            Some(state) => {
+                acknowledged.extend(state.acknowledged);
+                (state.attempts.saturating_add(1), state.first_failure)
+            }
+            None => (1, now),
+        };
+        Self {
+            attempts,
+            first_failure,
+            next_attempt: add_delay(now, jitter(backoff(attempts, cfg))),
+            last_error: error,
+            acknowledged,
+        }
+    }
+
+    /// Whether the file can be processed again
+    pub fn is_due(&self, now: DateTime<Utc>) -> bool {
+        self.next_attempt <= now
+    }
+
+    /// Whether we should stop retrying
+    pub fn is_expired(&self, cfg: &RetryConfig, now: DateTime<Utc>) -> bool {
+        self.attempts >= cfg.max_attempts
+            || now
+                .signed_duration_since(self.first_failure)
+                .to_std()
+                .map(|age| age >= cfg.max_age)
+                // first failure is in the future
+                .unwrap_or(false)
+    }
+}
+
+/// Delay before next attempt, doubled at each attempt until `max_delay`
+fn backoff(attempts: u32, cfg: &RetryConfig) -> Duration {
+    2u32.checked_pow(attempts.saturating_sub(1))
+        .and_then(|factor| cfg.initial_delay.checked_mul(factor))
+        .map(|delay| min(delay, cfg.max_delay))
+        .unwrap_or(cfg.max_delay)
+}
+
+/// Picks a delay between half and full given delay, to avoid retrying all
+/// files failed at the same time together
+fn jitter(delay: Duration) -> Duration {
+    let half = delay / 2;
+    half + Duration::from_millis(rand::thread_rng().gen_range(0, half.as_millis() as u64 + 1))
+}
+
+fn add_delay(now: DateTime<Utc>, delay: Duration) -> DateTime<Utc> {
+    chrono::Duration::from_std(delay)
+        .ok()
+        .and_then(|d| now.checked_add_signed(d))
+        .unwrap_or_else(|| chrono::MAX_DATE.and_hms(0, 0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> RetryConfig {
+        RetryConfig {
+            initial_delay: Duration::from_secs(60),
+            max_delay: Duration::from_secs(3600),
+            max_attempts: 5,
+            max_age: Duration::from_secs(3600 * 24),
+        }
+    }
+
+    #[test]
+    fn it_computes_backoff() {
+        let cfg = config();
+        assert_eq!(backoff(1, &cfg), Duration::from_secs(60));
+        assert_eq!(backoff(2, &cfg), Duration::from_secs(120));
+        assert_eq!(backoff(4, &cfg), Duration::from_secs(480));
+        assert_eq!(backoff(7, &cfg), Duration::from_secs(3600));
+        assert_eq!(backoff(100, &cfg), Duration::from_secs(3600));
+
+        for _ in 0..100 {
+            let delay = jitter(Duration::from_secs(60));
+            assert!(delay >= Duration::from_secs(30) && delay <= Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn it_records_attempts() {
+        let cfg = config();
+        let now = Utc::now();
+
+        let state = RetryState::failed(None, "error".to_string(), BTreeSet::new(), &cfg, now);
+        assert_eq!(state.attempts, 1);
+        assert_eq!(state.first_failure, now);
+        assert!(!state.is_due(now));
+        assert!(state.is_due(now + chrono::Duration::seconds(60)));
+        assert!(!state.is_expired(&cfg, now));
+
+        let later = now + chrono::Duration::seconds(60);
+        let mut acknowledged = BTreeSet::new();
+        acknowledged.insert(Output::ReportDatabase);
+        let state = RetryState::failed(
+            Some(state),
+            "other error".to_string(),
+            acknowledged.clone(),
+            &cfg,
+            later,
+        );
+        assert_eq!(state.attempts, 2);
+        assert_eq!(state.first_failure, now);
+        assert_eq!(state.last_error, "other error");
+        assert_eq!(state.acknowledged, acknowledged);
+        assert!(!state.is_expired(&cfg, later));
+        assert!(state.is_expired(&cfg, now + chrono::Duration::days(1)));
+
+        // Acknowledgements are kept
+        let state = RetryState::failed(
+            Some(state),
+            "other error".to_string(),
+            BTreeSet::new(),
+            &cfg,
+            later,
+        );
+        assert_eq!(state.acknowledged, acknowledged);
+
+        let state = RetryState {
+            attempts: 5,
+            ..state
+        };
+        assert!(state.is_expired(&cfg, later));
+    }
+}
@@ -0,0 +1,173 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+use crate::{
+    data::shared_file::Metadata, error::Error, processing::send_events, stats::Event, JobConfig,
+};
+use chrono::Utc;
+use futures::{
+    future::{poll_fn, Future},
+    sync::mpsc,
+    Stream,
+};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+use tokio_threadpool::blocking;
+use tracing::{debug, info, span, warn, Level};
+
+const METADATA_EXTENSION: &str = ".metadata";
+
+pub fn start(job_config: &Arc<JobConfig>, stats: &mpsc::Sender<Event>) {
+    let span = span!(Level::TRACE, "shared_files");
+    let _enter = span.enter();
+
+    tokio::spawn(job_config.until_shutdown(cleanup(
+        job_config.cfg.shared_files.path.clone(),
+        job_config.cfg.shared_files.cleanup_frequency,
+        job_config.shared_files_lock.clone(),
+        stats.clone(),
+    )));
+}
+
+/// Periodically removes expired shared files along with their metadata
+pub fn cleanup(
+    path: PathBuf,
+    frequency: Duration,
+    lock: Arc<Mutex<()>>,
+    stats: mpsc::Sender<Event>,
+) -> impl Future<Item = (), Error = ()> {
+    Interval::new(Instant::now(), frequency)
+        .map_err(|e| warn!("interval error: {}", e))
+        .for_each(move |_instant| {
+            debug!("cleaning {:?}", path);
+            let path = path.clone();
+            let lock = lock.clone();
+            let stats = stats.clone();
+            poll_fn(move || blocking(|| remove_expired(&path, Utc::now().timestamp(), &lock)))
+                .then(|res| res.expect("the thread pool shut down"))
+                .map_err(|e| warn!("shared files cleanup error: {}", e))
+                .and_then(move |removed| {
+                    send_events(vec![Event::SharedFileExpired; removed], stats)
+                })
+        })
+}
+
+/// Metadata files in the directory and its subdirectories
+fn metadata_files(directory: &Path, files: &mut Vec<PathBuf>) -> Result<(), Error> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+        let name = path.to_string_lossy().into_owned();
+        if entry.file_type()?.is_dir() {
+            metadata_files(&path, files)?;
+        } else if name.ends_with(METADATA_EXTENSION) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Shared file described by a metadata file
+fn shared_file_path(metadata: &Path) -> PathBuf {
+    let metadata = metadata.to_string_lossy();
+    PathBuf::from(&metadata[..metadata.len() - METADATA_EXTENSION.len()])
+}
+
+fn is_expired(metadata: &Path, now: i64) -> Result<bool, Error> {
+    Ok(Metadata::from_str(&fs::read_to_string(metadata)?)?.has_expired(now))
+}
+
+/// Removes a shared file if it has expired
+///
+/// Uploads store files while holding the same lock, so a new upload of the same
+/// file can't be removed by mistake. The content is removed first, an interrupted
+/// removal leaves the expired metadata which will be removed on next run.
+fn remove_if_expired(metadata: &Path, now: i64, lock: &Mutex<()>) -> Result<bool, Error> {
+    let _stored = lock.lock().expect("shared files lock poisoned");
+    if !is_expired(metadata, now)? {
+        return Ok(false);
+    }
+
+    match fs::remove_file(shared_file_path(metadata)) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    fs::remove_file(metadata)?;
+    Ok(true)
+}
+
+/// Removes expired shared files, returns the number of removed files
+pub fn remove_expired(directory: &Path, now: i64, lock: &Mutex<()>) -> Result<usize, Error> {
+    // Nothing was shared yet
+    if !directory.exists() {
+        return Ok(0);
+    }
+    let mut files = vec![];
+    metadata_files(directory, &mut files)?;
+
+    let mut removed = 0;
+    for metadata in files {
+        match remove_if_expired(&metadata, now, lock) {
+            Ok(true) => {
+                info!(
+                    "removed expired shared file {:?}",
+                    shared_file_path(&metadata)
+                );
+                removed += 1;
+            }
+            Ok(false) => (),
+            // Skip broken metadata, it may be fixed by a new upload
+            Err(e) => warn!("could not check expiration of {:?}: {}", metadata, e),
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_removes_expired_shared_files() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("target").join("files").join("source");
+        fs::create_dir_all(&source).unwrap();
+        let metadata = fs::read_to_string("tests/files/metadata.txt").unwrap();
+        let expires = 1_562_230_426;
+        assert!(metadata.contains(&format!("expires={}", expires)));
+
+        fs::write(source.join("expired.metadata"), &metadata).unwrap();
+        fs::write(source.join("expired"), "expired").unwrap();
+        fs::write(
+            source.join("valid.metadata"),
+            metadata.replace(
+                &format!("expires={}", expires),
+                &format!("expires={}", expires + 100),
+            ),
+        )
+        .unwrap();
+        fs::write(source.join("valid"), "valid").unwrap();
+        fs::write(source.join("broken.metadata"), "expires=0").unwrap();
+        fs::write(source.join("broken"), "broken").unwrap();
+        let lock = Mutex::new(());
+
+        assert_eq!(remove_expired(dir.path(), expires + 50, &lock).unwrap(), 1);
+        assert!(!source.join("expired.metadata").exists());
+        assert!(!source.join("expired").exists());
+        assert!(source.join("valid.metadata").exists());
+        assert!(source.join("valid").exists());
+        assert!(source.join("broken").exists());
+
+        assert_eq!(remove_expired(dir.path(), expires + 150, &lock).unwrap(), 1);
+        assert!(!source.join("valid").exists());
+        assert_eq!(fs::read_dir(&source).unwrap().count(), 2);
+    }
+}
@@ -2,7 +2,9 @@
 // SPDX-FileCopyrightText: 2019-2020 Normation SAS
 
 use relayd::{
-    check_configuration, configuration::cli::CliConfiguration, init_logger, start, ExitStatus,
+    check_configuration,
+    configuration::cli::{CliConfiguration, Command},
+    init_logger, query_archive, replay_runlogs, start, ExitStatus,
 };
 use std::{env, process::exit};
 use structopt::StructOpt;
@@ -20,24 +22,43 @@ fn main() {
     }
 
     let cli_cfg = CliConfiguration::from_args();
-    if cli_cfg.check_configuration {
-        if let Err(e) = check_configuration(&cli_cfg.configuration_dir) {
-            println!("{}", e);
-            exit(ExitStatus::StartError(e).code());
+    match cli_cfg.command {
+        // Runlogs are written to stdout
+        Some(Command::Archive(ref query)) => {
+            if let Err(e) = query_archive(&cli_cfg.configuration_dir, query) {
+                eprintln!("{}", e);
+                exit(ExitStatus::StartError(e).code());
+            }
+        }
+        Some(Command::Replay(ref options)) => {
+            match replay_runlogs(&cli_cfg.configuration_dir, options) {
+                Ok(summary) => println!("{}", summary),
+                Err(e) => {
+                    println!("{}", e);
+                    exit(ExitStatus::StartError(e).code());
+                }
+            }
         }
-        println!("Syntax: OK");
-    } else {
-        let reload_handle = match init_logger() {
-            Ok(handle) => handle,
-            Err(e) => {
+        None if cli_cfg.check_configuration => {
+            if let Err(e) = check_configuration(&cli_cfg.configuration_dir) {
                 println!("{}", e);
                 exit(ExitStatus::StartError(e).code());
             }
-        };
+            println!("Syntax: OK");
+        }
+        None => {
+            let reload_handle = match init_logger() {
+                Ok(handle) => handle,
+                Err(e) => {
+                    println!("{}", e);
+                    exit(ExitStatus::StartError(e).code());
+                }
+            };
 
-        if let Err(e) = start(cli_cfg, reload_handle) {
-            error!("{}", e);
-            exit(ExitStatus::StartError(e).code());
+            if let Err(e) = start(cli_cfg, reload_handle) {
+                error!("{}", e);
+                exit(ExitStatus::StartError(e).code());
+            }
         }
     }
 }
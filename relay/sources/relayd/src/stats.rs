@@ -3,7 +3,15 @@
 
 use futures::{stream::Stream, sync::mpsc, Future};
 use serde::{Deserialize, Serialize};
-use std::sync::{Arc, RwLock};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
 use tracing::trace;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
@@ -12,9 +20,26 @@ pub struct Stats {
     pub report_refused: u64,
     pub report_sent: u64,
     pub report_inserted: u64,
+    pub report_archived: u64,
+    /// Reports with truncated messages
+    pub report_truncated: u64,
+    /// Reports refused because of size limits, also counted as refused
+    pub report_too_large: u64,
     pub inventory_received: u64,
     pub inventory_refused: u64,
     pub inventory_sent: u64,
+    /// Shared files removed once expired
+    pub shared_file_expired: u64,
+    /// Nodes which could not be triggered as their sub-relay was unreachable
+    pub remote_run_not_triggered: u64,
+    // Only exposed as metrics
+    #[serde(skip)]
+    pub remote_run_forward_failed: BTreeMap<ForwardFailure, u64>,
+    // Only exposed as metrics
+    #[serde(skip)]
+    pub output_duration: BTreeMap<Output, Histogram>,
+    #[serde(skip)]
+    pub upstream_responses: BTreeMap<u16, u64>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -22,10 +47,122 @@ pub enum Event {
     ReportReceived,
     ReportSent,
     ReportInserted,
+    ReportArchived,
     ReportRefused,
+    ReportTruncated,
+    ReportTooLarge,
     InventoryReceived,
     InventorySent,
     InventoryRefused,
+    SharedFileExpired,
+    /// Time spent processing a file
+    OutputDuration(Output, Duration),
+    /// Status code of an upstream server response
+    UpstreamResponse(u16),
+    /// A remote run could not be forwarded to a sub-relay
+    RemoteRunForwardFailed(ForwardFailure),
+    /// Number of nodes not triggered because of a forwarding failure
+    RemoteRunNotTriggered(usize),
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    ReportDatabase,
+    ReportUpstream,
+    ReportArchive,
+    InventoryUpstream,
+}
+
+impl fmt::Display for Output {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Output::ReportDatabase => "report_database",
+                Output::ReportUpstream => "report_upstream",
+                Output::ReportArchive => "report_archive",
+                Output::InventoryUpstream => "inventory_upstream",
+            }
+        )
+    }
+}
+
+/// Why a remote run could not be forwarded to a sub-relay
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardFailure {
+    /// Including refused connections and name resolution errors
+    Connection,
+    Tls,
+    /// Non-2xx response
+    Status,
+    Timeout,
+    /// Response without any remote run event, like from an older relay
+    InvalidResponse,
+}
+
+impl fmt::Display for ForwardFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                ForwardFailure::Connection => "connection",
+                ForwardFailure::Tls => "tls",
+                ForwardFailure::Status => "status",
+                ForwardFailure::Timeout => "timeout",
+                ForwardFailure::InvalidResponse => "invalid_response",
+            }
+        )
+    }
+}
+
+/// Upper bounds of histogram buckets, in seconds
+pub const HISTOGRAM_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Histogram {
+    /// Number of observations in each bucket (not cumulative),
+    /// the last one being for values above all bounds
+    counts: [u64; 12],
+    sum: Duration,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        let bucket = HISTOGRAM_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(HISTOGRAM_BUCKETS.len());
+        self.counts[bucket] += 1;
+        self.sum += value;
+    }
+
+    /// Cumulative counts for each bucket upper bound, `None` standing for `+Inf`
+    pub fn buckets(&self) -> Vec<(Option<f64>, u64)> {
+        let mut total = 0;
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                total += count;
+                (HISTOGRAM_BUCKETS.get(i).copied(), total)
+            })
+            .collect()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
 }
 
 impl Stats {
@@ -34,10 +171,24 @@ impl Stats {
             Event::ReportReceived => self.report_received += 1,
             Event::ReportSent => self.report_sent += 1,
             Event::ReportInserted => self.report_inserted += 1,
+            Event::ReportArchived => self.report_archived += 1,
             Event::ReportRefused => self.report_refused += 1,
+            Event::ReportTruncated => self.report_truncated += 1,
+            Event::ReportTooLarge => self.report_too_large += 1,
             Event::InventoryReceived => self.inventory_received += 1,
             Event::InventorySent => self.inventory_sent += 1,
             Event::InventoryRefused => self.inventory_refused += 1,
+            Event::SharedFileExpired => self.shared_file_expired += 1,
+            Event::OutputDuration(output, duration) => self
+                .output_duration
+                .entry(output)
+                .or_default()
+                .observe(duration),
+            Event::UpstreamResponse(code) => *self.upstream_responses.entry(code).or_insert(0) += 1,
+            Event::RemoteRunForwardFailed(reason) => {
+                *self.remote_run_forward_failed.entry(reason).or_insert(0) += 1
+            }
+            Event::RemoteRunNotTriggered(nodes) => self.remote_run_not_triggered += nodes as u64,
         }
     }
 
@@ -55,3 +206,81 @@ impl Stats {
         })
     }
 }
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Queue {
+    Reporting,
+    InventoryNew,
+    InventoryUpdate,
+}
+
+impl fmt::Display for Queue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Queue::Reporting => "reporting",
+                Queue::InventoryNew => "inventory_new",
+                Queue::InventoryUpdate => "inventory_update",
+            }
+        )
+    }
+}
+
+/// Number of files waiting in processing queues, shared between
+/// watchers and processing loops
+#[derive(Debug, Default)]
+pub struct QueueDepths {
+    reporting: AtomicUsize,
+    inventory_new: AtomicUsize,
+    inventory_update: AtomicUsize,
+}
+
+impl QueueDepths {
+    fn depth(&self, queue: Queue) -> &AtomicUsize {
+        match queue {
+            Queue::Reporting => &self.reporting,
+            Queue::InventoryNew => &self.inventory_new,
+            Queue::InventoryUpdate => &self.inventory_update,
+        }
+    }
+
+    /// Must be called before sending the file
+    pub fn enqueued(&self, queue: Queue) {
+        self.depth(queue).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dequeued(&self, queue: Queue) {
+        self.depth(queue).fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, queue: Queue) -> usize {
+        self.depth(queue).load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_computes_histograms() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(1));
+        histogram.observe(Duration::from_millis(300));
+        histogram.observe(Duration::from_millis(400));
+        histogram.observe(Duration::from_secs(60));
+
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum(), Duration::from_millis(60_701));
+
+        let buckets = histogram.buckets();
+        assert_eq!(buckets.len(), 12);
+        assert_eq!(buckets[0], (Some(0.005), 1));
+        assert_eq!(buckets[5], (Some(0.25), 1));
+        assert_eq!(buckets[6], (Some(0.5), 3));
+        assert_eq!(buckets[10], (Some(10.0), 3));
+        assert_eq!(buckets[11], (None, 4));
+    }
+}
@@ -0,0 +1,38 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+// SPDX-FileCopyrightText: 2019-2020 Normation SAS
+
+mod common;
+
+use relayd::{configuration::cli::CliConfiguration, init_logger, start};
+use reqwest;
+use std::thread;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_serves_metrics() {
+        let cli_cfg = CliConfiguration::new("tests/files/config/", false);
+        thread::spawn(move || {
+            start(cli_cfg, init_logger().unwrap()).unwrap();
+        });
+        assert!(common::start_api().is_ok());
+
+        for url in &[
+            "http://localhost:3030/rudder/relay-api/1/system/metrics",
+            "http://localhost:3030/metrics",
+        ] {
+            let mut response = reqwest::get(*url).unwrap();
+            assert_eq!(response.status(), hyper::StatusCode::OK);
+            assert_eq!(
+                response.headers()["content-type"],
+                "text/plain; version=0.0.4"
+            );
+            assert!(response
+                .text()
+                .unwrap()
+                .contains("# TYPE relayd_reports_received_total counter\n"));
+        }
+    }
+}
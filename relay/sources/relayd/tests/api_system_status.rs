@@ -19,7 +19,7 @@ mod tests {
         });
         assert!(common::start_api().is_ok());
 
-        let response: serde_json::Value = serde_json::from_str(
+        let mut response: serde_json::Value = serde_json::from_str(
             &reqwest::get("http://localhost:3030/rudder/relay-api/1/system/status")
                 .unwrap()
                 .text()
@@ -27,6 +27,21 @@ mod tests {
         )
         .unwrap();
 
+        // Depends on the upstream server availability
+        let outputs = response["data"]
+            .as_object_mut()
+            .unwrap()
+            .remove("outputs")
+            .unwrap();
+        assert_eq!(
+            outputs["database"],
+            serde_json::from_str::<serde_json::Value>(
+                "{\"state\":\"closed\",\"consecutive-failures\":0}"
+            )
+            .unwrap()
+        );
+        assert!(outputs.get("upstream").is_some());
+
         let reference: serde_json::Value = serde_json::from_str("{\"data\":{\"database\":{\"status\":\"success\"},\"configuration\":{\"status\":\"success\"}},\"result\":\"success\",\"action\":\"getStatus\"}").unwrap();
 
         assert_eq!(reference, response);
@@ -39,13 +54,14 @@ mod tests {
         )
         .unwrap();
 
-        let response: serde_json::Value = serde_json::from_str(
+        let mut response: serde_json::Value = serde_json::from_str(
             &reqwest::get("http://localhost:3030/rudder/relay-api/1/system/status")
                 .unwrap()
                 .text()
                 .unwrap(),
         )
         .unwrap();
+        response["data"].as_object_mut().unwrap().remove("outputs");
 
         rename(
             "tests/files/config/main.conf.new",
@@ -4,8 +4,12 @@
 use diesel::{self, prelude::*, PgConnection};
 use filetime::{set_file_times, FileTime};
 use relayd::{
-    configuration::cli::CliConfiguration, data::report::QueryableReport, init_logger,
-    output::database::schema::ruddersysevents::dsl::*, start, stats::Stats,
+    configuration::cli::CliConfiguration,
+    data::report::QueryableReport,
+    init_logger,
+    output::database::schema::{relaydrunlogs, ruddersysevents::dsl::*},
+    start,
+    stats::Stats,
 };
 use reqwest;
 use serde_json;
@@ -42,6 +46,7 @@ pub fn start_number(db: &PgConnection, expected: usize) -> Result<(), ()> {
 fn it_reads_and_inserts_a_runlog() {
     let db = db_connection();
     diesel::delete(ruddersysevents).execute(&db).unwrap();
+    diesel::delete(relaydrunlogs::table).execute(&db).unwrap();
 
     assert!(start_number(&db, 0).is_ok());
 
@@ -113,6 +118,8 @@ fn it_reads_and_inserts_a_runlog() {
         inventory_received: 0,
         inventory_refused: 0,
         inventory_sent: 0,
+        // not serialized
+        ..Default::default()
     };
     assert_eq!(reference, answer);
 }
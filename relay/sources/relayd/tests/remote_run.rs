@@ -98,13 +98,38 @@ mod tests {
             .send()
             .unwrap();
         assert_eq!(response.status(), hyper::StatusCode::OK);
-        assert_eq!(response.text().unwrap(), "".to_string());
+        let started: serde_json::Value = serde_json::from_str(&response.text().unwrap()).unwrap();
+        let job_id = started["data"]["id"].as_str().unwrap();
         // async, let's wait a bit
-        thread::sleep(time::Duration::from_millis(700));
+        thread::sleep(time::Duration::from_millis(1000));
         assert_eq!(
             "remote run -D class2,class4 server.rudder.local".to_string(),
             read_to_string("target/tmp/api_test.txt").unwrap()
         );
+        let job: serde_json::Value = serde_json::from_str(
+            &client
+                .get(&format!(
+                    "http://localhost:3030/rudder/relay-api/1/remote-run/jobs/{}",
+                    job_id
+                ))
+                .send()
+                .unwrap()
+                .text()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(job["data"]["state"], "finished");
+        assert_eq!(job["data"]["nodes"]["root"]["state"], "success");
+        assert_eq!(job["data"]["nodes"]["root"]["exit_code"], 0);
+        assert_eq!(
+            job["data"]["nodes"]["root"]["output"],
+            serde_json::json!(["OK", "END"])
+        );
+        let response = client
+            .delete("http://localhost:3030/rudder/relay-api/1/remote-run/jobs/unknown")
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::NOT_FOUND);
 
         // Sync & keep
 
@@ -148,6 +173,55 @@ mod tests {
             read_to_string("target/tmp/api_test.txt").unwrap()
         );
 
+        // Sync & no keep, behind an unreachable relay
+
+        let params_sync = [
+            ("asynchronous", "false"),
+            ("keep_output", "false"),
+            ("classes", "class2,class6"),
+            ("nodes", "c745a140-40bc-4b86-b6dc-084488fc906b"),
+        ];
+        let mut response = client
+            .post("http://localhost:3030/rudder/relay-api/1/remote-run/nodes")
+            .form(&params_sync)
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        let output = response.text().unwrap();
+        assert!(output.starts_with("Remote run could not be forwarded to node2.rudder.local"));
+        assert!(output.ends_with("nodes not triggered: c745a140-40bc-4b86-b6dc-084488fc906b\n"));
+
+        // Sync & keep, with events
+
+        let _ = remove_file("target/tmp/api_test.txt");
+        let params_sync = [
+            ("asynchronous", "false"),
+            ("keep_output", "true"),
+            ("classes", "class2,class7"),
+            ("nodes", "root"),
+            ("format", "json"),
+        ];
+        let mut response = client
+            .post("http://localhost:3030/rudder/relay-api/1/remote-run/nodes")
+            .form(&params_sync)
+            .send()
+            .unwrap();
+        assert_eq!(response.status(), hyper::StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+        assert_eq!(
+            response.text().unwrap(),
+            r#"{"node_id":"root","relay_path":["root"],"event":"started"}
+{"node_id":"root","relay_path":["root"],"event":"output","line":"OK"}
+{"node_id":"root","relay_path":["root"],"event":"output","line":"END"}
+{"node_id":"root","relay_path":["root"],"event":"finished","exit_code":0}
+"#
+            .to_string()
+        );
+        assert_eq!(
+            "remote run -D class2,class7 server.rudder.local".to_string(),
+            read_to_string("target/tmp/api_test.txt").unwrap()
+        );
+
         // Failure
 
         let params = [
@@ -6,7 +6,7 @@ use relayd::{
 };
 use reqwest;
 use std::{
-    fs::{read_to_string, remove_file},
+    fs::{copy, create_dir_all, read_dir, read_to_string, remove_dir_all, write},
     str::FromStr,
     thread,
 };
@@ -19,6 +19,20 @@ mod tests {
 
     #[test]
     fn it_shares_files() {
+        // Work on a copy as expired files get removed
+        let fixture = "tests/api_shared_files/37817c4d-fbf7-4850-a985-50021f4e8f41/files/e745a140-40bc-4b86-b6dc-084488fc906b";
+        let shared = "target/tmp/api_shared_files/37817c4d-fbf7-4850-a985-50021f4e8f41/files/e745a140-40bc-4b86-b6dc-084488fc906b";
+        let _ = remove_dir_all("target/tmp/api_shared_files");
+        create_dir_all(shared).unwrap();
+        copy(format!("{}/file", fixture), format!("{}/file", shared)).unwrap();
+        write(
+            format!("{}/file.metadata", shared),
+            read_to_string(format!("{}/file.metadata", fixture))
+                .unwrap()
+                .replace("expires=1580941341", "expires=4102444800"),
+        )
+        .unwrap();
+
         let cli_cfg = CliConfiguration::new("tests/files/config/", false);
 
         thread::spawn(move || {
@@ -62,11 +76,72 @@ mod tests {
 
         assert_eq!(404, no_hash_sent.status());
 
+        // Download by the target node
+
+        let target_cert =
+            read_to_string("tests/files/keys/37817c4d-fbf7-4850-a985-50021f4e8f41.cert").unwrap();
+        let other_cert =
+            read_to_string("tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.cert").unwrap();
+
+        let mut download = client
+            .get("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41/e745a140-40bc-4b86-b6dc-084488fc906b/file")
+            .header("X-Rudder-Client-Certificate", target_cert.replace('\n', " "))
+            .send()
+            .unwrap();
+        assert_eq!(200, download.status());
+        assert_eq!(
+            download.headers()["X-Rudder-Metadata-Hash-Value"],
+            "dda78e9b97a69aca3cff21de266246bde0d91bc4b61df72bfb0387564ac0c7bd64dd4caca39ce1ef400f32aa711ec4909789705beec93314eb65fabd5183bbfe"
+        );
+        assert_eq!(
+            download.text().unwrap(),
+            read_to_string("tests/api_shared_files/37817c4d-fbf7-4850-a985-50021f4e8f41/files/e745a140-40bc-4b86-b6dc-084488fc906b/file").unwrap()
+        );
+
+        let not_target = client
+            .get("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41/e745a140-40bc-4b86-b6dc-084488fc906b/file")
+            .header("X-Rudder-Client-Certificate", other_cert.replace('\n', " "))
+            .send()
+            .unwrap();
+        assert_eq!(403, not_target.status());
+
+        let no_certificate = client
+            .get("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41/e745a140-40bc-4b86-b6dc-084488fc906b/file")
+            .send()
+            .unwrap();
+        assert_eq!(403, no_certificate.status());
+
+        // Listing by the target node
+
+        let mut listing = client
+            .get("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41?source=e745a140-40bc-4b86-b6dc-084488fc906b")
+            .header("X-Rudder-Client-Certificate", target_cert.replace('\n', " "))
+            .send()
+            .unwrap();
+        assert_eq!(200, listing.status());
+        let listing: serde_json::Value = serde_json::from_str(&listing.text().unwrap()).unwrap();
+        assert_eq!(listing["data"][0]["file_id"], "file");
+        assert_eq!(listing["data"].as_array().unwrap().len(), 1);
+
+        let malformed_query = client
+            .get("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41?source=a&source=b")
+            .header("X-Rudder-Client-Certificate", target_cert.replace('\n', " "))
+            .send()
+            .unwrap();
+        assert_eq!(400, malformed_query.status());
+
+        let not_target = client
+            .get("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/37817c4d-fbf7-4850-a985-50021f4e8f41")
+            .header("X-Rudder-Client-Certificate", other_cert.replace('\n', " "))
+            .send()
+            .unwrap();
+        assert_eq!(403, not_target.status());
+
         // prepare body content
         // .sign created with:
         // tools/rudder-sign tests/api_shared_files/37817c4d-fbf7-4850-a985-50021f4e8f41/e745a140-40bc-4b86-b6dc-084488fc906b/file2 tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.priv tests/files/keys/e745a140-40bc-4b86-b6dc-084488fc906b.pub "node1.rudder.local"
 
-        let file = "tests/api_shared_files/37817c4d-fbf7-4850-a985-50021f4e8f41/files/e745a140-40bc-4b86-b6dc-084488fc906b/file2";
+        let file = format!("{}/file2", fixture);
         let signature = read_to_string(&format!("{}.sign", file)).unwrap();
         let content = read_to_string(&format!("{}.source", file)).unwrap();
 
@@ -87,8 +162,9 @@ mod tests {
         .send().unwrap();
         assert_eq!(200, upload.status());
 
+        let written = format!("{}/file2", shared);
         let mut written_metadata =
-            Metadata::from_str(&read_to_string(&format!("{}.metadata", file)).unwrap()).unwrap();
+            Metadata::from_str(&read_to_string(&format!("{}.metadata", written)).unwrap()).unwrap();
         let expiration = (chrono::Utc::now() + chrono::Duration::days(1)).timestamp();
         // Check expiration is correct
         assert!((expiration - written_metadata.expires.unwrap()).abs() < 500);
@@ -99,14 +175,29 @@ mod tests {
 
         // Check uploaded file
         assert_eq!(
-            read_to_string(file).unwrap(),
+            read_to_string(written).unwrap(),
             read_to_string(&format!("{}.source", file)).unwrap()
         );
         // Check metadata file
         assert_eq!(source_metadata, written_metadata);
 
-        // Remove leftover
-        remove_file(file).unwrap();
-        remove_file(&format!("{}.metadata", file)).unwrap();
+        // Target behind another relay, checked here as the sub-relay does not know the source
+
+        let forward = client.put("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/c745a140-40bc-4b86-b6dc-084488fc906b/e745a140-40bc-4b86-b6dc-084488fc906b/file2?ttl=1d").body(format!("{}\n{}", wrong_signature, content))
+        .send().unwrap();
+        assert_eq!(500, forward.status());
+
+        // node2.rudder.local can't be reached
+        let forward = client.put("http://127.0.0.1:3030/rudder/relay-api/1/shared-files/c745a140-40bc-4b86-b6dc-084488fc906b/e745a140-40bc-4b86-b6dc-084488fc906b/file2?ttl=1d").body(format!("{}\n{}", signature, content))
+        .send().unwrap();
+        assert_eq!(502, forward.status());
+
+        // Temporary files are removed
+        assert_eq!(
+            read_dir("target/tmp/api_shared_files/c745a140-40bc-4b86-b6dc-084488fc906b/files/e745a140-40bc-4b86-b6dc-084488fc906b")
+                .unwrap()
+                .count(),
+            0
+        );
     }
 }
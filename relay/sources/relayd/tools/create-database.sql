@@ -5,7 +5,10 @@ create user rudderreports with encrypted password 'PASSWORD';
 grant usage on sequence serial to rudderreports;
 grant select on table ruddersysevents to rudderreports;
 grant insert on table ruddersysevents to rudderreports;
+grant select on table relaydrunlogs to rudderreports;
+grant insert on table relaydrunlogs to rudderreports;
 /* only for test databases
 grant delete on table ruddersysevents to rudderreports;
 grant truncate on table ruddersysevents to rudderreports;
+grant delete on table relaydrunlogs to rudderreports;
 */
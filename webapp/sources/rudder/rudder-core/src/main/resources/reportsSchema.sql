@@ -87,6 +87,20 @@ CREATE INDEX ruleId_idx                   ON RudderSysEvents (ruleId);
 CREATE INDEX endRun_control_idx ON RudderSysEvents (id) WHERE eventType = 'control' and component = 'end';
 CREATE INDEX changes_executionTimeStamp_idx ON RudderSysEvents (executionTimeStamp) WHERE eventType = 'result_repaired';
 
+/*
+ * Agent runs inserted by relayd, used to insert each run exactly once,
+ * with the digest of their runlog.
+ */
+CREATE TABLE RelaydRunlogs (
+  nodeId             text NOT NULL CHECK (nodeId <> '')
+, executionTimeStamp timestamp with time zone NOT NULL
+, digest             text
+, insertionDate      timestamp with time zone NOT NULL default now()
+, PRIMARY KEY (nodeId, executionTimeStamp)
+);
+
+CREATE INDEX relaydrunlogs_executionTimeStamp_idx ON RelaydRunlogs (executionTimeStamp);
+
 /*
  * The table used to store archived agent execution reports.
  */
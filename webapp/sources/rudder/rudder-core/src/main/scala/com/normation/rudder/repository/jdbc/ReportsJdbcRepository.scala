@@ -65,6 +65,7 @@ class ReportsJdbcRepository(doobie: Doobie) extends ReportsRepository with Logga
   val archiveTable = "archivedruddersysevents"
 
   private[this] val reportsExecutionTable = "reportsexecution"
+  private[this] val relaydRunlogsTable = "relaydrunlogs"
   private[this] val common_reports_column = "executiondate, ruleid, directiveid, nodeid, serial, component, keyvalue, executiontimestamp, eventtype, msg"
   // When we want reports we already know the type (request with where clause on eventtype) we do not want eventtype in request because it will be used as value for message in corresponding case class
   private[this] val typed_reports_column = "executiondate, ruleid, directiveid, nodeid, serial, component, keyvalue, executiontimestamp, msg"
@@ -260,10 +261,12 @@ class ReportsJdbcRepository(doobie: Doobie) extends ReportsRepository with Logga
     val d1 = s"delete from ${reports} where executionTimeStamp < '${dateAt_0000}'"
     val d2 = s"delete from ${archiveTable} where executionTimeStamp < '${dateAt_0000}'"
     val d3 = s"delete from ${reportsExecutionTable} where date < '${dateAt_0000}'"
+    val d4 = s"delete from ${relaydRunlogsTable} where executionTimeStamp < '${dateAt_0000}'"
 
     val v1 = s"vacuum ${reports}"
     val v2 = s"vacuum full ${archiveTable}"
     val v3 = s"vacuum ${reportsExecutionTable}"
+    val v4 = s"vacuum ${relaydRunlogsTable}"
 
     logger.debug(s"""Deleting report with SQL query: [[
                    | ${d1}
@@ -271,12 +274,14 @@ class ReportsJdbcRepository(doobie: Doobie) extends ReportsRepository with Logga
                    | ${d2}
                    |]] and: [[
                    | ${d3}
+                   |]] and: [[
+                   | ${d4}
                    |]]""".stripMargin)
 
     (for {
-      i <- transactRun(xa => (d1 :: d2 :: d3 :: Nil).traverse(q => Update0(q, None).run).transact(xa).either)
+      i <- transactRun(xa => (d1 :: d2 :: d3 :: d4 :: Nil).traverse(q => Update0(q, None).run).transact(xa).either)
            // Vacuum cannot be run in a transaction block, it has to be in an autoCommit block
-      _ <- { (v1 :: v2 :: v3 :: Nil).map { vacuum =>
+      _ <- { (v1 :: v2 :: v3 :: v4 :: Nil).map { vacuum =>
                 transactRun(xa => (FC.setAutoCommit(true) *> Update0(vacuum, None).run <* FC.setAutoCommit(false)).transact(xa).either) }.sequence
            }
     } yield {